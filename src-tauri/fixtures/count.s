@@ -0,0 +1,38 @@
+@ Source of count.elf, assembled with llvm-mc and linked with flash at 0x08000000 and RAM at 0x100.
+    .syntax unified
+    .cpu cortex-m4
+    .thumb
+
+    .section .isr_vector, "a"
+    .word 0x400             @ initial SP
+    .word reset + 1         @ reset handler
+
+    .text
+    .global reset
+    .type reset, %function
+reset:
+    mov r1, #0x100          @ address of counter
+    ldr r0, [r1]
+loop:
+    adds r0, #1
+    cmp r0, #5
+    bne loop
+    ite eq
+    moveq r2, #1
+    movne r2, #0
+    ldr r3, =0x12345678
+    str r0, [r1, #4]
+    bl finish
+    .ltorg
+    .type finish, %function
+finish:
+    strb r2, [r1, #8]
+
+    .data
+    .global counter
+counter:
+    .word 2
+
+    .bss
+result:
+    .space 8
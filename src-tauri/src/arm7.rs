@@ -1,11 +1,24 @@
 use regex::Regex;
-use std::{collections::HashMap, str::FromStr, thread, time::Duration};
+use std::{
+    collections::{HashMap, VecDeque},
+    ops::Range,
+    str::FromStr,
+    thread,
+    time::Duration,
+};
 use tauri::State;
 
 pub use crate::instructions::*;
 use crate::{
     backend_api::{compile, GlobalKillSwitch, GlobalProcessor},
+    decode,
+    dwarf::{self, LineRow},
+    elf::{self, Elf},
+    encode::{self, Encoding},
     error::{self, CompileErr, InstructionCompileErr},
+    link::{self, LinkedData, MemoryImage, Section},
+    memory::{Memory, MemoryMap},
+    preprocess::{self, Frame, SourceFile, SourceLine},
     utils,
 };
 
@@ -25,7 +38,7 @@ impl MemSize {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
 /// Shifts applied to registers. Shifts an element by k bits, k should be <= 32.
 /// TODO: Use this enum
 pub enum Shift {
@@ -36,7 +49,7 @@ pub enum Shift {
     // RRX,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum ITStatus {
     OUT,
     IN,
@@ -56,7 +69,7 @@ pub enum InputStatus {
     None,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum Label {
     Index(usize),
     CR,
@@ -66,6 +79,24 @@ pub enum Label {
     GetChar,
     GetNumber,
 }
+impl Label {
+    /// Names of the predefined subroutines.
+    pub fn predefined_names() -> [&'static str; 6] {
+        ["cr", "value", "printchar", "getchar", "getnumber", "printf"]
+    }
+}
+/// Position of a built-in subroutine in Label::predefined_names, used to place its code.
+pub fn builtin_index(label: Label) -> u32 {
+    match label {
+        Label::CR => 0,
+        Label::VALUE => 1,
+        Label::PRINTCHAR => 2,
+        Label::GetChar => 3,
+        Label::GetNumber => 4,
+        Label::PRINTF => 5,
+        Label::Index(_) => 0,
+    }
+}
 impl FromStr for Label {
     type Err = Vec<String>;
 
@@ -85,117 +116,344 @@ impl FromStr for Label {
     }
 }
 
+/// What a label refers to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolValue {
+    /// Index of an instruction in the .text section.
+    Code(usize),
+    /// Memory address in the .rodata, .data, or .bss section.
+    Data(u32),
+}
+
+/// Where a label can be used.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Binding {
+    /// Only visible inside the file that defines it.
+    Local,
+    /// Visible in every file, exported with .global
+    Global,
+    /// Visible in every file, exported with .weak. A global label with the same name takes precedence.
+    Weak,
+}
+impl Binding {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Self::Local => "local",
+            Self::Global => "global",
+            Self::Weak => "weak",
+        }
+    }
+}
+
+/// A label definition.
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub value: SymbolValue,
+    pub binding: Binding,
+    /// Marked as a function with .thumb_func or ".type name, %function"
+    pub function: bool,
+    /// The line that defines the label.
+    pub line: SourceLine,
+}
+
 /// Contains all labels, and handles all label logic
+/// Labels are local to the file that defines them, unless they are exported with .global or .weak
 #[derive(Debug)]
 pub struct Labels {
-    global_labels: HashMap<String, usize>,
-    local_labels: HashMap<String, usize>,
+    /// The labels defined in each file, in the same order as the files.
+    files: Vec<HashMap<String, Symbol>>,
+    /// The labels exported with .global or .weak
+    globals: HashMap<String, Symbol>,
+    /// Index of the file being compiled.
+    current_file: usize,
 }
 impl Labels {
-    fn new() -> Self {
-        Self {
-            global_labels: HashMap::new(),
-            local_labels: HashMap::new(),
-        }
-    }
-    pub fn get_global_labels(config: &compile::Config) -> Result<Self, Vec<String>> {
-        let mut labels = Self::new();
+    /// Builds the symbol table of every file.
+    /// Labels followed by a .string variable in the .text section are data labels, the link step moves the string to .rodata.
+    /// Returns errors for invalid labels, labels defined twice, labels named after a built-in subroutine, and .extern labels that no file exports.
+    pub fn collect(sources: &[SourceFile], linked: &LinkedData) -> Result<Self, Vec<String>> {
+        let mut labels = Labels {
+            files: Vec::new(),
+            globals: HashMap::new(),
+            current_file: 0,
+        };
         // The global PC index, used for labels.
         let mut pc = 0usize;
         let mut errors = CompileErr::new();
-        let global_regex = Regex::new(r"\s*.global\s+\w+\s*").unwrap();
-
-        for (file_name, file_content) in config.read_contents()? {
-            errors.update_current_file(file_name.clone());
-            // get all local labels first
-            labels.get_local_labels(&file_content, &mut pc, &mut errors);
-
-            // get all global directives in a file
-            for mat in global_regex.find_iter(&file_content) {
-                let line = compile::preprocess_line(mat.as_str());
-                let words: Vec<&str> = line.split_whitespace().collect();
-                // get the label name and index
-                let (label, index) = (
-                    words[1].to_string(),
-                    *labels.local_labels.get(words[1]).ok_or_else(|| {
-                        CompileErr::message(format!(
+        // labels declared with .extern, checked once every file is read.
+        let mut externs: Vec<(usize, &str, &SourceLine)> = Vec::new();
+
+        for (file_index, source) in sources.iter().enumerate() {
+            let linked_labels = linked.labels(file_index);
+            let data_labels = linked_labels.iter().map(|label| Symbol {
+                name: label.name.clone(),
+                value: SymbolValue::Data(label.address),
+                binding: Binding::Local,
+                function: false,
+                line: label.line.clone(),
+            });
+            let mut symbols: HashMap<String, Symbol> = HashMap::new();
+            // the label of a .string variable in the .text section is only a data label.
+            let code_labels = Self::get_local_labels(&source.lines, &mut pc, &mut errors)
+                .into_iter()
+                .filter(|symbol| {
+                    !linked_labels
+                        .iter()
+                        .any(|label| label.name == symbol.name && label.line == symbol.line)
+                });
+            for symbol in code_labels.chain(data_labels) {
+                errors.update_location(&symbol.line);
+                if Label::predefined_names().contains(&symbol.name.as_str()) {
+                    errors.push_message(&format!(
+                        "Label \"{}\" has the same name as a built-in subroutine, rename the label.",
+                        symbol.name
+                    ));
+                } else if let Some(previous) = symbols.get(&symbol.name) {
+                    errors.push_message(&format!(
+                        "Label \"{}\" is already defined on {}.",
+                        symbol.name, previous.line.location
+                    ));
+                } else {
+                    symbols.insert(symbol.name.clone(), symbol);
+                }
+            }
+            // mark the labels of ".type name, %function" as functions
+            for line in source
+                .lines
+                .iter()
+                .filter(|line| line.text.to_lowercase().starts_with(".type"))
+            {
+                let name = line.text[".type".len()..]
+                    .split(',')
+                    .next()
+                    .unwrap_or("")
+                    .trim();
+                let is_function = line.text.to_lowercase().contains("func");
+                match symbols.get_mut(name) {
+                    Some(symbol) => symbol.function |= is_function,
+                    None => {
+                        errors.update_location(line);
+                        errors.push_message(&format!(
+                            "Label \"{}\" is not defined in the file \"{}\".",
+                            name, source.name
+                        ));
+                    }
+                }
+            }
+            // get all .global, .weak, and .extern directives in a file
+            for line in &source.lines {
+                let (directive, args) = line
+                    .text
+                    .split_once(char::is_whitespace)
+                    .unwrap_or((&line.text, ""));
+                let binding = match directive {
+                    ".global" | ".globl" => Binding::Global,
+                    ".weak" => Binding::Weak,
+                    ".extern" => Binding::Local,
+                    _ => continue,
+                };
+                errors.update_location(line);
+                for name in args.split(',').map(str::trim) {
+                    if name.is_empty() {
+                        errors.push_message(&format!("{} must be followed by a label.", directive));
+                    } else if directive == ".extern" {
+                        externs.push((file_index, name, line));
+                    } else if let Some(symbol) = symbols.get_mut(name) {
+                        symbol.binding = binding;
+                        labels.export(symbol.clone(), &mut errors);
+                    } else if binding == Binding::Global {
+                        errors.push_message(&format!(
                             "Global label \"{}\" is not defined in the file \"{}\".",
-                            words[1], file_name
-                        ))
-                    })?,
-                );
-                if let Some(_) = labels.global_labels.insert(label, index) {
-                    return Err(CompileErr::message(format!("Global label \"{}\" was already defined, attempting to overwrite global label in file \"{}\".", words[1], file_name)));
+                            name, source.name
+                        ));
+                    }
+                    // an undefined .weak label is a reference to a label that may not exist.
                 }
             }
+            labels.files.push(symbols);
         }
-        labels.local_labels.clear();
+        for (file_index, name, line) in externs {
+            if !labels.files[file_index].contains_key(name) && !labels.globals.contains_key(name) {
+                errors.update_location(line);
+                errors.push_message(&format!(
+                    "External label \"{}\" is not exported by any file, add \".global {}\" to the file that defines it.",
+                    name, name
+                ));
+            }
+        }
+        errors.result()?;
         Ok(labels)
     }
-    /// Retrieves all local labels inside a file, and returns local string variables
-    pub fn get_local_labels(
-        &mut self,
-        file_content: &String,
+    /// Adds a label to the labels visible in every file.
+    fn export(&mut self, symbol: Symbol, errors: &mut CompileErr) {
+        match self.globals.get(&symbol.name) {
+            // the label was exported twice
+            Some(previous) if previous.line.location == symbol.line.location => (),
+            Some(previous)
+                if previous.binding == Binding::Global && symbol.binding == Binding::Global =>
+            {
+                errors.push_message(&format!(
+                    "Global label \"{}\" is defined twice, on {} and on {}.",
+                    symbol.name, previous.line.location, symbol.line.location
+                ));
+            }
+            // keep the global label, or the first weak label
+            Some(previous)
+                if previous.binding == Binding::Global || symbol.binding == Binding::Weak => {}
+            _ => {
+                self.globals.insert(symbol.name.clone(), symbol);
+            }
+        }
+    }
+    /// Retrieves the labels in the .text section of a file, and the index of the instruction they point to.
+    fn get_local_labels(
+        lines: &[SourceLine],
         pc: &mut usize,
         errors: &mut CompileErr,
-    ) -> (Vec<String>, HashMap<String, usize>) {
+    ) -> Vec<Symbol> {
         let re_label = Regex::new(r"^[a-zA-Z_]+\w*\s*:$").unwrap();
-        let mut local_labels: HashMap<String, usize> = HashMap::new();
-        let mut last_label: Option<String> = None;
-        // stores a list of string variables
-        let mut strings: Vec<String> = Vec::new();
-        // key -> label, value -> label's index in list of string variables
-        let mut string_labels: HashMap<String, usize> = HashMap::new();
-
-        for (line_number, line) in file_content.lines().enumerate() {
-            errors.update_line_number(line_number); // update line number for error messages
-            let line = compile::preprocess_line(line);
-
-            // skip if white space, or directive, or IT instruction or directive
-            if line.starts_with(".string") {
-                let line = line.split_once('\"');
-                if let Some((_, mes)) = line {
-                    strings.push(mes[..mes.len() - 1].to_string());
-                    if let Some(label) = &last_label {
-                        string_labels.insert(label.clone(), strings.len() - 1);
-                    } else {
-                        errors.push_message(".string variable should have a label beforehand.");
-                    }
-                } else {
-                    errors.push_message("Invalid syntax.");
-                }
+        let mut symbols: Vec<Symbol> = Vec::new();
+        // the last .thumb_func directive, which marks the next label as a function.
+        let mut thumb_func: Option<&SourceLine> = None;
+
+        for (section, source_line) in link::sections(lines) {
+            errors.update_location(source_line); // update line number for error messages
+            let line = source_line.text.as_str();
+
+            if line.to_lowercase() == ".thumb_func" {
+                thumb_func = Some(source_line);
+                continue;
             }
-            if line.is_empty() || line.to_lowercase().starts_with("it") || line.starts_with('.') {
+            // skip if white space, or IT instruction or directive
+            if line.is_empty()
+                || compile::is_if_then_block(&line.to_lowercase())
+                || line.starts_with('.')
+            {
                 continue;
             }
-            // If it is a label, store it in the Hashmap of local_labels.
+            // If it is a label, store it in the list of labels.
             if line.ends_with(':') {
-                if re_label.is_match(line) {
-                    let label = line.trim_end_matches(':').to_string();
-                    local_labels.insert(label.clone(), *pc);
-                    last_label = Some(label);
-                } else {
+                if !re_label.is_match(line) && !preprocess::is_numeric_label(line) {
                     errors.push_message("Invalid label.");
+                } else if section == Section::Text {
+                    // labels in the data sections are placed by the link step.
+                    symbols.push(Symbol {
+                        name: line.trim_end_matches(':').trim().to_string(),
+                        value: SymbolValue::Code(*pc),
+                        binding: Binding::Local,
+                        function: thumb_func.take().is_some(),
+                        line: source_line.clone(),
+                    });
                 }
-            } else {
+            } else if section == Section::Text {
                 *pc += 1; // increment PC for each instruction.
             }
+            // the label after .thumb_func was not in the .text section, or an instruction came first.
+            if let Some(directive) = thumb_func.take() {
+                errors.update_location(directive);
+                errors
+                    .push_message(".thumb_func must be followed by a label in the .text section.");
+            }
+        }
+        if let Some(directive) = thumb_func {
+            errors.update_location(directive);
+            errors.push_message(".thumb_func must be followed by a label in the .text section.");
+        }
+        symbols
+    }
+    /// Returns the labels exported with .global or .weak
+    pub fn globals(&self) -> impl Iterator<Item = &Symbol> {
+        self.globals.values()
+    }
+    /// Returns the labels defined in every file, in the order of the files and their lines.
+    pub fn symbols(&self) -> Vec<&Symbol> {
+        (0..self.files.len())
+            .flat_map(|file_index| self.file_symbols(file_index))
+            .collect()
+    }
+    /// Returns the labels defined in a file, in the order of their lines.
+    pub fn file_symbols(&self, file_index: usize) -> Vec<&Symbol> {
+        let mut symbols: Vec<&Symbol> = self.files[file_index].values().collect();
+        symbols.sort_by_key(|symbol| symbol.line.location.line_number);
+        symbols
+    }
+    /// Sets the file whose labels are used, called before compiling each file.
+    pub fn set_current_file(&mut self, file_index: usize) {
+        self.current_file = file_index;
+    }
+    /// Finds a label defined in a file, or exported by any file.
+    /// A weak label is replaced by the global label with the same name, even inside its own file.
+    pub fn lookup(&self, file_index: usize, label: &str) -> Option<&Symbol> {
+        match self
+            .files
+            .get(file_index)
+            .and_then(|symbols| symbols.get(label))
+        {
+            Some(symbol) if symbol.binding != Binding::Weak => Some(symbol),
+            _ => self.globals.get(label),
+        }
+    }
+    /// Returns the value of a label used in a file, the instruction index of a code label or the address of a data label.
+    pub fn value(&self, file_index: usize, label: &str) -> Option<i64> {
+        self.lookup(file_index, label)
+            .map(|symbol| match symbol.value {
+                SymbolValue::Code(index) => index as i64,
+                SymbolValue::Data(address) => i64::from(address),
+            })
+    }
+    /// Returns the names of the data labels visible to the current file.
+    fn data_labels(&self) -> impl Iterator<Item = &String> {
+        self.files
+            .get(self.current_file)
+            .into_iter()
+            .flat_map(|symbols| symbols.values())
+            .chain(self.globals.values())
+            .filter(|symbol| matches!(symbol.value, SymbolValue::Data(_)))
+            .map(|symbol| &symbol.name)
+    }
+    /// Returns the memory address of a data label.
+    pub fn get_address(&self, label: &str) -> Option<u32> {
+        match self.lookup(self.current_file, label)?.value {
+            SymbolValue::Data(address) => Some(address),
+            SymbolValue::Code(_) => None,
         }
-        self.local_labels = local_labels;
-        (strings, string_labels)
     }
     fn get(&self, label: &str) -> Result<Label, Vec<String>> {
-        if self.global_labels.contains_key(label) {
-            Ok(Label::Index(*self.global_labels.get(label).unwrap()))
-        } else if self.local_labels.contains_key(label) {
-            Ok(Label::Index(*self.local_labels.get(label).unwrap()))
-        } else {
-            Label::from_str(label)
+        match self.lookup(self.current_file, label).map(|symbol| symbol.value) {
+            Some(SymbolValue::Code(index)) => Ok(Label::Index(index)),
+            Some(SymbolValue::Data(_)) => Err(CompileErr::message(format!(
+                "Label \"{}\" is in a data section, only labels in the .text section can be branched to.",
+                label
+            ))),
+            None => Label::from_str(label).map_err(|errors| {
+                errors
+                    .into_iter()
+                    .map(|err| error::with_suggestion(err, self.suggest(label)))
+                    .collect()
+            }),
         }
     }
+    /// Returns the known label closest to a misspelled label.
+    pub fn suggest(&self, label: &str) -> Option<&str> {
+        let mut candidates: Vec<&str> = self
+            .files
+            .get(self.current_file)
+            .into_iter()
+            .flat_map(|symbols| symbols.keys())
+            .chain(self.globals.keys())
+            .map(String::as_str)
+            .filter(|name| !preprocess::is_numeric_label(name))
+            .collect();
+        // sort so suggestions do not depend on hashmap order.
+        candidates.sort_unstable();
+        candidates.dedup();
+        candidates.extend(Label::predefined_names());
+        utils::closest_match(label, candidates)
+    }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
 #[allow(non_camel_case_types, non_snake_case)]
 /// Instruction Sub-Category, named Category for convenience.
 pub enum Operands {
@@ -347,13 +605,15 @@ impl FromStr for Operands {
                 label: args[1],
             })
         } else {
-            Err(error::invalid_args(line))
+            let mut errors = error::invalid_args(line);
+            errors.extend(utils::suggest_register(line));
+            Err(errors)
         }
     }
 }
 
 /// Condition Codes
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
 pub enum ConditionCode {
     EQ,
     NE,
@@ -438,12 +698,13 @@ impl FromStr for ConditionCode {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 /// Metadata & Extensions that may be attached to mnemonics.
 pub struct MnemonicExtension {
     pub cc: Option<ConditionCode>, // <cc> conditional code
     pub s: bool,                   // s flag
     pub w: bool,                   // .w extension
+    pub n: bool,                   // .n extension
     pub it_status: ITStatus,       // in/out/last
 }
 impl MnemonicExtension {
@@ -452,41 +713,105 @@ impl MnemonicExtension {
             cc: None,
             s: false,
             w: false,
+            n: false,
             it_status: ITStatus::OUT,
         }
     }
 }
 /// Contains an instruction line and metadata
-struct Line {
+/// Serialized by the program cache, the address and machine code are set again when the program is encoded.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Line {
     mnemonic: String,
     /// Used for error messages
     file_name: String,
     line_number: usize,
+    /// The macro invocations and includes the line came from, used for error messages.
+    backtrace: Vec<Frame>,
     extension: MnemonicExtension,
     is_breakpoint: bool,
     /// Used to run assembly code.
     operands: Operands,
+    /// The IT instruction before this line, and its line number.
+    it_instruction: Option<(u16, usize)>,
+    /// Address of the instruction, set by Program::encode
+    #[serde(skip)]
+    address: u32,
+    /// Machine code, set by Program::encode
+    #[serde(skip)]
+    encoding: Option<Encoding>,
 }
 impl Line {
     fn new(
         mnemonic: String,
-        file_name: String,
-        line_number: usize,
+        source_line: &SourceLine,
         extension: MnemonicExtension,
         is_breakpoint: bool,
         operands: Operands,
     ) -> Self {
         Line {
             mnemonic,
-            file_name,
-            line_number,
+            file_name: source_line.location.file_name.clone(),
+            line_number: source_line.location.line_number,
+            backtrace: source_line.backtrace.clone(),
             extension,
             is_breakpoint,
             operands,
+            it_instruction: None,
+            address: 0,
+            encoding: None,
+        }
+    }
+    /// The location and backtrace of the line, without its text, used for error messages.
+    fn source_line(&self) -> SourceLine {
+        let mut source_line = SourceLine::new(String::new(), &self.file_name, self.line_number);
+        source_line.backtrace = self.backtrace.clone();
+        source_line
+    }
+    /// Address of the first halfword of the line, which is the IT instruction if the line has one.
+    fn start_address(&self) -> u32 {
+        match self.it_instruction {
+            Some(_) => self.address - 2,
+            None => self.address,
         }
     }
 }
 
+/// An executable loaded from an ELF file.
+#[derive(Debug, serde::Serialize)]
+pub struct LoadedExecutable {
+    /// The disassembled code with its labels, the line numbers of the program refer to this text.
+    pub listing: String,
+    /// The initial SP, read from the vector table.
+    pub stack_pointer: u32,
+    /// Index of the line at the entry point.
+    pub entry: usize,
+}
+
+/// The machine code of an instruction, sent to the frontend.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct InstructionEncoding {
+    pub file_name: String,
+    pub line_number: usize,
+    pub address: u32,
+    /// Halfwords in hexadecimal, ex: "f04f 0101"
+    pub machine_code: String,
+}
+
+/// A row of a listing file, the machine code of an instruction or IT instruction.
+#[derive(Debug, Clone)]
+pub struct ListedInstruction {
+    pub file_name: String,
+    pub line_number: usize,
+    pub address: u32,
+    /// Halfwords in hexadecimal, ex: "f04f 0101"
+    pub machine_code: String,
+    /// Estimated number of cycles, ex: "2-4"
+    pub cycles: &'static str,
+    /// The address and value of the literal pool entry loaded by "ldr Rt, =value"
+    pub literal: Option<(u32, u32)>,
+}
+
 /// Contains the Assembly program.
 /// Labels, lines, and a list of all Instructions
 /// Initialized at compile time, cannot be changed at runtime.
@@ -497,8 +822,14 @@ pub struct Program {
     instructions: HashMap<String, Box<dyn Instruction>>,
     /// The delay between each instruction
     delay: u16,
-    /// stores string variables
-    pub string_messages: Vec<String>,
+    /// The initial contents of the .rodata, .data, and .bss sections.
+    pub memory_image: MemoryImage,
+    /// An IT instruction waiting for the next instruction line, and its line number.
+    pending_it: Option<(u16, usize)>,
+    /// Values loaded with "ldr Rt, =value", stored after the code.
+    literal_pool: Vec<u32>,
+    /// The labels of every file, written to the symbol table of exported ELF files.
+    pub symbols: Vec<Symbol>,
 }
 
 impl Program {
@@ -507,32 +838,62 @@ impl Program {
             lines: Vec::new(),
             instructions: all_instructions(),
             delay: 0,
-            string_messages: Vec::new(),
+            memory_image: MemoryImage::default(),
+            pending_it: None,
+            literal_pool: Vec::new(),
+            symbols: Vec::new(),
         }
     }
     pub fn reset(&mut self, delay: u16) {
         self.lines.clear();
         self.delay = delay;
-        self.string_messages.clear();
+        self.memory_image = MemoryImage::default();
+        self.pending_it = None;
+        self.literal_pool.clear();
+        self.symbols.clear();
+    }
+    /// Number of compiled lines.
+    pub fn line_count(&self) -> usize {
+        self.lines.len()
+    }
+    /// Returns the compiled lines from an index onwards, ex: the lines of the last compiled file.
+    pub fn lines_from(&self, start: usize) -> &[Line] {
+        &self.lines[start..]
+    }
+    /// Appends lines that were compiled before, ex: the lines of a file from the program cache.
+    pub fn extend_lines(&mut self, lines: &[Line]) {
+        self.lines.extend_from_slice(lines);
+    }
+    /// Loads the data sections into memory, and the machine code if a memory region holds the code address.
+    pub fn load_memory(&self, processor: &mut Processor) {
+        processor
+            .memory
+            .load(encode::TEXT_ADDRESS, &self.code_image());
+        processor.memory.load_image(&self.memory_image);
     }
     /// Pushes a new compiled line.
     fn push_line(
         &mut self,
         mnemonic: String,
-        file_name: &String,
-        line_number: usize,
+        source_line: &SourceLine,
         extension: MnemonicExtension,
         is_breakpoint: bool,
         operands: Operands,
     ) {
-        self.lines.push(Line::new(
-            mnemonic,
-            file_name.clone(),
-            line_number,
-            extension,
-            is_breakpoint,
-            operands,
-        ));
+        let mut line = Line::new(mnemonic, source_line, extension, is_breakpoint, operands);
+        line.it_instruction = self.pending_it.take();
+        self.lines.push(line);
+    }
+    /// Stores an IT instruction, it is encoded before the next instruction line.
+    pub fn set_it_instruction(&mut self, line: &str, line_number: usize) {
+        self.pending_it = encode::it_instruction(line).map(|it| (it, line_number));
+    }
+    /// Returns the instruction mnemonic closest to the first word of an invalid line.
+    pub fn suggest_mnemonic(&self, line: &str) -> Option<&str> {
+        let word = line.split_whitespace().next()?;
+        let mut mnemonics: Vec<&str> = self.instructions.keys().map(String::as_str).collect();
+        mnemonics.sort_unstable();
+        utils::closest_match(word, mnemonics)
     }
     /// Returns the mnemonic of a given line, if there is one.
     /// Warning: In implementation, the line is converted to lowercase first before being passed to this function.
@@ -546,10 +907,11 @@ impl Program {
         if self.instructions.contains_key(line) {
             return Some((line.to_string(), extension));
         }
-        // check for .w extension
-        if line.ends_with(".w") {
+        // check for .w and .n extensions
+        if line.ends_with(".w") || line.ends_with(".n") {
+            extension.w = line.ends_with(".w");
+            extension.n = line.ends_with(".n");
             line = &line[..line.len() - 2];
-            extension.w = true;
             if self.instructions.contains_key(line) {
                 return Some((line.to_string(), extension));
             }
@@ -597,7 +959,7 @@ impl Program {
         // push compiled line onto instruction stack. Returns compile errors if any.
         if utils::is_label(line) {
             // get the string label
-            let label = Regex::new(r"\w+$").unwrap().find(line).unwrap().as_str();
+            let label = Regex::new(r"[\w$]+$").unwrap().find(line).unwrap().as_str();
             // Validate label
             let operands = Operands::label {
                 label: labels.get(label)?,
@@ -611,7 +973,7 @@ impl Program {
         &mut self,
         _extension: &MnemonicExtension,
         line: &str,
-        string_labels: &HashMap<String, usize>,
+        labels: &Labels,
     ) -> Result<Operands, Vec<String>> {
         let mut errors = InstructionCompileErr::new();
         let operands = Operands::from_str(line)?;
@@ -625,14 +987,28 @@ impl Program {
                 errors.check_mem_left_shift(shift);
             }
             Operands::Rt_label { Rt, .. } => {
-                // get the string label
-                let label = Regex::new(r"\w+$").unwrap().find(line).unwrap().as_str();
-                return Ok(Operands::Rt_label {
-                    Rt,
-                    label: *string_labels
-                        .get(label)
-                        .ok_or(InstructionCompileErr::invalid_label(label))?,
-                });
+                // get the data label
+                let label = Regex::new(r"[\w$]+$").unwrap().find(line).unwrap().as_str();
+                // a .equ/.set symbol loads its value
+                if let Some(value) = utils::symbol_value(label) {
+                    return Ok(Operands::Rt_imm {
+                        Rt,
+                        label: value as u32,
+                    });
+                }
+                // data labels, including .string variables, load their memory address.
+                let address = labels.get_address(label).ok_or_else(|| {
+                    let mut names: Vec<&str> = labels.data_labels().map(String::as_str).collect();
+                    names.sort_unstable();
+                    names.dedup();
+                    InstructionCompileErr::invalid_label(label)
+                        .into_iter()
+                        .map(|err| {
+                            error::with_suggestion(err, utils::closest_match(label, names.clone()))
+                        })
+                        .collect::<Vec<String>>()
+                })?;
+                return Ok(Operands::Rt_imm { Rt, label: address });
             }
             _ => return Err(error::invalid_args(line)),
         }
@@ -643,14 +1019,12 @@ impl Program {
     pub fn compile_instruction(
         &mut self,
         mnemonic: String,
-        file_name: &String,
-        line_number: usize,
+        source_line: &SourceLine,
         extension: MnemonicExtension,
         is_breakpoint: bool,
         original_line: &str,
         line: &String,
         labels: &Labels,
-        string_labels: &HashMap<String, usize>,
     ) -> Result<(), Vec<String>> {
         // get instruction
         let instruction = self
@@ -663,21 +1037,576 @@ impl Program {
             // compile branch instructions separately.
             self.compile_branch_instruction(&extension, original_line, labels)?
         } else if mnemonic == "ldr" {
-            self.compile_ldr_instruction(&extension, original_line, string_labels)?
+            self.compile_ldr_instruction(&extension, original_line, labels)?
         } else {
             instruction.get_operands(&extension, line)?
         };
-        self.push_line(
-            mnemonic,
-            file_name,
-            line_number,
-            extension,
-            is_breakpoint,
-            operands,
-        );
+        self.push_line(mnemonic, source_line, extension, is_breakpoint, operands);
 
         Ok(())
     }
+    /// Encodes every line into Thumb-2 machine code, and assigns the addresses of the lines.
+    /// Lines start with their narrow encoding and only grow, so the layout always settles.
+    /// The code of the built-in subroutines and the literal pool are placed after the last line.
+    pub fn encode(&mut self) -> Result<(), Vec<String>> {
+        let mut literal_pool: Vec<u32> = Vec::new();
+        for line in &self.lines {
+            if let Operands::Rt_imm { label, .. } = line.operands {
+                literal_pool.push(label);
+            }
+        }
+        literal_pool.sort_unstable();
+        literal_pool.dedup();
+        self.literal_pool = literal_pool;
+
+        let mut wide: Vec<bool> = self.lines.iter().map(|line| line.extension.w).collect();
+        loop {
+            let mut address = encode::TEXT_ADDRESS;
+            for (line, &is_wide) in self.lines.iter_mut().zip(&wide) {
+                if line.it_instruction.is_some() {
+                    address += 2;
+                }
+                line.address = address;
+                address += if is_wide { 4 } else { 2 };
+            }
+            let builtins = address;
+            let literal_pool = encode::literal_pool_address(builtins);
+            let starts: Vec<u32> = self.lines.iter().map(Line::start_address).collect();
+            let target = |label: Label| match label {
+                Label::Index(index) => starts.get(index).copied().unwrap_or(builtins),
+                builtin => builtins + builtin_index(builtin) * encode::BUILTIN_SIZE,
+            };
+            let literal = |value: u32| {
+                let index = self.literal_pool.binary_search(&value).unwrap_or(0);
+                literal_pool + 4 * index as u32
+            };
+
+            let mut errors = CompileErr::new();
+            let mut encodings: Vec<Option<Encoding>> = Vec::with_capacity(self.lines.len());
+            let mut grew = false;
+            for (line, is_wide) in self.lines.iter().zip(wide.iter_mut()) {
+                let context = encode::Context {
+                    address: line.address,
+                    target: &target,
+                    literal: &literal,
+                };
+                match encode::encode(
+                    &line.mnemonic,
+                    &line.extension,
+                    &line.operands,
+                    &context,
+                    *is_wide,
+                ) {
+                    Ok(encoding) => {
+                        if encoding.size() == 4 && !*is_wide {
+                            *is_wide = true;
+                            grew = true;
+                        }
+                        encodings.push(Some(encoding));
+                    }
+                    Err(err) => {
+                        errors.update_location(&line.source_line());
+                        errors.push_message(&err);
+                        encodings.push(None);
+                    }
+                }
+            }
+            if grew {
+                continue;
+            }
+            errors.result()?;
+            for (line, encoding) in self.lines.iter_mut().zip(encodings) {
+                line.encoding = encoding;
+            }
+            return Ok(());
+        }
+    }
+    /// Address of the code of the first built-in subroutine, which comes right after the last line.
+    pub fn builtins_address(&self) -> u32 {
+        self.lines
+            .last()
+            .and_then(|line| Some(line.address + line.encoding?.size()))
+            .unwrap_or(encode::TEXT_ADDRESS)
+    }
+    /// Returns the machine code of every instruction, including IT instructions.
+    /// Should only be called after the program was encoded.
+    pub fn encodings(&self) -> Vec<InstructionEncoding> {
+        let mut encodings = Vec::new();
+        for line in &self.lines {
+            if let Some((it, line_number)) = line.it_instruction {
+                encodings.push(InstructionEncoding {
+                    file_name: line.file_name.clone(),
+                    line_number,
+                    address: line.address - 2,
+                    machine_code: Encoding::Narrow(it).to_string(),
+                });
+            }
+            if let Some(encoding) = line.encoding {
+                encodings.push(InstructionEncoding {
+                    file_name: line.file_name.clone(),
+                    line_number: line.line_number,
+                    address: line.address,
+                    machine_code: encoding.to_string(),
+                });
+            }
+        }
+        encodings
+    }
+    /// Returns the rows of the listing files, in the same order as Program::encodings.
+    /// Should only be called after the program was encoded.
+    pub fn listed_instructions(&self) -> Vec<ListedInstruction> {
+        let literal_pool = encode::literal_pool_address(self.builtins_address());
+        let mut rows = Vec::new();
+        for line in &self.lines {
+            if let Some((it, line_number)) = line.it_instruction {
+                rows.push(ListedInstruction {
+                    file_name: line.file_name.clone(),
+                    line_number,
+                    address: line.address - 2,
+                    machine_code: Encoding::Narrow(it).to_string(),
+                    cycles: "1",
+                    literal: None,
+                });
+            }
+            let literal = match line.operands {
+                Operands::Rt_imm { label, .. } => Some(label),
+                _ => None,
+            };
+            if let Some(encoding) = line.encoding {
+                rows.push(ListedInstruction {
+                    file_name: line.file_name.clone(),
+                    line_number: line.line_number,
+                    address: line.address,
+                    machine_code: encoding.to_string(),
+                    cycles: self
+                        .instructions
+                        .get(&line.mnemonic)
+                        .map_or("1", |instruction| instruction.cycles(&line.extension)),
+                    literal: literal.and_then(|value| {
+                        let index = self.literal_pool.binary_search(&value).ok()?;
+                        Some((literal_pool + 4 * index as u32, value))
+                    }),
+                });
+            }
+        }
+        rows
+    }
+    /// The address ranges of the code of each file, consecutive lines of the same file form a range.
+    /// Should only be called after the program was encoded.
+    pub fn text_ranges(&self) -> Vec<(String, Range<u32>)> {
+        let mut ranges: Vec<(String, Range<u32>)> = Vec::new();
+        for line in &self.lines {
+            let end = line.address + line.encoding.map_or(0, |encoding| encoding.size());
+            match ranges.last_mut() {
+                Some((file_name, range)) if *file_name == line.file_name => range.end = end,
+                _ => ranges.push((line.file_name.clone(), line.start_address()..end)),
+            }
+        }
+        ranges
+    }
+    /// Address of a label, code labels refer to the first halfword of their line.
+    pub fn symbol_address(&self, symbol: &Symbol) -> u32 {
+        match symbol.value {
+            SymbolValue::Code(index) => self
+                .lines
+                .get(index)
+                .map_or(self.builtins_address(), Line::start_address),
+            SymbolValue::Data(address) => address,
+        }
+    }
+    /// Returns the machine code of the program, which starts at encode::TEXT_ADDRESS.
+    /// Contains the instructions, the code of the built-in subroutines, and the literal pool.
+    /// Should only be called after the program was encoded.
+    pub fn code_image(&self) -> Vec<u8> {
+        let mut code: Vec<u8> = Vec::new();
+        for line in &self.lines {
+            if let Some((it, _)) = line.it_instruction {
+                code.extend(it.to_le_bytes());
+            }
+            if let Some(encoding) = line.encoding {
+                code.extend(encoding.bytes());
+            }
+        }
+        for number in 0..Label::predefined_names().len() as u8 {
+            code.extend(encode::svc(number).to_le_bytes());
+            code.extend(encode::BX_LR.to_le_bytes());
+        }
+        let literal_pool = encode::literal_pool_address(self.builtins_address());
+        code.resize((literal_pool - encode::TEXT_ADDRESS) as usize, 0);
+        for value in &self.literal_pool {
+            code.extend(value.to_le_bytes());
+        }
+        code
+    }
+    /// Returns the label of the line or built-in subroutine at an address.
+    pub fn label_at(&self, address: u32) -> Option<Label> {
+        if let Some(index) = self
+            .lines
+            .iter()
+            .position(|line| line.start_address() == address)
+        {
+            return Some(Label::Index(index));
+        }
+        let offset = address.checked_sub(self.builtins_address())?;
+        let name = Label::predefined_names()
+            .get((offset / encode::BUILTIN_SIZE) as usize)
+            .copied()?;
+        if offset % encode::BUILTIN_SIZE == 0 {
+            Label::from_str(name).ok()
+        } else {
+            None
+        }
+    }
+    /// Name of a branch target in disassembled code, lines are named by their address.
+    pub fn label_name(&self, label: Label) -> String {
+        match label {
+            Label::Index(index) => format!(
+                "{:#010x}",
+                self.lines
+                    .get(index)
+                    .map_or(self.builtins_address(), Line::start_address)
+            ),
+            builtin => Label::predefined_names()[builtin_index(builtin) as usize].to_string(),
+        }
+    }
+    /// Disassembles the machine code of the program.
+    /// name returns the name of a branch target.
+    pub fn disassemble(&self, name: &dyn Fn(Label) -> String) -> Vec<(u32, String)> {
+        decode::disassemble(
+            &self.code_image(),
+            encode::TEXT_ADDRESS,
+            &|address| self.label_at(address),
+            name,
+        )
+    }
+    /// Builds an ELF executable of the program, with a symbol table and a .debug_line table.
+    /// .data holds the initial memory contents, including .rodata and the zeroed .bss bytes.
+    /// Should only be called after the program was encoded.
+    pub fn to_elf(&self) -> Vec<u8> {
+        let code = self.code_image();
+        let code_end = encode::TEXT_ADDRESS + code.len() as u32;
+        let mut sections = vec![elf::SectionData {
+            name: ".text".into(),
+            kind: elf::SHT_PROGBITS,
+            flags: elf::SHF_ALLOC | elf::SHF_EXECINSTR,
+            address: encode::TEXT_ADDRESS,
+            data: code,
+            align: 4,
+        }];
+        // each contiguous part of memory is its own section, so the file does not hold the gaps between them
+        for (index, (address, memory)) in self
+            .memory_image
+            .contiguous_blocks()
+            .into_iter()
+            .enumerate()
+        {
+            sections.push(elf::SectionData {
+                name: match index {
+                    0 => ".data".into(),
+                    _ => format!(".data.{}", index),
+                },
+                kind: elf::SHT_PROGBITS,
+                flags: elf::SHF_ALLOC | elf::SHF_WRITE,
+                address: address as u32,
+                data: memory,
+                align: 1,
+            });
+        }
+        // the section of a data label, the data sections start at index 2
+        let data_ranges: Vec<Range<u64>> = sections[1..]
+            .iter()
+            .map(|section| {
+                section.address as u64..section.address as u64 + section.data.len() as u64
+            })
+            .collect();
+        let data_section = |address: u32| {
+            data_ranges
+                .iter()
+                .position(|range| range.contains(&(address as u64)))
+                .map_or(elf::SHN_ABS, |index| index as u16 + 2)
+        };
+
+        let mut files: Vec<String> = Vec::new();
+        let mut rows = Vec::new();
+        for encoding in self.encodings() {
+            let file = match files.iter().position(|file| *file == encoding.file_name) {
+                Some(file) => file,
+                None => {
+                    files.push(encoding.file_name);
+                    files.len() - 1
+                }
+            };
+            rows.push(LineRow {
+                address: encoding.address,
+                file,
+                line: encoding.line_number,
+            });
+        }
+        sections.push(elf::SectionData {
+            name: ".debug_line".into(),
+            kind: elf::SHT_PROGBITS,
+            flags: 0,
+            address: 0,
+            data: dwarf::debug_line(&rows, &files, self.builtins_address()),
+            align: 1,
+        });
+        sections.push(elf::SectionData {
+            name: ".ARM.attributes".into(),
+            kind: elf::SHT_ARM_ATTRIBUTES,
+            flags: 0,
+            address: 0,
+            data: elf::armv7m_attributes(),
+            align: 1,
+        });
+
+        let symbol = |name: &str, value: u32, kind: u8, section: u16| elf::Symbol {
+            name: name.into(),
+            value,
+            size: 0,
+            kind,
+            global: false,
+            section,
+        };
+        let mut symbols = vec![symbol("$t", encode::TEXT_ADDRESS, elf::STT_NOTYPE, 1)];
+        for (index, name) in Label::predefined_names().iter().enumerate() {
+            let address = self.builtins_address() + index as u32 * encode::BUILTIN_SIZE;
+            symbols.push(elf::Symbol {
+                size: encode::BUILTIN_SIZE,
+                ..symbol(name, address | 1, elf::STT_FUNC, 1)
+            });
+        }
+        let literal_pool = encode::literal_pool_address(self.builtins_address());
+        if literal_pool < code_end {
+            symbols.push(symbol("$d", literal_pool, elf::STT_NOTYPE, 1));
+        }
+        for label in &self.symbols {
+            let address = self.symbol_address(label);
+            let mut symbol = match label.value {
+                SymbolValue::Code(_) => {
+                    if label.function {
+                        symbol(&label.name, address | 1, elf::STT_FUNC, 1)
+                    } else {
+                        symbol(&label.name, address, elf::STT_NOTYPE, 1)
+                    }
+                }
+                SymbolValue::Data(_) => {
+                    symbol(&label.name, address, elf::STT_OBJECT, data_section(address))
+                }
+            };
+            symbol.global = label.binding != Binding::Local;
+            symbols.push(symbol);
+        }
+        elf::write(encode::TEXT_ADDRESS | 1, &sections, &symbols)
+    }
+    /// Loads the code and data of an ELF executable, the code is decoded into lines.
+    /// Thumb code is found with the $t and $d mapping symbols, a code section without them is all Thumb code.
+    pub fn load_elf(
+        &mut self,
+        file_name: &str,
+        elf: &Elf,
+        memory_map: &MemoryMap,
+    ) -> Result<LoadedExecutable, Vec<String>> {
+        let error = |address: u32, message: String| {
+            format!("\"{}\" {:#010x}: {}", file_name, address, message)
+        };
+        // the address ranges of Thumb code
+        let mut regions: Vec<(u32, u32)> = Vec::new();
+        for (index, section) in elf.sections.iter().enumerate() {
+            if !section.is_code() {
+                continue;
+            }
+            let mut mappings: Vec<(u32, char)> = elf
+                .symbols
+                .iter()
+                .filter(|symbol| usize::from(symbol.section) == index)
+                .filter_map(|symbol| Some((symbol.value, symbol.mapping()?)))
+                .collect();
+            mappings.sort_unstable();
+            if mappings
+                .first()
+                .map_or(true, |&(start, _)| start > section.address)
+            {
+                mappings.insert(0, (section.address, 't'));
+            }
+            let end = section.address + section.size;
+            for (position, &(start, kind)) in mappings.iter().enumerate() {
+                let region_end = mappings.get(position + 1).map_or(end, |&(next, _)| next);
+                match kind {
+                    't' if start < region_end => regions.push((start, region_end)),
+                    'a' => {
+                        return Err(vec![error(
+                            start,
+                            "ARM code is not supported, compile with -mthumb.".into(),
+                        )])
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        // find where each line starts: (line address, instruction address, IT instruction)
+        let mut starts: Vec<(u32, u32, Option<u16>)> = Vec::new();
+        for &(start, end) in &regions {
+            let halfwords = halfwords(elf.read(start, end - start).unwrap_or(&[]));
+            let (mut index, mut it, mut it_remaining) = (0, None, 0);
+            while index < halfwords.len() {
+                let address = start + 2 * index as u32;
+                match decode::it_conditions(halfwords[index]) {
+                    Some(conditions) if it_remaining == 0 => {
+                        it = Some((halfwords[index], address));
+                        it_remaining = conditions.len();
+                        index += 1;
+                        continue;
+                    }
+                    _ => (),
+                }
+                let line_address = it.map_or(address, |(_, it_address)| it_address);
+                starts.push((line_address, address, it.take().map(|(it, _)| it)));
+                it_remaining = it_remaining.saturating_sub(1);
+                index += if decode::is_wide(halfwords[index]) {
+                    2
+                } else {
+                    1
+                };
+            }
+        }
+        let symbol_at = |address: u32| {
+            elf.symbols
+                .iter()
+                .find(|symbol| symbol.is_label() && symbol.value == address)
+        };
+        let line_at = |address: u32| {
+            starts
+                .iter()
+                .position(|&(line_address, instruction_address, _)| {
+                    line_address == address || instruction_address == address
+                })
+        };
+        let label = |address: u32| {
+            let builtin = symbol_at(address).and_then(|symbol| Label::from_str(&symbol.name).ok());
+            builtin.or_else(|| line_at(address).map(Label::Index))
+        };
+        let name = |label: Label| match label {
+            Label::Index(index) => {
+                let address = starts[index].0;
+                symbol_at(address)
+                    .map_or(format!("{:#010x}", address), |symbol| symbol.name.clone())
+            }
+            builtin => Label::predefined_names()[builtin_index(builtin) as usize].to_string(),
+        };
+        let word = |address: u32| elf.word(address);
+
+        // decode the lines, and write the listing
+        let mut errors = Vec::new();
+        let mut listing: Vec<String> = Vec::new();
+        let mut it_block: VecDeque<ConditionCode> = VecDeque::new();
+        for &(line_address, address, it) in &starts {
+            let mut labels: Vec<&str> = elf
+                .symbols
+                .iter()
+                .filter(|symbol| symbol.is_label() && symbol.value == line_address)
+                .map(|symbol| symbol.name.as_str())
+                .collect();
+            labels.dedup();
+            listing.extend(labels.iter().map(|label| format!("{}:", label)));
+            let it_instruction = it.map(|it| {
+                it_block.extend(decode::it_conditions(it).unwrap_or_default());
+                listing.push(format!("    {}", decode::it_text(it).unwrap_or_default()));
+                (it, listing.len())
+            });
+            let code: Vec<u16> = halfwords(
+                elf.read(address, 4)
+                    .or_else(|| elf.read(address, 2))
+                    .unwrap_or(&[]),
+            );
+            let context = decode::Context {
+                address,
+                label: &label,
+                word: &word,
+            };
+            match decode::decode(&code, &mut it_block, &context) {
+                Ok(decoded) => {
+                    listing.push(format!("    {}", decode::format(&decoded, &name)));
+                    let mut line = Line::new(
+                        decoded.mnemonic,
+                        &SourceLine::new(String::new(), file_name, listing.len()),
+                        decoded.extension,
+                        false,
+                        decoded.operands,
+                    );
+                    line.it_instruction = it_instruction;
+                    line.address = address;
+                    line.encoding = Some(decoded.encoding);
+                    self.lines.push(line);
+                }
+                Err(err) => errors.push(error(address, err)),
+            }
+        }
+        let entry = line_at(elf.entry).ok_or_else(|| {
+            vec![error(
+                elf.entry,
+                "The entry point is not a Thumb instruction.".into(),
+            )]
+        });
+
+        // the vector table is at the start of the code, its first word is the initial SP
+        let vector_table = elf
+            .segments
+            .iter()
+            .filter(|segment| segment.is_executable())
+            .map(|segment| segment.address)
+            .min()
+            .unwrap_or(0);
+        let stack_pointer = elf
+            .word(vector_table)
+            .unwrap_or_else(|| memory_map.stack_top());
+        // the first push writes the word below the initial SP
+        let is_stack_writable = memory_map
+            .region_of(stack_pointer.wrapping_sub(4), 4)
+            .map_or(false, |region| region.access.write);
+        if !is_stack_writable {
+            errors.push(error(
+                vector_table,
+                format!(
+                    "The initial SP {:#x} is not at the end of a writable memory region.",
+                    stack_pointer
+                ),
+            ));
+        }
+        for segment in &elf.segments {
+            let end = segment.address.saturating_add(segment.memory_size);
+            if segment.is_executable() {
+                if let Err(err) = memory_map.check_code(segment.address..end) {
+                    errors.push(error(segment.address, err));
+                    continue;
+                }
+            } else if memory_map
+                .region_of(segment.address, segment.memory_size as usize)
+                .is_none()
+            {
+                errors.push(error(
+                    segment.address,
+                    "The data segment is not inside a memory region.".into(),
+                ));
+                continue;
+            }
+            // code segments are copied too, they hold the vector table and the literal pools.
+            self.memory_image.add_segment(
+                segment.address as usize,
+                segment.data.clone(),
+                segment.memory_size as usize,
+            );
+        }
+        let entry = entry?;
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(LoadedExecutable {
+            listing: listing.join("\n"),
+            stack_pointer,
+            entry,
+        })
+    }
     /// Runs compiled assembly instuctions
     /// Returns Standard Output, or Standard Error message
     pub fn run(
@@ -712,7 +1641,7 @@ impl Program {
                     &line.operands,
                     &line.file_name,
                     line.line_number,
-                    &self.string_messages,
+                    &processor.memory,
                 )? {
                     return Ok((std_out, input_status, DebugStatus::RUNNING));
                 }
@@ -795,7 +1724,7 @@ impl Program {
                 &line.operands,
                 &line.file_name,
                 line.line_number,
-                &self.string_messages,
+                &processor.memory,
             )? {
                 return Ok((
                     line.file_name.clone(),
@@ -828,6 +1757,17 @@ impl Program {
     }
 }
 
+/// Reads little-endian halfwords.
+fn halfwords(bytes: &[u8]) -> Vec<u16> {
+    bytes
+        .chunks_exact(2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+        .collect()
+}
+
+/// Size of the RAM in bytes, when config.json does not set the memory regions.
+pub const MEMORY_SIZE: usize = 1024;
+
 #[derive(Debug)]
 #[allow(non_snake_case)]
 /// Contains both CPU and Memory information.
@@ -838,27 +1778,29 @@ pub struct Processor {
     pub Z: bool,
     pub C: bool,
     pub V: bool,
-    // size = 1kb = 1024 bytes
-    // 1 byte = 8 bits
-    /// RAM
-    pub memory: [u8; 1024],
+    /// The memory regions set in config.json, 1 KB of RAM by default.
+    pub memory: Memory,
 }
 impl Processor {
     pub fn new() -> Self {
+        Self::with_memory_map(MemoryMap::default())
+    }
+    /// Creates a processor with the given memory regions, SP starts at the end of the data region.
+    pub fn with_memory_map(memory_map: MemoryMap) -> Self {
         // full descending stack
         let mut registers = [0; 16];
-        registers[13] = 1023;
+        registers[13] = memory_map.stack_top();
         Processor {
             R: registers,
             N: false,
             Z: false,
             C: false,
             V: false,
-            memory: [0; 1024],
+            memory: Memory::new(memory_map),
         }
     }
-    /// Resets all values except the instructions hashmap.
+    /// Resets the registers and clears memory, keeping the memory regions.
     pub fn reset(&mut self) {
-        *self = Self::new();
+        *self = Self::with_memory_map(self.memory.map().clone());
     }
 }
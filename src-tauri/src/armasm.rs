@@ -0,0 +1,184 @@
+/// Contains the ARMASM (Keil) dialect, which is translated into GNU assembler lines before preprocessing.
+/// ARMASM labels start in column 1 without a colon, and directives are written without a dot, ex: "table DCD 1, 2"
+use crate::preprocess;
+use regex::Regex;
+
+/// The assembler syntax of a file.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Dialect {
+    /// GNU assembler syntax, ex: ".word 1"
+    Gnu,
+    /// ARM and Keil assembler syntax, ex: "DCD 1"
+    Armasm,
+}
+
+/// Detects the dialect of a file, ARMASM files contain an AREA directive.
+pub fn detect(content: &str) -> Dialect {
+    let is_armasm = content.lines().any(|line| {
+        line.split_whitespace()
+            .next()
+            .map_or(false, |word| word.eq_ignore_ascii_case("area"))
+    });
+    if is_armasm {
+        Dialect::Armasm
+    } else {
+        Dialect::Gnu
+    }
+}
+
+/// Translates an ARMASM line without comments into GNU assembler lines.
+/// in_column_1 is true if the line starts in column 1, which makes the first word a label.
+pub fn translate(text: &str, in_column_1: bool) -> Result<Vec<String>, String> {
+    let (label, rest) = if in_column_1 && !text.is_empty() {
+        let (label, rest) = split_word(text);
+        (Some(label.trim_end_matches(':')), rest)
+    } else {
+        (None, text)
+    };
+    let (word, args) = split_word(rest);
+    let directive = word.to_uppercase();
+    let name = || label.ok_or_else(|| format!("{} must have a name in column 1.", directive));
+    let mut lines: Vec<String> = Vec::new();
+    match directive.as_str() {
+        "EQU" | "*" => return Ok(vec![format!(".equ {}, {}", name()?, args)]),
+        "RN" => return Ok(vec![format!("{} .req {}", name()?, args)]),
+        "PROC" | "FUNCTION" => return Ok(vec![".thumb_func".into(), format!("{}:", name()?)]),
+        // DCD and DCW align their data, the label points after the padding.
+        "DCD" => lines.push(".balign 4".into()),
+        "DCW" => lines.push(".balign 2".into()),
+        _ => (),
+    }
+    if let Some(label) = label {
+        lines.push(format!("{}:", label));
+    }
+    match directive.as_str() {
+        "" | "ENTRY" | "THUMB" | "CODE16" | "PRESERVE8" | "REQUIRE8" | "ENDP" | "ENDFUNC" => (),
+        "ARM" | "CODE32" => return Err("Only Thumb code is supported, use THUMB".into()),
+        "AREA" => lines.push(area(args)?),
+        "DCD" | "DCDU" => lines.push(format!(".word {}", args)),
+        "DCW" | "DCWU" => lines.push(format!(".hword {}", args)),
+        "DCB" => lines.extend(bytes(args)),
+        "SPACE" | "%" | "FILL" => lines.push(format!(".space {}", args)),
+        "ALIGN" if args.is_empty() => lines.push(".balign 4".into()),
+        "ALIGN" => lines.push(format!(".balign {}", args)),
+        "EXPORT" | "GLOBAL" => lines.push(symbol_list(".global", ".weak", args)),
+        "IMPORT" | "EXTERN" => lines.push(symbol_list(".extern", ".extern", args)),
+        "INCLUDE" | "GET" => lines.push(format!(".include \"{}\"", args.trim_matches('"'))),
+        "IF" => lines.push(condition(args)),
+        "ELIF" => lines.push(format!(".elseif {}", expression(args))),
+        "ELSE" => lines.push(".else".into()),
+        "ENDIF" => lines.push(".endif".into()),
+        "END" => lines.push(".end".into()),
+        // instructions, numeric local labels are written as %b1 and %f1
+        _ => lines.push(
+            Regex::new(r"%([bfBF])(\d+)")
+                .unwrap()
+                .replace_all(rest, "$2$1")
+                .into_owned(),
+        ),
+    }
+    Ok(lines)
+}
+
+/// Splits the first word from the rest of a line.
+fn split_word(text: &str) -> (&str, &str) {
+    match text.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim()),
+        None => (text, ""),
+    }
+}
+
+/// Translates "AREA name, attributes" into a section directive.
+fn area(args: &str) -> Result<String, String> {
+    let attributes: Vec<String> = preprocess::split_arguments(args, false)
+        .iter()
+        .skip(1)
+        .map(|attribute| attribute.to_uppercase())
+        .collect();
+    let has = |attribute: &str| attributes.iter().any(|a| a == attribute);
+    if has("CODE") {
+        Ok(".text".into())
+    } else if has("DATA") && has("NOINIT") {
+        Ok(".bss".into())
+    } else if has("DATA") && has("READONLY") {
+        Ok(".rodata".into())
+    } else if has("DATA") {
+        Ok(".data".into())
+    } else {
+        Err("AREA must have the CODE or DATA attribute, ex: \"AREA main, CODE, READONLY\"".into())
+    }
+}
+
+/// Translates the arguments of DCB, strings are stored with .ascii and numbers with .byte
+fn bytes(args: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut numbers: Vec<String> = Vec::new();
+    for arg in preprocess::split_arguments(args, false) {
+        if arg.starts_with('"') {
+            if !numbers.is_empty() {
+                lines.push(format!(".byte {}", numbers.join(", ")));
+                numbers.clear();
+            }
+            lines.push(format!(".ascii {}", arg));
+        } else {
+            numbers.push(arg);
+        }
+    }
+    if !numbers.is_empty() {
+        lines.push(format!(".byte {}", numbers.join(", ")));
+    }
+    lines
+}
+
+/// Translates "EXPORT name" and "EXPORT name [WEAK]"
+fn symbol_list(directive: &str, weak_directive: &str, args: &str) -> String {
+    let weak = Regex::new(r"(?i)\s*\[\s*weak\s*\]$").unwrap();
+    if weak.is_match(args) {
+        format!("{} {}", weak_directive, weak.replace(args, ""))
+    } else {
+        format!("{} {}", directive, args)
+    }
+}
+
+/// Translates the condition of IF, ":DEF:NAME" checks if a symbol is defined.
+fn condition(args: &str) -> String {
+    let args = args.trim();
+    let upper = args.to_uppercase();
+    if let Some(name) = upper.strip_prefix(":LNOT::DEF:") {
+        format!(".ifndef {}", &args[args.len() - name.len()..].trim())
+    } else if let Some(name) = upper.strip_prefix(":DEF:") {
+        format!(".ifdef {}", &args[args.len() - name.len()..].trim())
+    } else {
+        format!(".if {}", expression(args))
+    }
+}
+
+/// Replaces the ARMASM operators of an expression with C operators, ex: "A = 1 :LAND: B" becomes "A == 1 && B"
+fn expression(args: &str) -> String {
+    let operators = [
+        (":LAND:", "&&"),
+        (":LOR:", "||"),
+        (":LNOT:", "!"),
+        (":AND:", "&"),
+        (":OR:", "|"),
+        (":EOR:", "^"),
+        (":NOT:", "~"),
+        (":MOD:", "%"),
+        (":SHL:", "<<"),
+        (":SHR:", ">>"),
+        ("<>", "!="),
+        ("/=", "!="),
+    ];
+    let mut result = args.to_string();
+    for (armasm, c) in operators.iter() {
+        result = Regex::new(&format!("(?i){}", regex::escape(armasm)))
+            .unwrap()
+            .replace_all(&result, *c)
+            .into_owned();
+    }
+    Regex::new(r"([^<>=!])=([^=])")
+        .unwrap()
+        .replace_all(&result, "$1==$2")
+        .into_owned()
+}
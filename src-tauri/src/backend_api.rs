@@ -1,9 +1,22 @@
 // For Compiling, Running, and Debugging assembly code.
-use crate::arm7::{ConditionCode, DebugStatus, InputStatus, Labels, Processor, Program};
-use crate::error::CompileErr;
-use compile::{Config, CPU};
+use crate::arm7::{
+    ConditionCode, DebugStatus, InputStatus, InstructionEncoding, Labels, LoadedExecutable,
+    Processor, Program,
+};
+use crate::cache::{self, ProgramCache};
+use crate::elf::Elf;
+use crate::encode;
+use crate::error::{self, CompileErr};
+use crate::hexfile::{self, Format};
+use crate::link::{self, LinkedData, Section};
+use crate::lint;
+use crate::memory;
+use crate::preprocess::{SourceFile, SourceLine};
+use crate::utils;
+use compile::{Config, MemoryView, CPU};
 use regex::Regex;
 use std::collections::{HashMap, VecDeque};
+use std::path::Path;
 use std::sync::Mutex;
 use tauri::State;
 
@@ -15,7 +28,7 @@ pub struct GlobalProgram(pub Mutex<Program>);
 pub struct GlobalKillSwitch(pub Mutex<bool>);
 
 /// Compile assembly code.
-/// Returns a list of compile-time errors if there are any.
+/// Returns a list of compile-time warnings if compiled successfully, otherwise returns a list of compile-time errors.
 #[tauri::command(rename_all = "snake_case")]
 pub async fn compile(
     processor: State<'_, GlobalProcessor>,
@@ -23,17 +36,16 @@ pub async fn compile(
     kill_switch: State<'_, GlobalKillSwitch>,
     dir_path: &str,
     breakpoint_map: Option<HashMap<&str, Vec<usize>>>,
-) -> Result<(), Vec<String>> {
+) -> Result<Vec<String>, Vec<String>> {
     // Load file contents, and get time delay
     let config = Config::new(dir_path)?;
 
-    let mut processor = processor
+    // Reset CPU and Memory of Processor, with the memory regions of config.json
+    *processor
         .0
         .lock()
-        .expect("Failed to get processor in compile function.");
-    // Reset CPU and Memory of Processor
-    processor.reset();
-    drop(processor);
+        .expect("Failed to get processor in compile function.") =
+        Processor::with_memory_map(config.get_memory_map().clone());
     // reset kill switch
     *kill_switch.0.lock().unwrap() = false;
 
@@ -44,79 +56,192 @@ pub async fn compile(
     // Reset compiled lines & labels of the program
     program.reset(config.get_delay());
 
+    let is_breakpoint = |source_line: &SourceLine| {
+        breakpoint_map.as_ref().map_or(false, |map| {
+            source_line.locations().any(|location| {
+                map.get(location.file_name.as_str())
+                    .map_or(false, |list| list.contains(&location.line_number))
+            })
+        })
+    };
+    let build = build(&config, &is_breakpoint)?;
+    *program = build.program;
+    program.load_memory(
+        &mut processor
+            .0
+            .lock()
+            .expect("Failed to get processor in compile function."),
+    );
+    Ok(build.warnings)
+}
+
+/// A compiled program, and the results of the link step it was compiled with.
+pub struct Build {
+    pub program: Program,
+    pub sources: Vec<SourceFile>,
+    pub linked: LinkedData,
+    pub labels: Labels,
+    /// Compile-time warnings, they do not fail the build.
+    pub warnings: Vec<String>,
+}
+
+/// Compiles every file of a project: preprocess, link, compile each file or reuse it from the cache, encode, and lint.
+/// Does not use the state of the app, the compile command loads the program into the processor.
+pub fn build(
+    config: &Config,
+    is_breakpoint: &dyn Fn(&SourceLine) -> bool,
+) -> Result<Build, Vec<String>> {
+    let mut program = Program::new();
+    // the time delay of each instruction when debugging
+    program.reset(config.get_delay());
     // Represents the IT block. A list of Condition Codes representing If-Else conditions.
-    let mut it_block: VecDeque<ConditionCode> = VecDeque::with_capacity(4);
-    // The global PC index, used for labels.
-    let mut pc = 0usize;
+    let mut it_block: VecDeque<Option<ConditionCode>> = VecDeque::with_capacity(4);
     // Stores all compile time errors
     let mut errors = CompileErr::new();
+    // Expand macros in every file
+    let sources = config.read_sources()?;
+    // Place the data sections of every file in memory
+    let mut linked = link::link(&sources, config.get_layout(), config.data_memory())?;
     // Stores all local and global labels
-    let mut labels = Labels::get_global_labels(&config)?;
-    // Stores labels that refer to a string variable. *string variables are stored in a vector that is needed at runtime.
-    let mut string_labels: HashMap<String, usize> = HashMap::new();
+    let mut labels = Labels::collect(&sources, &linked)?;
+    // Write the data values that use labels
+    let memory_image = linked.relocate(&|file_index, label| labels.value(file_index, label))?;
+    // The compiled lines of the previous compile, files that did not change are not compiled again.
+    let cache = config.load_cache();
+    let mut new_cache = ProgramCache::default();
 
     // Compile each file
-    for (file_name, file_content) in config.read_contents()? {
-        errors.update_current_file(file_name.clone());
+    for (file_index, source) in sources.iter().enumerate() {
         it_block.clear();
+        labels.set_current_file(file_index);
 
-        // find all labels first
-        let (new_strings, new_string_labels) =
-            labels.get_local_labels(&file_content, &mut pc, &mut errors);
-        string_labels.extend(
-            new_string_labels
-                .into_iter()
-                .map(|(k, v)| (k, v + program.string_messages.len())),
-        );
-        program.string_messages.extend(new_strings);
+        let key = cache::file_key(source, file_index, &labels, is_breakpoint);
+        if let Some(lines) = cache.get(&source.name, key) {
+            program.extend_lines(lines);
+            new_cache.insert(&source.name, key, lines);
+            continue;
+        }
+        let (first_line, error_count) = (program.line_count(), errors.count());
 
         // Parse instructions
-        for (line_number, line) in file_content.lines().enumerate() {
-            let line_number = line_number + 1; // offset line number by one
-            let original_line = compile::preprocess_line(line);
+        for (section, source_line) in link::sections(&source.lines) {
+            let original_line = source_line.text.as_str();
             let line = original_line.to_lowercase(); // set entire line to lowercase for easier parsing
-            let is_breakpoint = breakpoint_map.as_ref().map_or(false, |map| {
-                map.get(file_name.as_str())
-                    .map_or(false, |list| list.contains(&line_number))
-            });
-            errors.update_line_number(line_number);
+            let is_breakpoint = is_breakpoint(source_line);
+            errors.update_location(source_line);
 
             // skip if white space or label or directive
             if line.is_empty() || line.ends_with(':') || line.starts_with('.') {
                 continue;
             }
+            if section != Section::Text {
+                errors.push_message(&format!(
+                    "Instructions must be in the .text section, not the {} section.",
+                    section.name()
+                ));
+                continue;
+            }
             // Handle IT statement
             if compile::is_if_then_block(&line) {
-                errors = errors.handle_it_instruction(&mut it_block, line)?;
+                program.set_it_instruction(&line, source_line.location.line_number);
+                errors.handle_it_instruction(&mut it_block, line);
             }
             // Handle other instructions.
             else if let Some((mnemonic, mut extension)) = program.find_mnemonic(&line) {
                 // Valid Mnemonic.
                 extension.it_status = errors.get_it_status(&mut it_block, extension.cc);
                 // return any compile time errors for this instruction.
-                if let Err(err) = program.compile_instruction(
-                    mnemonic,
-                    file_name,
-                    line_number,
-                    extension,
-                    is_breakpoint,
-                    original_line,
-                    &line,
-                    &labels,
-                    &string_labels,
-                ) {
+                if let Err(err) = utils::with_source_line(source_line, || {
+                    program.compile_instruction(
+                        mnemonic,
+                        source_line,
+                        extension,
+                        is_breakpoint,
+                        original_line,
+                        &line,
+                        &labels,
+                    )
+                }) {
                     errors.extend(err);
                 }
             } else {
                 // No mnemonic detected.
-                errors.push_message("Invalid instruction.");
+                errors.push_message(&error::with_suggestion(
+                    "Invalid instruction.".into(),
+                    program.suggest_mnemonic(&line),
+                ));
             };
         }
         if !it_block.is_empty() {
             errors.push_message("IT block does not have all conditions covered.");
         }
+        if errors.count() == error_count {
+            new_cache.insert(&source.name, key, program.lines_from(first_line));
+        }
     }
-    errors.result()
+    errors.result()?;
+    let cache_warning = config.save_cache(&new_cache).err();
+    // Encode the instructions into machine code.
+    program.encode()?;
+    let code_end = encode::TEXT_ADDRESS + program.code_image().len() as u32;
+    config
+        .get_memory_map()
+        .check_code(encode::TEXT_ADDRESS..code_end)
+        .map_err(CompileErr::message)?;
+    program.symbols = labels.symbols().into_iter().cloned().collect();
+    // Write the listing files and the map file, if they are enabled in config.json
+    config.write_listings(&sources, &program, &linked, &labels)?;
+    config.write_map(&program, &linked, &labels)?;
+    // Load the .rodata and .data sections, and zero the .bss section.
+    program.memory_image = memory_image;
+    config.preload_memory(&mut program.memory_image)?;
+    // Warnings pass, does not fail the build.
+    let mut warnings = lint::lint(config, &sources, &program);
+    warnings.extend(cache_warning);
+    Ok(Build {
+        program,
+        sources,
+        linked,
+        labels,
+        warnings,
+    })
+}
+
+#[tauri::command(rename_all = "snake_case")]
+/// Loads an ELF executable built by an arm-none-eabi toolchain, instead of compiling assembly code.
+/// Returns the disassembled code that the line numbers of the debugger refer to, otherwise returns a list of errors.
+pub async fn load_executable(
+    processor: State<'_, GlobalProcessor>,
+    program: State<'_, GlobalProgram>,
+    kill_switch: State<'_, GlobalKillSwitch>,
+    file_path: &str,
+    delay: Option<u16>,
+) -> Result<LoadedExecutable, Vec<String>> {
+    let path = Path::new(file_path);
+    let file_name = path
+        .file_name()
+        .map_or(file_path.into(), |name| name.to_string_lossy());
+    let bytes = std::fs::read(path)
+        .map_err(|err| vec![format!("Could not read \"{}\": {}", file_name, err)])?;
+    let elf = Elf::parse(&bytes).map_err(|err| vec![format!("\"{}\": {}", file_name, err)])?;
+
+    *kill_switch.0.lock().unwrap() = false;
+    let mut program = program
+        .0
+        .lock()
+        .expect("Failed to get program in load_executable function");
+    program.reset(delay.unwrap_or(0));
+    let mut processor = processor
+        .0
+        .lock()
+        .expect("Failed to get processor in load_executable function.");
+    // the memory regions of the last compiled project are kept
+    processor.reset();
+    let executable = program.load_elf(&file_name, &elf, processor.memory.map())?;
+    program.load_memory(&mut processor);
+    processor.R[13] = executable.stack_pointer;
+    processor.R[15] = executable.entry as u32;
+    Ok(executable)
 }
 
 #[tauri::command(rename_all = "snake_case")]
@@ -163,6 +288,64 @@ pub async fn debug_run(
     program.debug_run(processor, kill_switch, std_input)
 }
 #[tauri::command]
+/// Sends the machine code of every instruction to the Frontend.
+pub async fn machine_code(
+    program: State<'_, GlobalProgram>,
+) -> Result<Vec<InstructionEncoding>, ()> {
+    let program = program
+        .0
+        .lock()
+        .expect("Failed to get Program in machine_code function.");
+    Ok(program.encodings())
+}
+#[tauri::command]
+/// Sends the disassembled machine code of the program to the Frontend, as (address, assembly text) pairs.
+pub async fn disassemble(program: State<'_, GlobalProgram>) -> Result<Vec<(u32, String)>, ()> {
+    let program = program
+        .0
+        .lock()
+        .expect("Failed to get Program in disassemble function.");
+    Ok(program.disassemble(&|label| program.label_name(label)))
+}
+#[tauri::command(rename_all = "snake_case")]
+/// Writes the compiled program to an ELF executable, which other tools such as objdump and gdb can read.
+pub async fn export_elf(program: State<'_, GlobalProgram>, file_path: &str) -> Result<(), String> {
+    let program = program
+        .0
+        .lock()
+        .expect("Failed to get Program in export_elf function.");
+    std::fs::write(file_path, program.to_elf())
+        .map_err(|err| format!("Could not write \"{}\": {}", file_path, err))
+}
+#[tauri::command(rename_all = "snake_case")]
+/// Saves memory from start up to end to an Intel HEX, S-record, or raw binary file.
+/// The format is picked from the file extension if it is not given, the data region is saved by default.
+pub async fn export_memory(
+    processor: State<'_, GlobalProcessor>,
+    file_path: &str,
+    format: Option<Format>,
+    start: Option<u32>,
+    end: Option<u32>,
+) -> Result<(), String> {
+    let format = hexfile::format_of(file_path, format)?;
+    let processor = processor
+        .0
+        .lock()
+        .expect("Failed to get processor in export_memory function.");
+    let range = hexfile::memory_range(start, end, processor.memory.map())?;
+    let bytes = processor
+        .memory
+        .bytes(range.start as u64..range.end as u64)
+        .ok_or_else(|| {
+            format!(
+                "The address range {:#x}..{:#x} is not inside a memory region.",
+                range.start, range.end
+            )
+        })?;
+    std::fs::write(file_path, hexfile::write(format, range.start, &bytes))
+        .map_err(|err| format!("Could not write \"{}\": {}", file_path, err))
+}
+#[tauri::command]
 /// Stops the current assembly code from running.
 pub async fn kill_process(kill_switch: State<'_, GlobalKillSwitch>) -> Result<(), ()> {
     let mut switch = kill_switch
@@ -203,16 +386,19 @@ pub async fn display_cpu(
 }
 
 #[tauri::command(rename_all = "snake_case")]
-/// Sends Memory data to Frontend
+/// Sends Memory data to Frontend. Only the pages of the data region that changed since the last call are sent,
+/// every allocated page is sent if all is true, ex: when the number format changes, or after a reset.
+/// Pages that were never written are not sent, the Frontend shows them as zero.
 pub async fn display_memory(
     processor: State<'_, GlobalProcessor>,
     num_format: String,
-) -> Result<(Vec<String>, u32), ()> {
+    all: Option<bool>,
+) -> Result<MemoryView, ()> {
     // get processor
-    let processor = processor
+    let mut processor = processor
         .0
         .lock()
-        .expect("Failed to get processor in display_CPU function.");
+        .expect("Failed to get processor in display_memory function.");
 
     // format based on chosen number system. Uses 8 bit representation.
     let formatter = match num_format.as_str() {
@@ -221,16 +407,61 @@ pub async fn display_memory(
         "hexadecimal" => |byte| format!("{:#04x}", byte),
         _ => |byte| format!("{}", byte), // default is unsigned u32
     };
-    Ok((
-        processor.memory.into_iter().map(formatter).collect(),
-        processor.R[13],
-    ))
+    // the data region is shown, it holds the data sections and the stack
+    let region = processor.memory.map().data_region().ok_or(())?.clone();
+    let range = region.range();
+    let page_size = memory::PAGE_SIZE as u64;
+    let pages = (range.start / page_size) as u32..=((range.end - 1) / page_size) as u32;
+    let (changed, all): (Vec<u32>, bool) = match processor.memory.take_changed_pages() {
+        Some(changed) if all != Some(true) => (
+            changed
+                .into_iter()
+                .filter(|page| pages.contains(page))
+                .collect(),
+            false,
+        ),
+        _ => (
+            pages
+                .filter(|&page| processor.memory.page(page).is_some())
+                .collect(),
+            true,
+        ),
+    };
+    let pages = changed
+        .into_iter()
+        .map(|page| {
+            // the part of the page inside the region
+            let start = (page as u64 * page_size).max(range.start);
+            let end = ((page as u64 + 1) * page_size).min(range.end);
+            let bytes = processor.memory.bytes(start..end).unwrap_or_default();
+            (start as u32, bytes.into_iter().map(formatter).collect())
+        })
+        .collect();
+    Ok(MemoryView {
+        pages,
+        all,
+        zero: formatter(0),
+        SP: processor.R[13],
+        origin: region.origin,
+        length: region.length,
+    })
 }
 
 /// Contains all functions & structs pertaining to compiling assembly code.
 pub mod compile {
-    use super::{CompileErr, Regex};
+    use super::{CompileErr, Labels, Program, Regex};
+    use crate::armasm::Dialect;
+    use crate::cache::{self, ProgramCache};
+    use crate::hexfile::Preload;
+    use crate::link::{LinkedData, MemoryImage, SectionLayout};
+    use crate::lint::Lint;
+    use crate::listing;
+    use crate::mapfile;
+    use crate::memory::MemoryMap;
+    use crate::preprocess::{self, PreprocessOptions, SourceFile};
+    use std::collections::HashMap;
     use std::fs;
+    use std::path::{Path, PathBuf};
 
     #[derive(serde::Serialize)]
     #[allow(non_snake_case)]
@@ -242,6 +473,20 @@ pub mod compile {
         pub C: bool,
         pub V: bool,
     }
+    #[derive(serde::Serialize)]
+    #[allow(non_snake_case)]
+    /// Used to output the changed pages of memory to Frontend, as (start address, bytes).
+    pub struct MemoryView {
+        pub pages: Vec<(u32, Vec<String>)>,
+        /// True if every allocated page was sent, the pages that were not sent are zero.
+        pub all: bool,
+        /// A zero byte in the chosen number format, for the pages that were not sent.
+        pub zero: String,
+        pub SP: u32,
+        /// The start address and size of the data region.
+        pub origin: u32,
+        pub length: u32,
+    }
     pub fn read_dir_file(dir_path: &str, file_name: &String) -> Result<String, Vec<String>> {
         match fs::read_to_string(format!("{}{}", dir_path, file_name)) {
             // If there is no config file, default to reading from main.s
@@ -260,6 +505,36 @@ pub mod compile {
         files: Vec<String>,
         #[serde(default)]
         delay: u16,
+        /// Warnings that are not reported, ex: ["unused-label"]
+        #[serde(default)]
+        suppress_warnings: Vec<Lint>,
+        /// Symbols for conditional assembly, ex: {"DEBUG": 1}
+        #[serde(default)]
+        defines: HashMap<String, i64>,
+        /// Directories searched by .include, relative to the project directory.
+        #[serde(default)]
+        include_dirs: Vec<String>,
+        /// Start addresses of the .rodata, .data, and .bss sections.
+        #[serde(default)]
+        layout: SectionLayout,
+        /// The assembler syntax, "gnu" or "armasm". Detected from each file if it is not set.
+        #[serde(default)]
+        dialect: Option<Dialect>,
+        /// Writes a .lst listing file next to each source file.
+        #[serde(default)]
+        listing: bool,
+        /// Writes a linker map file with this name after linking, ex: "program.map"
+        #[serde(default)]
+        map_file: Option<String>,
+        /// Memory image files that fill memory before the program starts.
+        #[serde(default)]
+        preload: Vec<Preload>,
+        /// The regions of memory, 1 KB of RAM at address 0 by default.
+        #[serde(default)]
+        memory: MemoryMap,
+        /// Stores the compiled lines of every file in the project directory, so unchanged files are not compiled again. Off by default.
+        #[serde(default)]
+        cache: bool,
         #[serde(skip)]
         dir_path: &'a str,
     }
@@ -275,18 +550,151 @@ pub mod compile {
                 Err(_) => Ok(Self {
                     files: vec!["main.s".into()],
                     delay: 0,
+                    suppress_warnings: Vec::new(),
+                    defines: HashMap::new(),
+                    include_dirs: Vec::new(),
+                    layout: SectionLayout::default(),
+                    dialect: None,
+                    listing: false,
+                    map_file: None,
+                    preload: Vec::new(),
+                    cache: false,
+                    memory: MemoryMap::default(),
                     dir_path: "",
                 }),
             };
-            config.map(|mut config| {
-                config.dir_path = dir_path;
-                config
-            })
+            let mut config = config?;
+            config.memory.check()?;
+            config.dir_path = dir_path;
+            Ok(config)
         }
         /// get time delay
         pub fn get_delay(&self) -> u16 {
             self.delay
         }
+        /// get the start addresses of the data sections
+        pub fn get_layout(&self) -> &SectionLayout {
+            &self.layout
+        }
+        /// get the memory regions
+        pub fn get_memory_map(&self) -> &MemoryMap {
+            &self.memory
+        }
+        /// The addresses of the data region, where the data sections are placed.
+        pub fn data_memory(&self) -> std::ops::Range<usize> {
+            self.memory.data_region().map_or(0..0, |region| {
+                region.range().start as usize..region.range().end as usize
+            })
+        }
+        /// get the warnings that should not be reported
+        pub fn get_suppressed_warnings(&self) -> &[Lint] {
+            &self.suppress_warnings
+        }
+        /// Reads and preprocesses every file. Returns the preprocessing errors of all files, if there are any.
+        pub fn read_sources(&self) -> Result<Vec<SourceFile>, Vec<String>> {
+            let options = PreprocessOptions {
+                dir_path: PathBuf::from(self.dir_path),
+                include_dirs: self.include_dirs.clone(),
+                symbols: self.defines.clone(),
+                dialect: self.dialect,
+            };
+            let mut sources: Vec<SourceFile> = Vec::new();
+            let mut errors: Vec<String> = Vec::new();
+            for (file_name, file_content) in self.read_contents()? {
+                match preprocess::preprocess_file(file_name, &file_content, &options) {
+                    Ok(lines) => sources.push(SourceFile {
+                        name: file_name.clone(),
+                        lines,
+                    }),
+                    Err(err) => errors.extend(err),
+                }
+            }
+            if errors.is_empty() {
+                Ok(sources)
+            } else {
+                Err(errors)
+            }
+        }
+        /// Writes the listing file of every file, ex: "main.lst" for "main.s"
+        pub fn write_listings(
+            &self,
+            sources: &[SourceFile],
+            program: &Program,
+            linked: &LinkedData,
+            labels: &Labels,
+        ) -> Result<(), Vec<String>> {
+            if !self.listing {
+                return Ok(());
+            }
+            let mut rows = program.listed_instructions().into();
+            for (file_index, (source, (file_name, text))) in
+                sources.iter().zip(self.read_contents()?).enumerate()
+            {
+                let content = listing::listing(
+                    source, &text, file_index, &mut rows, program, linked, labels,
+                );
+                let path = Path::new(file_name).with_extension("lst");
+                let path = format!("{}{}", self.dir_path, path.display());
+                fs::write(&path, content).map_err(|err| {
+                    CompileErr::message(format!("Could not write \"{}\": {}", path, err))
+                })?;
+            }
+            Ok(())
+        }
+        /// Writes the linker map file, if it is set.
+        pub fn write_map(
+            &self,
+            program: &Program,
+            linked: &LinkedData,
+            labels: &Labels,
+        ) -> Result<(), Vec<String>> {
+            let file_name = match &self.map_file {
+                Some(file_name) => file_name,
+                None => return Ok(()),
+            };
+            let path = format!("{}{}", self.dir_path, file_name);
+            fs::write(
+                &path,
+                mapfile::map_file(program, linked, labels, &self.memory),
+            )
+            .map_err(|err| CompileErr::message(format!("Could not write \"{}\": {}", path, err)))
+        }
+        /// Reads the program cache of the project, it is empty if the cache is disabled.
+        pub fn load_cache(&self) -> ProgramCache {
+            if self.cache {
+                ProgramCache::load(&format!("{}{}", self.dir_path, cache::CACHE_FILE))
+            } else {
+                ProgramCache::default()
+            }
+        }
+        /// Writes the program cache of the project, returns a warning if it could not be written.
+        pub fn save_cache(&self, cache: &ProgramCache) -> Result<(), String> {
+            if !self.cache {
+                return Ok(());
+            }
+            cache
+                .save(&format!("{}{}", self.dir_path, cache::CACHE_FILE))
+                .map_err(|err| format!("Could not write the program cache: {}", err))
+        }
+        /// Adds the contents of the preloaded files to the initial contents of memory.
+        pub fn preload_memory(&self, image: &mut MemoryImage) -> Result<(), Vec<String>> {
+            let mut errors: Vec<String> = Vec::new();
+            for preload in &self.preload {
+                match preload.read(self.dir_path, &self.memory) {
+                    Ok(blocks) => {
+                        for (address, bytes) in blocks {
+                            image.add_bytes(address as usize, bytes);
+                        }
+                    }
+                    Err(err) => errors.push(err),
+                }
+            }
+            if errors.is_empty() {
+                Ok(())
+            } else {
+                Err(errors)
+            }
+        }
         /// Returns an iterator over all the file contents. A list of (file_name, file_content) for each file.
         pub fn read_contents(&self) -> Result<Vec<(&String, String)>, Vec<String>> {
             let mut iterator: Vec<(&String, String)> = Vec::new();
@@ -298,9 +706,46 @@ pub mod compile {
         }
     }
     /// Removes comments & trims whitespace
-    pub fn preprocess_line(line: &str) -> &str {
-        let (line, _) = line.split_once("//").unwrap_or((line, "")); // Remove comments at the end of a line
-        line.trim() // trim white space
+    /// Line comments start with //, @, or ;, or with # at the start of the line. Comment characters inside quotes, and the \@ of macros, are kept.
+    /// Block comments /* */ can span lines, in_block_comment is true while the line is inside one.
+    pub fn preprocess_line(line: &str, in_block_comment: &mut bool) -> String {
+        let mut result = String::with_capacity(line.len());
+        let mut in_quotes = false;
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            if *in_block_comment {
+                if c == '*' && chars.peek() == Some(&'/') {
+                    chars.next();
+                    *in_block_comment = false;
+                    result.push(' '); // keep words on both sides of the comment apart
+                }
+                continue;
+            }
+            if in_quotes {
+                if c == '\\' {
+                    result.push(c);
+                    if let Some(escaped) = chars.next() {
+                        result.push(escaped);
+                    }
+                    continue;
+                }
+                in_quotes = c != '"';
+            } else if c == '"' {
+                in_quotes = true;
+            } else if c == '/' && chars.peek() == Some(&'*') {
+                chars.next();
+                *in_block_comment = true;
+                continue;
+            } else if (c == '@' && !result.ends_with('\\'))
+                || c == ';'
+                || (c == '/' && chars.peek() == Some(&'/'))
+                || (c == '#' && result.trim().is_empty())
+            {
+                break; // Remove comments at the end of a line
+            }
+            result.push(c);
+        }
+        result.trim().to_string() // trim white space
     }
     pub fn is_if_then_block(line: &str) -> bool {
         Regex::new(r"^it[te]*\s+\w+$").unwrap().is_match(line)
@@ -0,0 +1,122 @@
+/// Contains the program cache, which stores the compiled lines of every file so unchanged files are not compiled again.
+/// A file is reused when its key matches: a hash of its preprocessed lines, which includes the config.json
+/// symbols and included files, its breakpoints, and the values of every label it can use.
+/// Only the instruction lines are stored. The label table and the data image are not, since the key needs the
+/// value of every label, so the data sections are always linked again before any file is looked up.
+/// Linking is a single pass over the data directives, compiling the instructions is the slow part.
+use crate::arm7::{Labels, Line};
+use crate::preprocess::{SourceFile, SourceLine};
+use std::collections::HashMap;
+use std::fs;
+
+/// Changed whenever the stored format or the compiled lines change, older caches are ignored.
+pub const CACHE_VERSION: u32 = 1;
+/// Name of the cache file in the project directory.
+pub const CACHE_FILE: &str = ".program-cache.json";
+
+/// The compiled lines of a file, and the key they were compiled with.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct CachedFile {
+    key: u64,
+    lines: Vec<Line>,
+}
+
+/// The compiled lines of every file, stored on disk between compiles.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ProgramCache {
+    version: u32,
+    files: HashMap<String, CachedFile>,
+}
+impl Default for ProgramCache {
+    fn default() -> Self {
+        ProgramCache {
+            version: CACHE_VERSION,
+            files: HashMap::new(),
+        }
+    }
+}
+impl ProgramCache {
+    /// Reads a cache file. A missing, unreadable, or outdated cache is empty.
+    pub fn load(path: &str) -> Self {
+        fs::read_to_string(path)
+            .ok()
+            .and_then(|content| serde_json::from_str::<Self>(&content).ok())
+            .filter(|cache| cache.version == CACHE_VERSION)
+            .unwrap_or_default()
+    }
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let content = serde_json::to_string(self).map_err(|err| err.to_string())?;
+        fs::write(path, content).map_err(|err| err.to_string())
+    }
+    /// Returns the compiled lines of a file, if they were compiled with the same key.
+    pub fn get(&self, file_name: &str, key: u64) -> Option<&[Line]> {
+        self.files
+            .get(file_name)
+            .filter(|file| file.key == key)
+            .map(|file| file.lines.as_slice())
+    }
+    pub fn insert(&mut self, file_name: &str, key: u64, lines: &[Line]) {
+        self.files.insert(
+            file_name.to_string(),
+            CachedFile {
+                key,
+                lines: lines.to_vec(),
+            },
+        );
+    }
+}
+
+/// Computes the key of a file, compiling a file with the same key gives the same lines.
+pub fn file_key(
+    source: &SourceFile,
+    file_index: usize,
+    labels: &Labels,
+    is_breakpoint: &dyn Fn(&SourceLine) -> bool,
+) -> u64 {
+    let mut hasher = Hasher::new();
+    hasher.write(&CACHE_VERSION.to_le_bytes());
+    for line in &source.lines {
+        hasher.write_str(&line.text);
+        hasher.write_str(&line.location.to_string());
+        hasher.write(&[is_breakpoint(line) as u8]);
+        for (alias, register) in &line.register_aliases {
+            hasher.write_str(&format!("{} {}", alias, register));
+        }
+        for (name, value) in &line.symbols {
+            hasher.write_str(&format!("{} {}", name, value));
+        }
+    }
+    // the labels the file can use, sorted since the order of a HashMap changes
+    let mut symbols: Vec<String> = labels
+        .file_symbols(file_index)
+        .into_iter()
+        .chain(labels.globals())
+        .map(|symbol| format!("{} {:?} {:?}", symbol.name, symbol.value, symbol.binding))
+        .collect();
+    symbols.sort_unstable();
+    for text in &symbols {
+        hasher.write_str(text);
+    }
+    hasher.finish()
+}
+
+/// 64-bit FNV-1a hash, which gives the same keys on every platform and Rust version.
+struct Hasher(u64);
+impl Hasher {
+    fn new() -> Self {
+        Hasher(0xcbf2_9ce4_8422_2325)
+    }
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+    /// Writes a string and a separator, so "ab" + "c" and "a" + "bc" hash differently.
+    fn write_str(&mut self, text: &str) {
+        self.write(text.as_bytes());
+        self.write(&[0xFF]);
+    }
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
@@ -0,0 +1,595 @@
+/// Contains the C preprocessor that runs on .S files, before comments are removed and the assembler preprocessor runs.
+/// Supports object-like and function-like macros, #include, #if/#ifdef chains, and #line.
+/// Every output line keeps the file and line it came from, so errors point at the original code.
+use crate::error::CompileErr;
+use crate::expression;
+use crate::preprocess::{self, Frame, Location, PreprocessOptions, SourceLine};
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Maximum depth of nested #include directives. Headers can include each other, as long as they are guarded.
+const MAX_INCLUDE_DEPTH: usize = 50;
+
+/// Returns true if a file is run through the C preprocessor.
+pub fn is_c_source(file_name: &str) -> bool {
+    file_name.ends_with(".S")
+}
+
+/// Runs the C preprocessor over a file, returns the lines with the location they were written on.
+/// The symbols from the configuration are defined as object-like macros, and __ASSEMBLER__ is defined.
+pub fn preprocess(
+    file_name: &str,
+    content: &str,
+    options: &PreprocessOptions,
+) -> Result<Vec<SourceLine>, Vec<String>> {
+    let mut cpp = CPreprocessor {
+        options,
+        macros: options
+            .symbols
+            .iter()
+            .map(|(name, value)| (name.clone(), CMacro::object(&value.to_string())))
+            .collect(),
+        conditionals: Vec::new(),
+        include_stack: Vec::new(),
+        errors: CompileErr::new(),
+        lines: Vec::new(),
+    };
+    cpp.macros
+        .insert("__ASSEMBLER__".into(), CMacro::object("1"));
+    let path = options.dir_path.join(file_name);
+    cpp.process_file(&path, file_name, content, &[], 0);
+    for conditional in std::mem::take(&mut cpp.conditionals) {
+        cpp.errors.update_location(&conditional.line);
+        cpp.errors.push_message("#if block is missing #endif");
+    }
+    cpp.errors.result()?;
+    Ok(cpp.lines)
+}
+
+/// A macro defined by #define
+#[derive(Debug, Clone)]
+struct CMacro {
+    /// The parameter names of a function-like macro, ex: #define MAX(a, b)
+    /// "..." is the last parameter of a variadic macro, and is used as __VA_ARGS__
+    params: Option<Vec<String>>,
+    body: String,
+}
+impl CMacro {
+    fn object(body: &str) -> Self {
+        CMacro {
+            params: None,
+            body: body.to_string(),
+        }
+    }
+}
+
+/// An open #if block.
+struct Conditional {
+    /// Lines in the current branch are kept.
+    active: bool,
+    /// A branch was already kept, so the remaining #elif and #else branches are skipped.
+    taken: bool,
+    /// #else was seen, so no more branches are allowed.
+    has_else: bool,
+    /// The #if line, used for error messages.
+    line: SourceLine,
+}
+
+struct CPreprocessor<'a> {
+    options: &'a PreprocessOptions,
+    macros: HashMap<String, CMacro>,
+    /// Stack of open #if blocks, innermost last.
+    conditionals: Vec<Conditional>,
+    /// The canonical path and name of the file being preprocessed, followed by the files it includes. Innermost last.
+    include_stack: Vec<(PathBuf, String)>,
+    errors: CompileErr,
+    lines: Vec<SourceLine>,
+}
+impl<'a> CPreprocessor<'a> {
+    /// Preprocesses every line of a file, lines ending with a backslash are joined with the next line.
+    fn process_file(
+        &mut self,
+        path: &Path,
+        name: &str,
+        content: &str,
+        backtrace: &[Frame],
+        depth: usize,
+    ) {
+        let canonical_path = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        self.include_stack.push((canonical_path, name.to_string()));
+        let re_line = Regex::new(r#"^#\s*line\s+(\d+)(?:\s+"([^"]*)")?$"#).unwrap();
+        let mut location = Location {
+            file_name: name.to_string(),
+            line_number: 1,
+        };
+        let mut in_comment = false;
+        // the line where the last block comment started.
+        let mut comment_start: Option<SourceLine> = None;
+        let mut physical_lines = content.lines();
+        while let Some(first) = physical_lines.next() {
+            let start = location.clone();
+            let mut text = first.to_string();
+            location.line_number += 1;
+            while text.ends_with('\\') {
+                text.pop();
+                match physical_lines.next() {
+                    Some(next) => text.push_str(next),
+                    None => break,
+                }
+                location.line_number += 1;
+            }
+            let was_in_comment = in_comment;
+            let text = strip_comments(&text, &mut in_comment);
+            let line = SourceLine {
+                text,
+                location: start,
+                backtrace: backtrace.to_vec(),
+                register_aliases: Vec::new(),
+                symbols: Vec::new(),
+            };
+            if in_comment && !was_in_comment {
+                comment_start = Some(line.clone());
+            }
+            // #line changes the location of the next line
+            if let Some(cap) = re_line.captures(line.text.trim()) {
+                if self.is_active() {
+                    location.line_number = cap[1].parse().unwrap_or(1);
+                    if let Some(file_name) = cap.get(2) {
+                        location.file_name = file_name.as_str().to_string();
+                    }
+                }
+                continue;
+            }
+            self.process_line(line, path, depth);
+        }
+        if let Some(line) = comment_start.filter(|_| in_comment) {
+            self.errors.update_location(&line);
+            self.errors
+                .push_message("Block comment is missing the closing */");
+        }
+        self.include_stack.pop();
+    }
+    /// Handles one line, directives start with #
+    fn process_line(&mut self, line: SourceLine, path: &Path, depth: usize) {
+        let trimmed = line.text.trim();
+        let directive_line = match trimmed.strip_prefix('#') {
+            Some(rest) => rest.trim_start(),
+            None => {
+                if self.is_active() {
+                    let text = self.expand(&line.text, &HashSet::new(), &line);
+                    self.lines.push(SourceLine { text, ..line });
+                }
+                return;
+            }
+        };
+        let directive: String = directive_line
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric() || *c == '_')
+            .collect();
+        let args = directive_line[directive.len()..].trim().to_string();
+        if self.process_conditional(&directive, &args, &line) || !self.is_active() {
+            return;
+        }
+        self.errors.update_location(&line);
+        match directive.as_str() {
+            "define" => self.define(&args),
+            "undef" => {
+                self.macros.remove(&args);
+            }
+            "include" => self.include(&args, &line, path, depth),
+            "error" => self.errors.push_message(&format!("#error {}", args)),
+            "pragma" | "warning" => (),
+            // unknown directives are assembler comments, ex: "# comment"
+            _ => self.lines.push(line),
+        }
+    }
+    fn is_active(&self) -> bool {
+        self.conditionals
+            .iter()
+            .all(|conditional| conditional.active)
+    }
+    /// Handles #if, #ifdef, #ifndef, #elif, #else, and #endif. Returns false if the line is not a conditional directive.
+    fn process_conditional(&mut self, directive: &str, args: &str, line: &SourceLine) -> bool {
+        match directive {
+            "if" | "ifdef" | "ifndef" => {
+                // conditions inside a skipped branch are not evaluated.
+                let active = self.is_active() && self.condition(directive, args, line);
+                self.conditionals.push(Conditional {
+                    active,
+                    taken: active || !self.is_active(),
+                    has_else: false,
+                    line: line.clone(),
+                });
+            }
+            "elif" | "else" => {
+                let parent_active = self.conditionals.len() < 2
+                    || self.conditionals[..self.conditionals.len() - 1]
+                        .iter()
+                        .all(|conditional| conditional.active);
+                let (taken, has_else) = match self.conditionals.last() {
+                    Some(conditional) => (conditional.taken, conditional.has_else),
+                    None => {
+                        self.errors.update_location(line);
+                        self.errors
+                            .push_message(&format!("#{} does not have a matching #if", directive));
+                        return true;
+                    }
+                };
+                if has_else {
+                    self.errors.update_location(line);
+                    self.errors
+                        .push_message(&format!("#{} cannot come after #else", directive));
+                }
+                let active = !taken
+                    && parent_active
+                    && (directive == "else" || self.condition(directive, args, line));
+                let conditional = self.conditionals.last_mut().unwrap();
+                conditional.active = active;
+                conditional.taken |= active;
+                conditional.has_else |= directive == "else";
+            }
+            "endif" => {
+                if self.conditionals.pop().is_none() {
+                    self.errors.update_location(line);
+                    self.errors
+                        .push_message("#endif does not have a matching #if");
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+    /// Evaluates the condition of an #if, #ifdef, #ifndef, or #elif directive.
+    /// Identifiers that are not macros are zero, like in C.
+    fn condition(&mut self, directive: &str, args: &str, line: &SourceLine) -> bool {
+        self.errors.update_location(line);
+        if directive == "ifdef" || directive == "ifndef" {
+            if args.is_empty() || args.contains(char::is_whitespace) {
+                self.errors
+                    .push_message(&format!("#{} takes a single macro name.", directive));
+                return false;
+            }
+            return self.macros.contains_key(args) == (directive == "ifdef");
+        }
+        let defined = Regex::new(r"\bdefined\s*(?:\(\s*([a-zA-Z_]\w*)\s*\)|([a-zA-Z_]\w*))")
+            .unwrap()
+            .replace_all(args, |cap: &regex::Captures| {
+                let name = cap.get(1).or_else(|| cap.get(2)).unwrap().as_str();
+                if self.macros.contains_key(name) {
+                    "1"
+                } else {
+                    "0"
+                }
+            })
+            .into_owned();
+        let expanded = self.expand(&defined, &HashSet::new(), line);
+        match expression::evaluate(&expanded, &|_| Some(0)) {
+            Ok(value) => value != 0,
+            Err(err) => {
+                self.errors.update_location(line);
+                self.errors.push_message(&err);
+                false
+            }
+        }
+    }
+    /// Handles "#define NAME body" and "#define NAME(params) body"
+    fn define(&mut self, args: &str) {
+        let cap = match Regex::new(r"^([a-zA-Z_]\w*)(\(([^)]*)\))?\s*(.*)$")
+            .unwrap()
+            .captures(args)
+        {
+            Some(cap) => cap,
+            None => {
+                self.errors
+                    .push_message("#define must be followed by a macro name.");
+                return;
+            }
+        };
+        let params = match cap.get(3) {
+            Some(params) => {
+                let params: Vec<String> = params
+                    .as_str()
+                    .split(',')
+                    .map(|param| param.trim().to_string())
+                    .filter(|param| !param.is_empty())
+                    .collect();
+                let re_param = Regex::new(r"^[a-zA-Z_]\w*$").unwrap();
+                for (index, param) in params.iter().enumerate() {
+                    let is_vararg = param == "..." && index == params.len() - 1;
+                    if !is_vararg && !re_param.is_match(param) {
+                        self.errors.push_message(&format!(
+                            "\"{}\" is not a valid macro parameter.",
+                            param
+                        ));
+                        return;
+                    }
+                }
+                Some(params)
+            }
+            None => None,
+        };
+        self.macros.insert(
+            cap[1].to_string(),
+            CMacro {
+                params,
+                body: cap[4].trim().to_string(),
+            },
+        );
+    }
+    /// Handles '#include "file"' and "#include <file>". Quoted files are searched for in the directory of the current file first.
+    fn include(&mut self, args: &str, line: &SourceLine, path: &Path, depth: usize) {
+        let args = self.expand(args, &HashSet::new(), line);
+        let (file, current_dir) = match Regex::new(r#"^(?:"([^"]+)"|<([^>]+)>)$"#)
+            .unwrap()
+            .captures(args.trim())
+        {
+            Some(cap) => match cap.get(1) {
+                Some(file) => (file.as_str().to_string(), path.parent()),
+                None => (cap[2].to_string(), None),
+            },
+            None => {
+                self.errors
+                    .push_message("#include must be followed by a file name in quotes.");
+                return;
+            }
+        };
+        if depth >= MAX_INCLUDE_DEPTH {
+            self.errors.push_message(&format!(
+                "#include is nested too deeply, \"{}\" may include itself without an include guard.",
+                file
+            ));
+            return;
+        }
+        let (included_path, content): (PathBuf, String) = match preprocess::find_include(
+            self.options,
+            current_dir,
+            &file,
+        ) {
+            Some(found) => found,
+            None => {
+                self.errors.push_message(&format!(
+                        "Couldn't find the included file \"{}\" in the directory of the current file or the include_dirs.",
+                        file
+                    ));
+                return;
+            }
+        };
+        // a guarded header can include a file that is including it, the guard stops the second include.
+        // Reaching a file that is already on the stack twice means nothing stops the cycle.
+        let canonical_path =
+            fs::canonicalize(&included_path).unwrap_or_else(|_| included_path.clone());
+        let previous: Vec<usize> = self
+            .include_stack
+            .iter()
+            .enumerate()
+            .filter(|(_, (path, _))| *path == canonical_path)
+            .map(|(index, _)| index)
+            .collect();
+        if previous.len() >= 2 {
+            let start = previous[previous.len() - 1];
+            let chain: Vec<String> = self.include_stack[start..]
+                .iter()
+                .chain(std::iter::once(&self.include_stack[start]))
+                .map(|(_, name)| format!("\"{}\"", name))
+                .collect();
+            self.errors.push_message(&format!(
+                "Include cycle: {}, add an include guard to stop it.",
+                chain.join(" includes ")
+            ));
+            return;
+        }
+        let name = preprocess::relative_name(self.options, &included_path);
+        let mut backtrace = vec![Frame {
+            description: "included from".into(),
+            location: line.location.clone(),
+        }];
+        backtrace.extend(line.backtrace.iter().cloned());
+        self.process_file(&included_path, &name, &content, &backtrace, depth + 1);
+    }
+    /// Replaces the macros in a line. Macros in the hidden set are being expanded, and are not replaced again.
+    fn expand(&mut self, text: &str, hidden: &HashSet<String>, line: &SourceLine) -> String {
+        let chars: Vec<char> = text.chars().collect();
+        let mut result = String::with_capacity(text.len());
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            if c == '"' {
+                // copy string literals unchanged
+                let start = i;
+                i += 1;
+                while i < chars.len() && chars[i] != '"' {
+                    i += if chars[i] == '\\' { 2 } else { 1 };
+                }
+                i = (i + 1).min(chars.len());
+                result.extend(&chars[start..i]);
+            } else if c.is_ascii_digit() {
+                // numbers and numeric label references are never macros, ex: 0x1F, 1b
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                    result.push(chars[i]);
+                    i += 1;
+                }
+            } else if c.is_ascii_alphabetic() || c == '_' {
+                let start = i;
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                let name: String = chars[start..i].iter().collect();
+                let mac = match self.macros.get(&name) {
+                    Some(mac) if !hidden.contains(&name) => mac.clone(),
+                    _ => {
+                        result.push_str(&name);
+                        continue;
+                    }
+                };
+                let mut inner_hidden = hidden.clone();
+                inner_hidden.insert(name.clone());
+                let body = match &mac.params {
+                    None => mac.body.clone(),
+                    Some(params) => {
+                        // a function-like macro name without arguments is not replaced
+                        let mut open = i;
+                        while open < chars.len() && chars[open].is_whitespace() {
+                            open += 1;
+                        }
+                        if chars.get(open) != Some(&'(') {
+                            result.push_str(&name);
+                            continue;
+                        }
+                        let (args, end) = match split_call_arguments(&chars, open) {
+                            Some(found) => found,
+                            None => {
+                                self.errors.update_location(line);
+                                self.errors.push_message(&format!(
+                                    "The arguments of macro \"{}\" are missing a closing parenthesis.",
+                                    name
+                                ));
+                                return result;
+                            }
+                        };
+                        i = end;
+                        match self.substitute(&name, params, &mac.body, args, hidden, line) {
+                            Some(body) => body,
+                            None => continue,
+                        }
+                    }
+                };
+                let expanded = self.expand(&body, &inner_hidden, line);
+                result.push_str(&expanded);
+            } else {
+                result.push(c);
+                i += 1;
+            }
+        }
+        result
+    }
+    /// Replaces the parameters in the body of a function-like macro, and joins tokens around ##
+    fn substitute(
+        &mut self,
+        name: &str,
+        params: &[String],
+        body: &str,
+        mut args: Vec<String>,
+        hidden: &HashSet<String>,
+        line: &SourceLine,
+    ) -> Option<String> {
+        let is_variadic = params.last().map_or(false, |param| param == "...");
+        let fixed = if is_variadic {
+            params.len() - 1
+        } else {
+            params.len()
+        };
+        // "NAME()" passes one empty argument
+        if params.is_empty() && args.len() == 1 && args[0].is_empty() {
+            args.clear();
+        }
+        if args.len() < fixed || (!is_variadic && args.len() > fixed) {
+            self.errors.update_location(line);
+            self.errors.push_message(&format!(
+                "Macro \"{}\" takes {} arguments, but {} were given.",
+                name,
+                fixed,
+                args.len()
+            ));
+            return None;
+        }
+        let mut values: HashMap<&str, String> = HashMap::new();
+        for (param, arg) in params.iter().take(fixed).zip(&args) {
+            values.insert(param.as_str(), self.expand(arg, hidden, line));
+        }
+        if is_variadic {
+            values.insert(
+                "__VA_ARGS__",
+                self.expand(&args[fixed..].join(", "), hidden, line),
+            );
+        }
+        let substituted =
+            Regex::new(r"\b[a-zA-Z_]\w*\b")
+                .unwrap()
+                .replace_all(body, |cap: &regex::Captures| {
+                    values
+                        .get(&cap[0])
+                        .cloned()
+                        .unwrap_or_else(|| cap[0].to_string())
+                });
+        Some(
+            Regex::new(r"\s*##\s*")
+                .unwrap()
+                .replace_all(&substituted, "")
+                .into_owned(),
+        )
+    }
+}
+
+/// Splits the arguments of a macro call, starting at the opening parenthesis.
+/// Returns the arguments and the index after the closing parenthesis.
+fn split_call_arguments(chars: &[char], open: usize) -> Option<(Vec<String>, usize)> {
+    let mut args: Vec<String> = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0;
+    let mut in_quotes = false;
+    for (i, &c) in chars.iter().enumerate().skip(open) {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '(' if !in_quotes => {
+                depth += 1;
+                if depth == 1 {
+                    continue;
+                }
+            }
+            ')' if !in_quotes => {
+                depth -= 1;
+                if depth == 0 {
+                    args.push(current.trim().to_string());
+                    return Some((args, i + 1));
+                }
+            }
+            ',' if !in_quotes && depth == 1 => {
+                args.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            _ => (),
+        }
+        current.push(c);
+    }
+    None
+}
+
+/// Removes C comments, a comment spanning several lines is removed from each of them.
+fn strip_comments(line: &str, in_comment: &mut bool) -> String {
+    let mut result = String::with_capacity(line.len());
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if *in_comment {
+            if c == '*' && chars.peek() == Some(&'/') {
+                chars.next();
+                *in_comment = false;
+                result.push(' ');
+            }
+            continue;
+        }
+        if in_quotes {
+            if c == '\\' {
+                result.push(c);
+                if let Some(escaped) = chars.next() {
+                    result.push(escaped);
+                }
+                continue;
+            }
+            in_quotes = c != '"';
+        } else if c == '"' {
+            in_quotes = true;
+        } else if c == '/' && chars.peek() == Some(&'*') {
+            chars.next();
+            *in_comment = true;
+            continue;
+        } else if c == '/' && chars.peek() == Some(&'/') {
+            break;
+        }
+        result.push(c);
+    }
+    result
+}
@@ -0,0 +1,668 @@
+/// Contains the Thumb-2 decoder, which turns machine code back into compiled instructions and assembly text.
+/// Decoding an encoded instruction and encoding it again gives the same machine code.
+use crate::arm7::{ConditionCode, ITStatus, Label, MnemonicExtension, Operands};
+use crate::encode::{self, Encoding};
+use std::collections::VecDeque;
+
+/// Addresses the decoder needs to decode an instruction.
+pub struct Context<'a> {
+    /// Address of the instruction.
+    pub address: u32,
+    /// Label at a branch target address.
+    pub label: &'a dyn Fn(u32) -> Option<Label>,
+    /// Word stored at an address, used to read the literal loaded by "ldr Rt, =value"
+    pub word: &'a dyn Fn(u32) -> Option<u32>,
+}
+
+/// A decoded instruction.
+#[derive(Debug, PartialEq)]
+pub struct Decoded {
+    pub mnemonic: String,
+    pub extension: MnemonicExtension,
+    pub operands: Operands,
+    pub encoding: Encoding,
+}
+
+/// The fields of an instruction, before the IT block is applied.
+struct Fields {
+    mnemonic: &'static str,
+    s: bool,
+    /// Condition of a conditional branch.
+    cc: Option<ConditionCode>,
+    operands: Operands,
+    /// Address of a branch target.
+    target: u32,
+    /// Address of the literal loaded by "ldr Rt, =value"
+    literal: u32,
+}
+impl Fields {
+    fn new(mnemonic: &'static str, operands: Operands) -> Self {
+        Fields {
+            mnemonic,
+            s: false,
+            cc: None,
+            operands,
+            target: 0,
+            literal: 0,
+        }
+    }
+    fn flags(mut self, s: bool) -> Self {
+        self.s = s;
+        self
+    }
+}
+
+/// Returns true if a halfword is the first half of a 32-bit instruction.
+pub fn is_wide(halfword: u16) -> bool {
+    halfword >> 11 >= 0b11101
+}
+
+/// Decodes the instruction at the start of halfwords.
+/// it_block holds the conditions of the current IT block, the instruction takes the first one.
+pub fn decode(
+    halfwords: &[u16],
+    it_block: &mut VecDeque<ConditionCode>,
+    context: &Context,
+) -> Result<Decoded, String> {
+    let first = *halfwords
+        .first()
+        .ok_or("There is no instruction to decode.")?;
+    let encoding = if is_wide(first) {
+        let second = *halfwords
+            .get(1)
+            .ok_or("The second half of a 32-bit instruction is missing.")?;
+        Encoding::Wide(u32::from(first) << 16 | u32::from(second))
+    } else {
+        Encoding::Narrow(first)
+    };
+    let it_cc = it_block.pop_front();
+    let it_status = match it_cc {
+        None => ITStatus::OUT,
+        Some(_) if it_block.is_empty() => ITStatus::LAST,
+        Some(_) => ITStatus::IN,
+    };
+    let in_it_block = it_status != ITStatus::OUT;
+    let fields = match encoding {
+        Encoding::Narrow(halfword) => narrow(halfword, in_it_block, context)?,
+        Encoding::Wide(word) => wide(word, context)?,
+    };
+    let mut extension = MnemonicExtension::new();
+    extension.cc = it_cc.or(fields.cc);
+    extension.s = fields.s;
+    extension.it_status = it_status;
+    // the assembler only picks a wide encoding without .w when there is no narrow one.
+    if let Encoding::Wide(_) = encoding {
+        let encode_context = encode::Context {
+            address: context.address,
+            target: &|_| fields.target,
+            literal: &|_| fields.literal,
+        };
+        let encoded = encode::encode(
+            fields.mnemonic,
+            &extension,
+            &fields.operands,
+            &encode_context,
+            false,
+        );
+        // cmp only accepts an immediate above 255 with .w
+        let is_cmp_immediate =
+            matches!(fields.operands, Operands::Rd_immed { .. }) && fields.mnemonic == "cmp";
+        extension.w = is_cmp_immediate || matches!(encoded, Ok(Encoding::Narrow(_)));
+    }
+    Ok(Decoded {
+        mnemonic: fields.mnemonic.to_string(),
+        extension,
+        operands: fields.operands,
+        encoding,
+    })
+}
+
+/// Decodes an IT instruction into the conditions of its block, or None if the halfword is not an IT instruction.
+pub fn it_conditions(halfword: u16) -> Option<Vec<ConditionCode>> {
+    let mask = halfword & 0xF;
+    if halfword & 0xFF00 != 0xBF00 || mask == 0 {
+        return None;
+    }
+    let first_cond = halfword >> 4 & 0xF;
+    let first = encode::condition_code(first_cond)?;
+    let mut conditions = vec![first];
+    // the mask ends with a 1 bit, the bits before it choose the condition of the next instructions.
+    for bit in (mask.trailing_zeros() as u16 + 1..4).rev() {
+        if mask >> bit & 1 == first_cond & 1 {
+            conditions.push(first);
+        } else {
+            conditions.push(first.opposite_condition());
+        }
+    }
+    Some(conditions)
+}
+
+/// Formats an IT instruction, ex: "ite eq"
+pub fn it_text(halfword: u16) -> Option<String> {
+    let conditions = it_conditions(halfword)?;
+    let pattern: String = conditions[1..]
+        .iter()
+        .map(|&cc| if cc == conditions[0] { 't' } else { 'e' })
+        .collect();
+    Some(format!("it{} {}", pattern, condition_name(conditions[0])))
+}
+
+/// Formats a decoded instruction as assembly text, ex: "adds r0, r1, #1"
+/// name returns the name of a branch target.
+pub fn format(decoded: &Decoded, name: &dyn Fn(Label) -> String) -> String {
+    let extension = &decoded.extension;
+    let mut mnemonic = decoded.mnemonic.clone();
+    if extension.s {
+        mnemonic.push('s');
+    }
+    if let Some(cc) = extension.cc {
+        mnemonic += &condition_name(cc);
+    }
+    if extension.w {
+        mnemonic += ".w";
+    }
+    let operands = match decoded.operands {
+        Operands::Rd_immed { Rd, immed } => format!("{}, #{}", register(Rd), immed),
+        Operands::Rd_Rm { Rd, Rm, .. } => format!("{}, {}", register(Rd), register(Rm)),
+        Operands::Rd_Rn_immed { Rd, Rn, immed } => {
+            format!("{}, {}, #{}", register(Rd), register(Rn), immed)
+        }
+        Operands::Rd_Rn_Rm { Rd, Rn, Rm, .. } => {
+            format!("{}, {}, {}", register(Rd), register(Rn), register(Rm))
+        }
+        Operands::label { label } => name(label),
+        Operands::Rt_Rn_imm { Rt, Rn, imm } => match imm {
+            Some(imm) => format!("{}, [{}, #{}]", register(Rt), register(Rn), imm),
+            None => format!("{}, [{}]", register(Rt), register(Rn)),
+        },
+        Operands::Rt_Rn_imm_post { Rt, Rn, imm } => {
+            format!("{}, [{}], #{}", register(Rt), register(Rn), imm)
+        }
+        Operands::Rt_Rn_imm_pre { Rt, Rn, imm } => {
+            format!("{}, [{}, #{}]!", register(Rt), register(Rn), imm)
+        }
+        Operands::Rt_Rn_Rm { Rt, Rn, Rm, shift } => match shift {
+            Some(shift) => format!(
+                "{}, [{}, {}, lsl #{}]",
+                register(Rt),
+                register(Rn),
+                register(Rm),
+                shift
+            ),
+            None => format!("{}, [{}, {}]", register(Rt), register(Rn), register(Rm)),
+        },
+        Operands::Rt_label { Rt, label } => format!("{}, ={:#x}", register(Rt), label),
+        Operands::Rt_imm { Rt, label } => format!("{}, ={:#x}", register(Rt), label),
+    };
+    format!("{} {}", mnemonic, operands)
+}
+
+/// Disassembles a block of code that starts at an address.
+/// Returns the address and text of every instruction, halfwords that are not instructions are shown as ".hword"
+pub fn disassemble(
+    code: &[u8],
+    start: u32,
+    label: &dyn Fn(u32) -> Option<Label>,
+    name: &dyn Fn(Label) -> String,
+) -> Vec<(u32, String)> {
+    let halfwords: Vec<u16> = code
+        .chunks_exact(2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+        .collect();
+    let word = |address: u32| {
+        let offset = address.checked_sub(start)? as usize;
+        let bytes = code.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    };
+    let mut it_block: VecDeque<ConditionCode> = VecDeque::new();
+    let mut lines = Vec::new();
+    let mut index = 0;
+    while index < halfwords.len() {
+        let address = start + 2 * index as u32;
+        if let (Some(conditions), true) = (it_conditions(halfwords[index]), it_block.is_empty()) {
+            lines.push((address, it_text(halfwords[index]).unwrap_or_default()));
+            it_block.extend(conditions);
+            index += 1;
+            continue;
+        }
+        let context = Context {
+            address,
+            label,
+            word: &word,
+        };
+        match decode(&halfwords[index..], &mut it_block, &context) {
+            Ok(decoded) => {
+                lines.push((address, format(&decoded, name)));
+                index += decoded.encoding.size() as usize / 2;
+            }
+            Err(_) => {
+                lines.push((address, format!(".hword {:#06x}", halfwords[index])));
+                index += 1;
+            }
+        }
+    }
+    lines
+}
+
+/// Register names in assembly text, r13 to r15 use their special names.
+fn register(r: u8) -> String {
+    match r {
+        13 => "sp".into(),
+        14 => "lr".into(),
+        15 => "pc".into(),
+        _ => format!("r{}", r),
+    }
+}
+
+fn condition_name(cc: ConditionCode) -> String {
+    format!("{:?}", cc).to_lowercase()
+}
+
+/// Sign extends the lowest bits of a value.
+fn sign_extend(value: u32, bits: u32) -> i64 {
+    let shift = 32 - bits;
+    i64::from(((value << shift) as i32) >> shift)
+}
+
+/// Address of a branch target, the PC is 4 bytes after the branch.
+fn branch_target(context: &Context, offset: i64) -> Result<(u32, Operands), String> {
+    let target = (i64::from(context.address) + 4 + offset) as u32;
+    let label = (context.label)(target)
+        .ok_or_else(|| format!("There is no label at the branch target {:#010x}.", target))?;
+    Ok((target, Operands::label { label }))
+}
+
+/// Reads the literal loaded by "ldr Rt, =value"
+fn literal_load(context: &Context, rt: u8, offset: i64) -> Result<Fields, String> {
+    let pc = (context.address + 4) & !3;
+    let literal = (i64::from(pc) + offset) as u32;
+    let value = (context.word)(literal)
+        .ok_or_else(|| format!("The literal at {:#010x} cannot be read.", literal))?;
+    let mut fields = Fields::new(
+        "ldr",
+        Operands::Rt_imm {
+            Rt: rt,
+            label: value,
+        },
+    );
+    fields.literal = literal;
+    Ok(fields)
+}
+
+/// Mnemonic of a load or store, from its size in bytes.
+fn memory_mnemonic(load: bool, size: u32) -> &'static str {
+    match (load, size) {
+        (false, 1) => "strb",
+        (false, 2) => "strh",
+        (false, _) => "str",
+        (true, 1) => "ldrb",
+        (true, 2) => "ldrh",
+        (true, _) => "ldr",
+    }
+}
+
+/// Decodes a 16-bit instruction.
+fn narrow(halfword: u16, in_it_block: bool, context: &Context) -> Result<Fields, String> {
+    let bits = |shift: u16, width: u16| ((halfword >> shift) & ((1 << width) - 1)) as u8;
+    let imm8 = u32::from(halfword & 0xFF);
+    // narrow data-processing instructions set the flags outside an IT block
+    let sets_flags = !in_it_block;
+    let fields = match halfword {
+        h if h & 0xF800 == 0x2000 => Fields::new(
+            "mov",
+            Operands::Rd_immed {
+                Rd: bits(8, 3),
+                immed: imm8,
+            },
+        )
+        .flags(sets_flags),
+        h if h & 0xFF00 == 0x4600 => Fields::new(
+            "mov",
+            Operands::Rd_Rm {
+                Rd: bits(7, 1) << 3 | bits(0, 3),
+                Rm: bits(3, 4),
+                shift: None,
+            },
+        ),
+        h if h & 0xFFC0 == 0x0000 => Fields::new(
+            "mov",
+            Operands::Rd_Rm {
+                Rd: bits(0, 3),
+                Rm: bits(3, 3),
+                shift: None,
+            },
+        )
+        .flags(sets_flags),
+        h if h & 0xF800 == 0x3000 => Fields::new(
+            "add",
+            Operands::Rd_immed {
+                Rd: bits(8, 3),
+                immed: imm8,
+            },
+        )
+        .flags(sets_flags),
+        h if h & 0xFE00 == 0x1C00 => Fields::new(
+            "add",
+            Operands::Rd_Rn_immed {
+                Rd: bits(0, 3),
+                Rn: bits(3, 3),
+                immed: u32::from(bits(6, 3)),
+            },
+        )
+        .flags(sets_flags),
+        h if h & 0xFF80 == 0xB000 => Fields::new(
+            "add",
+            Operands::Rd_immed {
+                Rd: 13,
+                immed: u32::from(bits(0, 7)) * 4,
+            },
+        ),
+        h if h & 0xF800 == 0xA800 => Fields::new(
+            "add",
+            Operands::Rd_Rn_immed {
+                Rd: bits(8, 3),
+                Rn: 13,
+                immed: imm8 * 4,
+            },
+        ),
+        h if h & 0xFF00 == 0x4400 => Fields::new(
+            "add",
+            Operands::Rd_Rm {
+                Rd: bits(7, 1) << 3 | bits(0, 3),
+                Rm: bits(3, 4),
+                shift: None,
+            },
+        ),
+        h if h & 0xFE00 == 0x1800 => Fields::new(
+            "add",
+            Operands::Rd_Rn_Rm {
+                Rd: bits(0, 3),
+                Rn: bits(3, 3),
+                Rm: bits(6, 3),
+                shift: None,
+            },
+        )
+        .flags(sets_flags),
+        h if h & 0xF800 == 0x2800 => Fields::new(
+            "cmp",
+            Operands::Rd_immed {
+                Rd: bits(8, 3),
+                immed: imm8,
+            },
+        ),
+        h if h & 0xFFC0 == 0x4280 => Fields::new(
+            "cmp",
+            Operands::Rd_Rm {
+                Rd: bits(0, 3),
+                Rm: bits(3, 3),
+                shift: None,
+            },
+        ),
+        h if h & 0xFF00 == 0x4500 => Fields::new(
+            "cmp",
+            Operands::Rd_Rm {
+                Rd: bits(7, 1) << 3 | bits(0, 3),
+                Rm: bits(3, 4),
+                shift: None,
+            },
+        ),
+        h if h & 0xF000 == 0xD000 && bits(8, 4) < 14 => {
+            let (target, operands) = branch_target(context, sign_extend(imm8 << 1, 9))?;
+            let mut fields = Fields::new("b", operands);
+            fields.cc = encode::condition_code(u16::from(bits(8, 4)));
+            fields.target = target;
+            fields
+        }
+        h if h & 0xF800 == 0xE000 => {
+            let offset = sign_extend(u32::from(h & 0x7FF) << 1, 12);
+            let (target, operands) = branch_target(context, offset)?;
+            let mut fields = Fields::new("b", operands);
+            fields.target = target;
+            fields
+        }
+        h if h & 0xF800 == 0x4800 => literal_load(context, bits(8, 3), i64::from(imm8 * 4))?,
+        h if (0x6000..0x9000).contains(&(h & 0xF800)) => {
+            let (load, size) = match h & 0xF800 {
+                0x6000 => (false, 4),
+                0x6800 => (true, 4),
+                0x7000 => (false, 1),
+                0x7800 => (true, 1),
+                0x8000 => (false, 2),
+                _ => (true, 2),
+            };
+            let offset = i32::from(bits(6, 5)) * size as i32;
+            Fields::new(
+                memory_mnemonic(load, size),
+                Operands::Rt_Rn_imm {
+                    Rt: bits(0, 3),
+                    Rn: bits(3, 3),
+                    imm: if offset == 0 { None } else { Some(offset) },
+                },
+            )
+        }
+        h if h & 0xF000 == 0x9000 => {
+            let offset = imm8 as i32 * 4;
+            Fields::new(
+                memory_mnemonic(h & 0x0800 != 0, 4),
+                Operands::Rt_Rn_imm {
+                    Rt: bits(8, 3),
+                    Rn: 13,
+                    imm: if offset == 0 { None } else { Some(offset) },
+                },
+            )
+        }
+        h if h & 0xF000 == 0x5000 => {
+            let (load, size) = match h & 0xFE00 {
+                0x5000 => (false, 4),
+                0x5200 => (false, 2),
+                0x5400 => (false, 1),
+                0x5800 => (true, 4),
+                0x5A00 => (true, 2),
+                0x5C00 => (true, 1),
+                _ => return Err(unsupported(u32::from(h))),
+            };
+            Fields::new(
+                memory_mnemonic(load, size),
+                Operands::Rt_Rn_Rm {
+                    Rt: bits(0, 3),
+                    Rn: bits(3, 3),
+                    Rm: bits(6, 3),
+                    shift: None,
+                },
+            )
+        }
+        h => return Err(unsupported(u32::from(h))),
+    };
+    Ok(fields)
+}
+
+/// Decodes a 32-bit instruction.
+fn wide(word: u32, context: &Context) -> Result<Fields, String> {
+    let bits = |shift: u32, width: u32| ((word >> shift) & ((1 << width) - 1)) as u8;
+    let s = word >> 20 & 1 == 1;
+    let (rn, rd, rm) = (bits(16, 4), bits(8, 4), bits(0, 4));
+    let fields = match word {
+        w if w & 0xFBEF_8000 == 0xF04F_0000 => Fields::new(
+            "mov",
+            Operands::Rd_immed {
+                Rd: rd,
+                immed: expand_modified_immediate(w),
+            },
+        )
+        .flags(s),
+        w if w & 0xFBF0_8000 == 0xF240_0000 => Fields::new(
+            "mov",
+            Operands::Rd_immed {
+                Rd: rd,
+                immed: u32::from(bits(16, 4)) << 12 | imm12(w),
+            },
+        ),
+        w if w & 0xFFEF_F0F0 == 0xEA4F_0000 => Fields::new(
+            "mov",
+            Operands::Rd_Rm {
+                Rd: rd,
+                Rm: rm,
+                shift: None,
+            },
+        )
+        .flags(s),
+        w if w & 0xFBF0_8F00 == 0xF1B0_0F00 => Fields::new(
+            "cmp",
+            Operands::Rd_immed {
+                Rd: rn,
+                immed: expand_modified_immediate(w),
+            },
+        ),
+        w if w & 0xFBE0_8000 == 0xF100_0000 => Fields::new(
+            "add",
+            Operands::Rd_Rn_immed {
+                Rd: rd,
+                Rn: rn,
+                immed: expand_modified_immediate(w),
+            },
+        )
+        .flags(s),
+        w if w & 0xFBF0_8000 == 0xF200_0000 => Fields::new(
+            "add",
+            Operands::Rd_Rn_immed {
+                Rd: rd,
+                Rn: rn,
+                immed: imm12(w),
+            },
+        ),
+        w if w & 0xFFF0_FFF0 == 0xEBB0_0F00 => Fields::new(
+            "cmp",
+            Operands::Rd_Rm {
+                Rd: rn,
+                Rm: rm,
+                shift: None,
+            },
+        ),
+        w if w & 0xFFE0_F0F0 == 0xEB00_0000 => Fields::new(
+            "add",
+            Operands::Rd_Rn_Rm {
+                Rd: rd,
+                Rn: rn,
+                Rm: rm,
+                shift: None,
+            },
+        )
+        .flags(s),
+        w if w & 0xF800_D000 == 0xF000_8000 && bits(22, 4) < 14 => {
+            let offset = (w >> 26 & 1) << 20
+                | (w >> 11 & 1) << 19
+                | (w >> 13 & 1) << 18
+                | (w >> 16 & 0x3F) << 12
+                | (w & 0x7FF) << 1;
+            let (target, operands) = branch_target(context, sign_extend(offset, 21))?;
+            let mut fields = Fields::new("b", operands);
+            fields.cc = encode::condition_code(u16::from(bits(22, 4)));
+            fields.target = target;
+            fields
+        }
+        w if w & 0xF800_9000 == 0xF000_9000 => {
+            let sign = w >> 26 & 1;
+            let i1 = !(w >> 13 ^ sign) & 1;
+            let i2 = !(w >> 11 ^ sign) & 1;
+            let offset =
+                sign << 24 | i1 << 23 | i2 << 22 | (w >> 16 & 0x3FF) << 12 | (w & 0x7FF) << 1;
+            let (target, operands) = branch_target(context, sign_extend(offset, 25))?;
+            let mnemonic = if w & 0x4000 != 0 { "bl" } else { "b" };
+            let mut fields = Fields::new(mnemonic, operands);
+            fields.target = target;
+            fields
+        }
+        w if w & 0xFF7F_0000 == 0xF85F_0000 => {
+            let offset = i64::from(w & 0xFFF);
+            let offset = if w & 0x0080_0000 != 0 {
+                offset
+            } else {
+                -offset
+            };
+            literal_load(context, bits(12, 4), offset)?
+        }
+        w if w & 0xFF80_0000 == 0xF880_0000 && bits(21, 2) < 3 => {
+            let offset = (w & 0xFFF) as i32;
+            Fields::new(
+                memory_mnemonic(s, 1 << bits(21, 2)),
+                Operands::Rt_Rn_imm {
+                    Rt: bits(12, 4),
+                    Rn: rn,
+                    imm: if offset == 0 { None } else { Some(offset) },
+                },
+            )
+        }
+        w if w & 0xFF80_0800 == 0xF800_0800 && bits(21, 2) < 3 => {
+            let (pre, up, writeback) = (w >> 10 & 1 == 1, w >> 9 & 1 == 1, w >> 8 & 1 == 1);
+            let imm = i32::from(bits(0, 8));
+            let imm = if up { imm } else { -imm };
+            let (rt, mnemonic) = (bits(12, 4), memory_mnemonic(s, 1 << bits(21, 2)));
+            match (pre, writeback) {
+                (true, false) if !up => Fields::new(
+                    mnemonic,
+                    Operands::Rt_Rn_imm {
+                        Rt: rt,
+                        Rn: rn,
+                        imm: Some(imm),
+                    },
+                ),
+                (true, true) => Fields::new(
+                    mnemonic,
+                    Operands::Rt_Rn_imm_pre {
+                        Rt: rt,
+                        Rn: rn,
+                        imm,
+                    },
+                ),
+                (false, true) => Fields::new(
+                    mnemonic,
+                    Operands::Rt_Rn_imm_post {
+                        Rt: rt,
+                        Rn: rn,
+                        imm,
+                    },
+                ),
+                _ => return Err(unsupported(w)),
+            }
+        }
+        w if w & 0xFF80_0FC0 == 0xF800_0000 && bits(21, 2) < 3 => {
+            let shift = bits(4, 2);
+            Fields::new(
+                memory_mnemonic(s, 1 << bits(21, 2)),
+                Operands::Rt_Rn_Rm {
+                    Rt: bits(12, 4),
+                    Rn: rn,
+                    Rm: rm,
+                    shift: if shift == 0 { None } else { Some(shift) },
+                },
+            )
+        }
+        w => return Err(unsupported(w)),
+    };
+    Ok(fields)
+}
+
+/// Reads the i:imm3:imm8 fields of a 32-bit instruction.
+fn imm12(word: u32) -> u32 {
+    (word >> 26 & 1) << 11 | (word >> 12 & 7) << 8 | (word & 0xFF)
+}
+
+/// Expands a Thumb-2 modified immediate constant into its 32-bit value.
+fn expand_modified_immediate(word: u32) -> u32 {
+    let imm12 = imm12(word);
+    let byte = imm12 & 0xFF;
+    match imm12 >> 8 {
+        0 => byte,
+        1 => byte * 0x0001_0001,
+        2 => byte * 0x0100_0100,
+        3 => byte * 0x0101_0101,
+        _ => (0x80 | (imm12 & 0x7F)).rotate_right(imm12 >> 7),
+    }
+}
+
+fn unsupported(machine_code: u32) -> String {
+    format!(
+        "{:#x} is not an instruction supported by the simulator.",
+        machine_code
+    )
+}
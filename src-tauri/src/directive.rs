@@ -0,0 +1,122 @@
+/// Contains the directives that are passed on from the preprocessor, and checks that every directive is supported.
+/// Files written for arm-none-eabi-as contain directives that only matter to a real toolchain, those are accepted and removed.
+use crate::error;
+use crate::link::{self, Section};
+use crate::utils;
+
+/// What the preprocessor does with a directive.
+#[derive(Debug, PartialEq)]
+pub enum Handling {
+    /// The directive is used by a later pass.
+    Keep,
+    /// The directive has no effect in the simulator.
+    Ignore,
+    /// Stops assembling the file.
+    End,
+}
+
+/// Directives used by the label, link, and compile passes.
+const KEPT: [&str; 6] = [
+    ".global",
+    ".globl",
+    ".weak",
+    ".extern",
+    ".type",
+    ".thumb_func",
+];
+/// Directives that only matter to a real toolchain.
+const IGNORED: [&str; 17] = [
+    ".thumb",
+    ".force_thumb",
+    ".cpu",
+    ".arch",
+    ".arch_extension",
+    ".fpu",
+    ".eabi_attribute",
+    ".file",
+    ".ident",
+    ".size",
+    ".func",
+    ".endfunc",
+    ".fnstart",
+    ".fnend",
+    ".cantunwind",
+    ".ltorg",
+    ".pool",
+];
+/// Directives handled by the preprocessor, used for suggestions.
+const PREPROCESSOR: [&str; 21] = [
+    ".macro",
+    ".endm",
+    ".exitm",
+    ".include",
+    ".if",
+    ".ifdef",
+    ".ifndef",
+    ".ifnotdef",
+    ".ifeq",
+    ".ifne",
+    ".ifgt",
+    ".ifge",
+    ".iflt",
+    ".ifle",
+    ".elseif",
+    ".else",
+    ".endif",
+    ".equ",
+    ".set",
+    ".unreq",
+    ".end",
+];
+
+/// Decides what to do with a directive that the preprocessor does not handle itself.
+/// Returns an error for directives that are not supported.
+pub fn check(directive: &str, args: &str) -> Result<Handling, String> {
+    let args = args.trim().to_lowercase();
+    match directive {
+        ".end" => Ok(Handling::End),
+        ".syntax" if args == "unified" => Ok(Handling::Ignore),
+        ".syntax" => Err("Only the unified syntax is supported, use \".syntax unified\"".into()),
+        ".code" if args == "16" => Ok(Handling::Ignore),
+        ".code" | ".arm" => Err("Only Thumb code is supported, use \".thumb\"".into()),
+        ".type" => {
+            let kind = args.split(',').nth(1).unwrap_or("").trim();
+            if matches!(
+                kind,
+                "%function" | "@function" | "%object" | "@object" | "stt_func" | "stt_object"
+            ) {
+                Ok(Handling::Keep)
+            } else {
+                Err(
+                    ".type must be written as \".type name, %function\" or \".type name, %object\""
+                        .into(),
+                )
+            }
+        }
+        _ if directive.starts_with(".cfi_") => Ok(Handling::Ignore),
+        _ if IGNORED.contains(&directive) => Ok(Handling::Ignore),
+        _ if KEPT.contains(&directive)
+            || link::is_data_directive(directive)
+            || Section::from_directive(directive).is_some()
+            || directive == ".section" =>
+        {
+            Ok(Handling::Keep)
+        }
+        _ => {
+            let message = format!("Unknown directive \"{}\".", directive);
+            let known = KEPT
+                .iter()
+                .chain(&IGNORED)
+                .chain(&PREPROCESSOR)
+                .chain(&[
+                    ".text", ".data", ".bss", ".rodata", ".section", ".syntax", ".code",
+                ])
+                .copied()
+                .chain(link::DATA_DIRECTIVES);
+            Err(error::with_suggestion(
+                message,
+                utils::closest_match(directive, known),
+            ))
+        }
+    }
+}
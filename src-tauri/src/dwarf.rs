@@ -0,0 +1,121 @@
+//! Contains the DWARF writer, which builds the .debug_line table that maps addresses back to source lines.
+//! Version 2 of the line number program is used, since every debugger and objdump can read it.
+
+/// Thumb instructions are a multiple of 2 bytes.
+const MINIMUM_INSTRUCTION_LENGTH: u8 = 2;
+const LINE_BASE: i8 = -5;
+const LINE_RANGE: u8 = 14;
+const OPCODE_BASE: u8 = 13;
+/// Number of LEB128 operands of each standard opcode.
+const STANDARD_OPCODE_LENGTHS: [u8; 12] = [0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+
+/// Standard opcodes
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+/// Extended opcodes, which start with a zero byte.
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+
+/// A row of the line table.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineRow {
+    pub address: u32,
+    /// Index into the list of file names, starts at zero.
+    pub file: usize,
+    /// starts at one
+    pub line: usize,
+}
+
+/// Builds the contents of a .debug_line section with a single sequence.
+/// The rows must be sorted by address, the sequence ends at end_address.
+pub fn debug_line(rows: &[LineRow], files: &[String], end_address: u32) -> Vec<u8> {
+    let mut header = vec![
+        MINIMUM_INSTRUCTION_LENGTH,
+        // default_is_stmt
+        1,
+        LINE_BASE as u8,
+        LINE_RANGE,
+        OPCODE_BASE,
+    ];
+    header.extend(STANDARD_OPCODE_LENGTHS);
+    // no include directories
+    header.push(0);
+    for file in files {
+        header.extend(file.as_bytes());
+        // null terminator, directory, modification time, and length
+        header.extend([0, 0, 0, 0]);
+    }
+    header.push(0);
+
+    let mut program = Vec::new();
+    let (mut address, mut file, mut line) = (0, 0, 1);
+    if let Some(first) = rows.first() {
+        address = first.address;
+        program.extend([0, 5, DW_LNE_SET_ADDRESS]);
+        program.extend(address.to_le_bytes());
+    }
+    for row in rows {
+        if row.file != file {
+            file = row.file;
+            program.push(DW_LNS_SET_FILE);
+            uleb128(&mut program, file as u64 + 1);
+        }
+        if row.line != line {
+            program.push(DW_LNS_ADVANCE_LINE);
+            sleb128(&mut program, row.line as i64 - line as i64);
+            line = row.line;
+        }
+        if row.address != address {
+            program.push(DW_LNS_ADVANCE_PC);
+            let advance = (row.address - address) / MINIMUM_INSTRUCTION_LENGTH as u32;
+            uleb128(&mut program, advance as u64);
+            address = row.address;
+        }
+        program.push(DW_LNS_COPY);
+    }
+    if end_address > address {
+        program.push(DW_LNS_ADVANCE_PC);
+        let advance = (end_address - address) / MINIMUM_INSTRUCTION_LENGTH as u32;
+        uleb128(&mut program, advance as u64);
+    }
+    program.extend([0, 1, DW_LNE_END_SEQUENCE]);
+
+    // version, header_length, header, and program follow unit_length
+    let unit_length = 2 + 4 + header.len() + program.len();
+    let mut section = Vec::with_capacity(4 + unit_length);
+    section.extend((unit_length as u32).to_le_bytes());
+    section.extend(2u16.to_le_bytes());
+    section.extend((header.len() as u32).to_le_bytes());
+    section.extend(header);
+    section.extend(program);
+    section
+}
+
+/// Appends an unsigned LEB128 number.
+fn uleb128(bytes: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// Appends a signed LEB128 number.
+fn sleb128(bytes: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+        if done {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
@@ -0,0 +1,429 @@
+/// Contains the ELF32 reader, for executables built by arm-none-eabi toolchains, and the writer for exported programs.
+/// Only little-endian ARM executables are supported.
+use std::convert::TryInto;
+
+/// e_machine of ARM
+pub const EM_ARM: u16 = 40;
+/// e_type of an executable file
+pub const ET_EXEC: u16 = 2;
+/// p_type of a segment that is loaded into memory
+pub const PT_LOAD: u32 = 1;
+/// p_flags bits
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+/// e_flags of an EABI version 5 file that uses software floating point
+pub const EF_ARM_EABI5_SOFT_FLOAT: u32 = 0x0500_0200;
+/// sh_type values
+pub const SHT_PROGBITS: u32 = 1;
+pub const SHT_SYMTAB: u32 = 2;
+pub const SHT_STRTAB: u32 = 3;
+pub const SHT_NOBITS: u32 = 8;
+pub const SHT_ARM_ATTRIBUTES: u32 = 0x7000_0003;
+/// sh_flags bits
+pub const SHF_WRITE: u32 = 1;
+pub const SHF_ALLOC: u32 = 2;
+pub const SHF_EXECINSTR: u32 = 4;
+/// Section index of a symbol with an absolute value
+pub const SHN_ABS: u16 = 0xFFF1;
+/// st_info types
+pub const STT_NOTYPE: u8 = 0;
+pub const STT_OBJECT: u8 = 1;
+pub const STT_FUNC: u8 = 2;
+pub const STT_SECTION: u8 = 3;
+pub const STT_FILE: u8 = 4;
+
+/// A segment from the program headers.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub address: u32,
+    /// The bytes stored in the file, the rest of the segment is zeroed.
+    pub data: Vec<u8>,
+    pub memory_size: u32,
+    pub flags: u32,
+}
+impl Segment {
+    pub fn is_executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+}
+
+/// A section from the section headers.
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub name: String,
+    pub kind: u32,
+    pub flags: u32,
+    pub address: u32,
+    pub size: u32,
+}
+impl Section {
+    /// Returns true if the section holds code that is loaded into memory.
+    pub fn is_code(&self) -> bool {
+        self.flags & (SHF_ALLOC | SHF_EXECINSTR) == SHF_ALLOC | SHF_EXECINSTR
+    }
+}
+
+/// A symbol from .symtab
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    /// Address of the symbol, the Thumb bit of functions is cleared.
+    pub value: u32,
+    pub size: u32,
+    pub kind: u8,
+    pub global: bool,
+    /// Index of the section that defines the symbol, 0 if it is undefined.
+    pub section: u16,
+}
+impl Symbol {
+    /// Mapping symbols mark the start of Thumb code ($t), ARM code ($a), or data ($d) in a section.
+    pub fn mapping(&self) -> Option<char> {
+        let mut chars = self.name.chars();
+        match (chars.next(), chars.next(), chars.next()) {
+            (Some('$'), Some(kind @ ('t' | 'a' | 'd')), None | Some('.')) => Some(kind),
+            _ => None,
+        }
+    }
+    /// Returns true if the symbol is a label, not a mapping, section or file symbol.
+    pub fn is_label(&self) -> bool {
+        self.section != 0
+            && self.mapping().is_none()
+            && !self.name.is_empty()
+            && matches!(self.kind, STT_NOTYPE | STT_OBJECT | STT_FUNC)
+    }
+}
+
+/// An ELF executable.
+#[derive(Debug, Clone)]
+pub struct Elf {
+    /// Address of the first instruction, the Thumb bit is cleared.
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+}
+impl Elf {
+    /// Reads an ELF32 little-endian ARM executable.
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.get(..4) != Some(b"\x7fELF") {
+            return Err("The file is not an ELF file.".into());
+        }
+        if bytes.len() < 52 {
+            return Err("The ELF header is incomplete.".into());
+        }
+        if bytes[4] != 1 || bytes[5] != 1 {
+            return Err("Only 32-bit little-endian ELF files are supported.".into());
+        }
+        let reader = Reader(bytes);
+        if reader.u16(18)? != EM_ARM {
+            return Err("The ELF file is not for an ARM processor.".into());
+        }
+        if reader.u16(16)? != ET_EXEC {
+            return Err("The ELF file is not an executable, link the object files first.".into());
+        }
+        let entry = reader.u32(24)? & !1;
+        let (phoff, shoff) = (reader.u32(28)? as usize, reader.u32(32)? as usize);
+        let (phentsize, phnum) = (reader.u16(42)? as usize, reader.u16(44)? as usize);
+        let (shentsize, shnum) = (reader.u16(46)? as usize, reader.u16(48)? as usize);
+        let shstrndx = reader.u16(50)? as usize;
+
+        let mut segments = Vec::new();
+        for index in 0..phnum {
+            let header = phoff + index * phentsize;
+            if reader.u32(header)? != PT_LOAD {
+                continue;
+            }
+            let (offset, file_size) = (reader.u32(header + 4)?, reader.u32(header + 16)?);
+            segments.push(Segment {
+                address: reader.u32(header + 8)?,
+                data: reader.bytes(offset, file_size)?.to_vec(),
+                memory_size: reader.u32(header + 20)?,
+                flags: reader.u32(header + 24)?,
+            });
+        }
+
+        // (name offset, section, file offset, link)
+        let mut headers = Vec::new();
+        for index in 0..shnum {
+            let header = shoff + index * shentsize;
+            let section = Section {
+                name: String::new(),
+                kind: reader.u32(header + 4)?,
+                flags: reader.u32(header + 8)?,
+                address: reader.u32(header + 12)?,
+                size: reader.u32(header + 20)?,
+            };
+            headers.push((
+                reader.u32(header)?,
+                section,
+                reader.u32(header + 16)?,
+                reader.u32(header + 24)?,
+            ));
+        }
+        let names = match headers.get(shstrndx) {
+            Some((_, section, offset, _)) => reader.bytes(*offset, section.size)?,
+            None => &[],
+        };
+        let mut symbols = Vec::new();
+        for (_, section, offset, link) in &headers {
+            if section.kind != SHT_SYMTAB {
+                continue;
+            }
+            let strings = match headers.get(*link as usize) {
+                Some((_, strings, offset, _)) => reader.bytes(*offset, strings.size)?,
+                None => return Err("The symbol table has no string table.".into()),
+            };
+            let table = Reader(reader.bytes(*offset, section.size)?);
+            for entry in (16..section.size as usize).step_by(16) {
+                let kind = table.u8(entry + 12)? & 0xF;
+                let section_index = table.u16(entry + 14)?;
+                let mut value = table.u32(entry + 4)?;
+                let is_code = headers
+                    .get(section_index as usize)
+                    .map_or(false, |(_, section, _, _)| section.is_code());
+                if kind == STT_FUNC && is_code {
+                    value &= !1;
+                }
+                symbols.push(Symbol {
+                    name: string(strings, table.u32(entry)?),
+                    value,
+                    size: table.u32(entry + 8)?,
+                    kind,
+                    global: table.u8(entry + 12)? >> 4 != 0,
+                    // special indices such as SHN_ABS are not sections
+                    section: if section_index >= 0xFF00 {
+                        0
+                    } else {
+                        section_index
+                    },
+                });
+            }
+        }
+        let sections = headers
+            .into_iter()
+            .map(|(name, mut section, _, _)| {
+                section.name = string(names, name);
+                section
+            })
+            .collect();
+        Ok(Elf {
+            entry,
+            segments,
+            sections,
+            symbols,
+        })
+    }
+    /// Returns the bytes stored at an address in the loaded segments.
+    pub fn read(&self, address: u32, size: u32) -> Option<&[u8]> {
+        self.segments.iter().find_map(|segment| {
+            let offset = address.checked_sub(segment.address)? as usize;
+            segment.data.get(offset..offset + size as usize)
+        })
+    }
+    /// Returns the word stored at an address in the loaded segments.
+    pub fn word(&self, address: u32) -> Option<u32> {
+        let bytes = self.read(address, 4)?;
+        Some(u32::from_le_bytes(bytes.try_into().ok()?))
+    }
+}
+
+/// A section to write to an exported ELF file.
+#[derive(Debug, Clone)]
+pub struct SectionData {
+    pub name: String,
+    pub kind: u32,
+    pub flags: u32,
+    pub address: u32,
+    pub data: Vec<u8>,
+    pub align: u32,
+}
+impl SectionData {
+    fn is_alloc(&self) -> bool {
+        self.flags & SHF_ALLOC != 0
+    }
+}
+
+/// Returns the contents of an .ARM.attributes section for Thumb-2 code on an ARMv7-M processor.
+/// Tools such as objdump read it to pick the instruction set.
+pub fn armv7m_attributes() -> Vec<u8> {
+    // Tag_CPU_name "7-M", Tag_CPU_arch v7, Tag_CPU_arch_profile 'M', Tag_THUMB_ISA_use Thumb-2
+    let tags = [5, b'7', b'-', b'M', 0, 6, 10, 7, b'M', 9, 2];
+    let mut file = vec![1];
+    file.extend((5 + tags.len() as u32).to_le_bytes());
+    file.extend(tags);
+    let mut vendor = b"aeabi\0".to_vec();
+    vendor.extend(file);
+    let mut section = vec![b'A'];
+    section.extend((4 + vendor.len() as u32).to_le_bytes());
+    section.extend(vendor);
+    section
+}
+
+/// Writes an ELF32 little-endian ARM executable.
+/// Each allocated section gets its own loadable segment, .symtab, .strtab, and .shstrtab are added after the given sections.
+/// The section of a symbol is an index into sections, starting at one since index zero is the null section.
+pub fn write(entry: u32, sections: &[SectionData], symbols: &[Symbol]) -> Vec<u8> {
+    const HEADER_SIZE: usize = 52;
+    const PROGRAM_HEADER_SIZE: usize = 32;
+    const SECTION_HEADER_SIZE: usize = 40;
+    const SYMBOL_SIZE: usize = 16;
+
+    // Local symbols must come before the global ones.
+    let mut ordered: Vec<&Symbol> = symbols.iter().filter(|symbol| !symbol.global).collect();
+    let first_global = ordered.len() + 1;
+    ordered.extend(symbols.iter().filter(|symbol| symbol.global));
+    let mut strtab = vec![0];
+    let mut symtab = vec![0; SYMBOL_SIZE];
+    for symbol in ordered {
+        symtab.extend((strtab.len() as u32).to_le_bytes());
+        strtab.extend(symbol.name.as_bytes());
+        strtab.push(0);
+        symtab.extend(symbol.value.to_le_bytes());
+        symtab.extend(symbol.size.to_le_bytes());
+        symtab.push((symbol.global as u8) << 4 | symbol.kind);
+        symtab.push(0);
+        symtab.extend(symbol.section.to_le_bytes());
+    }
+
+    let symtab_index = sections.len() as u32 + 1;
+    let mut all: Vec<(SectionData, u32, u32, u32)> = sections
+        .iter()
+        .map(|section| (section.clone(), 0, 0, 0))
+        .collect();
+    let table = |name: &str, kind: u32, data: Vec<u8>, align: u32| SectionData {
+        name: name.into(),
+        kind,
+        flags: 0,
+        address: 0,
+        data,
+        align,
+    };
+    // (section, link, info, entry size)
+    all.push((
+        table(".symtab", SHT_SYMTAB, symtab, 4),
+        symtab_index + 1,
+        first_global as u32,
+        SYMBOL_SIZE as u32,
+    ));
+    all.push((table(".strtab", SHT_STRTAB, strtab, 1), 0, 0, 0));
+    let mut shstrtab = vec![0];
+    let mut names = Vec::new();
+    for (section, _, _, _) in &all {
+        names.push(shstrtab.len() as u32);
+        shstrtab.extend(section.name.as_bytes());
+        shstrtab.push(0);
+    }
+    names.push(shstrtab.len() as u32);
+    shstrtab.extend(b".shstrtab\0");
+    all.push((table(".shstrtab", SHT_STRTAB, shstrtab, 1), 0, 0, 0));
+
+    let segments = sections.iter().filter(|section| section.is_alloc()).count();
+    let mut file = vec![0; HEADER_SIZE + segments * PROGRAM_HEADER_SIZE];
+    let mut offsets = Vec::new();
+    for (section, _, _, _) in &all {
+        let align = section.align.max(1) as usize;
+        file.resize((file.len() + align - 1) / align * align, 0);
+        offsets.push(file.len() as u32);
+        file.extend(&section.data);
+    }
+    file.resize((file.len() + 3) / 4 * 4, 0);
+    let section_headers = file.len();
+
+    // null section header
+    file.extend([0; SECTION_HEADER_SIZE]);
+    for (index, (section, link, info, entry_size)) in all.iter().enumerate() {
+        for value in [
+            names[index],
+            section.kind,
+            section.flags,
+            section.address,
+            offsets[index],
+            section.data.len() as u32,
+            *link,
+            *info,
+            section.align,
+            *entry_size,
+        ] {
+            file.extend(value.to_le_bytes());
+        }
+    }
+
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    // 32-bit, little-endian, version 1
+    header.extend(b"\x7fELF\x01\x01\x01");
+    header.resize(16, 0);
+    header.extend(ET_EXEC.to_le_bytes());
+    header.extend(EM_ARM.to_le_bytes());
+    header.extend(1u32.to_le_bytes());
+    header.extend(entry.to_le_bytes());
+    header.extend((HEADER_SIZE as u32).to_le_bytes());
+    header.extend((section_headers as u32).to_le_bytes());
+    header.extend(EF_ARM_EABI5_SOFT_FLOAT.to_le_bytes());
+    for value in [
+        HEADER_SIZE,
+        PROGRAM_HEADER_SIZE,
+        segments,
+        SECTION_HEADER_SIZE,
+        all.len() + 1,
+        all.len(),
+    ] {
+        header.extend((value as u16).to_le_bytes());
+    }
+    for (index, section) in sections.iter().enumerate() {
+        if !section.is_alloc() {
+            continue;
+        }
+        let size = section.data.len() as u32;
+        let mut flags = PF_R;
+        if section.flags & SHF_WRITE != 0 {
+            flags |= PF_W;
+        }
+        if section.flags & SHF_EXECINSTR != 0 {
+            flags |= PF_X;
+        }
+        for value in [
+            PT_LOAD,
+            offsets[index],
+            section.address,
+            section.address,
+            size,
+            size,
+            flags,
+            section.align,
+        ] {
+            header.extend(value.to_le_bytes());
+        }
+    }
+    file[..header.len()].copy_from_slice(&header);
+    file
+}
+
+/// Reads little-endian values, with an error if the file is too short.
+struct Reader<'a>(&'a [u8]);
+impl<'a> Reader<'a> {
+    fn bytes(&self, offset: u32, size: u32) -> Result<&'a [u8], String> {
+        let (start, end) = (offset as usize, offset as usize + size as usize);
+        self.0
+            .get(start..end)
+            .ok_or_else(|| "The ELF file is truncated.".to_string())
+    }
+    fn u8(&self, offset: usize) -> Result<u8, String> {
+        Ok(self.bytes(offset as u32, 1)?[0])
+    }
+    fn u16(&self, offset: usize) -> Result<u16, String> {
+        let bytes = self.bytes(offset as u32, 2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+    fn u32(&self, offset: usize) -> Result<u32, String> {
+        let bytes = self.bytes(offset as u32, 4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+/// Reads a null-terminated string from a string table.
+fn string(table: &[u8], offset: u32) -> String {
+    let bytes = table.get(offset as usize..).unwrap_or(&[]);
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
@@ -0,0 +1,558 @@
+/// Contains the Thumb-2 encoder, which turns compiled instructions into their 16-bit or 32-bit machine code.
+/// The narrow (16-bit) encoding is picked when it is legal, the .w extension forces the wide (32-bit) encoding and .n forces the narrow one.
+use crate::arm7::{ConditionCode, ITStatus, Label, MnemonicExtension, Operands};
+use std::fmt;
+use std::str::FromStr;
+
+/// Address of the first instruction. Code is not stored in the simulated memory, so it is placed where flash memory usually starts.
+pub const TEXT_ADDRESS: u32 = 0x0800_0000;
+/// Size of the code of each built-in subroutine: "svc #n" followed by "bx lr"
+pub const BUILTIN_SIZE: u32 = 4;
+
+/// The machine code of an instruction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Narrow(u16),
+    Wide(u32),
+}
+impl Encoding {
+    /// Size in bytes.
+    pub fn size(&self) -> u32 {
+        match self {
+            Self::Narrow(_) => 2,
+            Self::Wide(_) => 4,
+        }
+    }
+    /// The halfwords in the order they are stored, the first halfword of a wide instruction holds the upper 16 bits.
+    pub fn halfwords(&self) -> Vec<u16> {
+        match *self {
+            Self::Narrow(halfword) => vec![halfword],
+            Self::Wide(word) => vec![(word >> 16) as u16, word as u16],
+        }
+    }
+    /// The bytes in memory order, each halfword is little-endian.
+    pub fn bytes(&self) -> Vec<u8> {
+        self.halfwords()
+            .into_iter()
+            .flat_map(u16::to_le_bytes)
+            .collect()
+    }
+}
+impl fmt::Display for Encoding {
+    /// Formats the halfwords in hexadecimal, ex: "2001" or "f04f 0101"
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let halfwords: Vec<String> = self
+            .halfwords()
+            .iter()
+            .map(|halfword| format!("{:04x}", halfword))
+            .collect();
+        write!(f, "{}", halfwords.join(" "))
+    }
+}
+
+/// Addresses the encoder needs to encode an instruction.
+pub struct Context<'a> {
+    /// Address of the instruction.
+    pub address: u32,
+    /// Address of a branch target.
+    pub target: &'a dyn Fn(Label) -> u32,
+    /// Address of the literal pool entry that holds a value, used by "ldr Rt, =value"
+    pub literal: &'a dyn Fn(u32) -> u32,
+}
+
+/// Encodes an instruction. If wide_only is true the narrow encoding is not considered,
+/// which keeps the size of an instruction from shrinking while the program layout settles.
+pub fn encode(
+    mnemonic: &str,
+    extension: &MnemonicExtension,
+    operands: &Operands,
+    context: &Context,
+    wide_only: bool,
+) -> Result<Encoding, String> {
+    // other conditional instructions outside an IT block are encoded without their condition,
+    // the lint pass warns about them.
+    if (mnemonic == "b" || mnemonic == "bl") && extension.it_status == ITStatus::IN {
+        return Err(
+            "A branch inside an IT block must be the last instruction of the block.".into(),
+        );
+    }
+    if extension.n {
+        return narrow(mnemonic, extension, operands, context)
+            .map(Encoding::Narrow)
+            .map_err(|reason| {
+                format!(
+                    "\"{}.n\" has no 16-bit encoding for these operands, {}.",
+                    mnemonic, reason
+                )
+            });
+    }
+    if !extension.w && !wide_only {
+        if let Ok(halfword) = narrow(mnemonic, extension, operands, context) {
+            return Ok(Encoding::Narrow(halfword));
+        }
+    }
+    wide(mnemonic, extension, operands, context).map(Encoding::Wide)
+}
+
+/// Returns true if an instruction only has a 32-bit encoding, no matter what its operands are.
+/// Used to warn about a .w extension that has no effect.
+pub fn is_always_wide(mnemonic: &str, line: &str) -> bool {
+    let is_memory = matches!(mnemonic, "ldr" | "ldrb" | "ldrh" | "str" | "strb" | "strh");
+    // pre-indexed and post-indexed addressing, ex: "[r0, #4]!" or "[r0], #4"
+    mnemonic == "bl" || (is_memory && (line.contains('!') || line.contains("],")))
+}
+
+/// Encodes an IT instruction, ex: "itte eq". Returns None if the instruction is invalid.
+pub fn it_instruction(line: &str) -> Option<u16> {
+    let mut words = line.split_whitespace();
+    let pattern = words.next()?.strip_prefix("it")?;
+    let first_cond = condition(ConditionCode::from_str(words.next()?).ok()?);
+    if pattern.len() > 3 {
+        return None;
+    }
+    let mut mask = 1 << (3 - pattern.len());
+    for (index, c) in pattern.chars().enumerate() {
+        let bit = if c == 't' {
+            first_cond & 1
+        } else {
+            !first_cond & 1
+        };
+        mask |= bit << (3 - index);
+    }
+    Some(0xBF00 | first_cond << 4 | mask)
+}
+
+/// Address of the literal pool, which is word aligned after the code of the built-in subroutines.
+pub fn literal_pool_address(builtins_address: u32) -> u32 {
+    let builtins_size = Label::predefined_names().len() as u32 * BUILTIN_SIZE;
+    (builtins_address + builtins_size + 3) & !3
+}
+
+/// Encodes "svc #number", used for the code of the built-in subroutines.
+pub fn svc(number: u8) -> u16 {
+    0xDF00 | u16::from(number)
+}
+/// Encodes "bx lr"
+pub const BX_LR: u16 = 0x4770;
+
+/// The condition codes in the order of their 4-bit numbers.
+const CONDITIONS: [ConditionCode; 15] = [
+    ConditionCode::EQ,
+    ConditionCode::NE,
+    ConditionCode::CS,
+    ConditionCode::CC,
+    ConditionCode::MI,
+    ConditionCode::PL,
+    ConditionCode::VS,
+    ConditionCode::VC,
+    ConditionCode::HI,
+    ConditionCode::LS,
+    ConditionCode::GE,
+    ConditionCode::LT,
+    ConditionCode::GT,
+    ConditionCode::LE,
+    ConditionCode::AL,
+];
+/// The condition code of a 4-bit number, 0b1111 is not a condition.
+pub fn condition_code(bits: u16) -> Option<ConditionCode> {
+    CONDITIONS.get(usize::from(bits)).copied()
+}
+
+/// The 4-bit number of a condition code.
+pub fn condition(cc: ConditionCode) -> u16 {
+    CONDITIONS
+        .iter()
+        .position(|&condition| condition == cc)
+        .expect("every condition code has a number") as u16
+}
+
+/// Error if any register is r8 or above.
+fn low_registers(registers: &[u8]) -> Result<(), String> {
+    if registers.iter().all(|&r| r < 8) {
+        Ok(())
+    } else {
+        Err("the registers must be r0-r7".into())
+    }
+}
+
+/// The 16-bit data-processing encodings set the flags outside an IT block, and do not set them inside one.
+fn narrow_flags(extension: &MnemonicExtension) -> Result<(), String> {
+    let in_it_block = extension.it_status != ITStatus::OUT;
+    match (extension.s, in_it_block) {
+        (true, false) | (false, true) => Ok(()),
+        (true, true) => Err("it cannot set the flags inside an IT block".into()),
+        (false, false) => {
+            Err("it always sets the flags outside an IT block, add the S flag".into())
+        }
+    }
+}
+
+/// Encodes a Thumb-2 modified immediate constant into the i:imm3:imm8 fields, or None if the value has no encoding.
+fn modified_immediate(value: u32) -> Option<u32> {
+    let byte = value & 0xFF;
+    let imm12 = if value <= 0xFF {
+        value
+    } else if value == byte * 0x0001_0001 {
+        0x100 | byte
+    } else if value == (value >> 8 & 0xFF) * 0x0100_0100 {
+        0x200 | (value >> 8 & 0xFF)
+    } else if value == byte * 0x0101_0101 {
+        0x300 | byte
+    } else {
+        // an 8-bit value with its top bit set, rotated right by 8 to 31 bits
+        let rotation =
+            (8..32).find(|&rotation| (0x80..=0xFF).contains(&value.rotate_left(rotation)))?;
+        rotation << 7 | (value.rotate_left(rotation) & 0x7F)
+    };
+    Some(imm12_fields(imm12))
+}
+
+/// Places a 12-bit immediate into the i:imm3:imm8 fields of a wide instruction.
+fn imm12_fields(imm12: u32) -> u32 {
+    (imm12 >> 11 & 1) << 26 | (imm12 >> 8 & 7) << 12 | (imm12 & 0xFF)
+}
+
+/// Places a 16-bit immediate into the imm4:i:imm3:imm8 fields of MOVW.
+fn imm16_fields(imm16: u32) -> u32 {
+    (imm16 >> 12 & 0xF) << 16 | imm12_fields(imm16 & 0xFFF)
+}
+
+fn s_bit(extension: &MnemonicExtension) -> u32 {
+    u32::from(extension.s) << 20
+}
+
+/// Tries the 16-bit encoding, returns the reason it cannot be used.
+fn narrow(
+    mnemonic: &str,
+    extension: &MnemonicExtension,
+    operands: &Operands,
+    context: &Context,
+) -> Result<u16, String> {
+    match (mnemonic, *operands) {
+        ("mov", Operands::Rd_immed { Rd, immed }) => {
+            narrow_flags(extension)?;
+            low_registers(&[Rd])?;
+            if immed > 0xFF {
+                return Err("the immediate must be 0-255".into());
+            }
+            Ok(0x2000 | u16::from(Rd) << 8 | immed as u16)
+        }
+        ("mov", Operands::Rd_Rm { Rd, Rm, .. }) => {
+            if !extension.s {
+                // MOV Rd, Rm can use every register, and never sets the flags
+                return Ok(0x4600
+                    | u16::from(Rd >> 3) << 7
+                    | u16::from(Rm) << 3
+                    | u16::from(Rd & 7));
+            }
+            narrow_flags(extension)?;
+            low_registers(&[Rd, Rm])?;
+            Ok(u16::from(Rm) << 3 | u16::from(Rd))
+        }
+        ("add", Operands::Rd_immed { Rd, immed }) => narrow_add_immediate(extension, Rd, Rd, immed),
+        ("add", Operands::Rd_Rn_immed { Rd, Rn, immed }) => {
+            narrow_add_immediate(extension, Rd, Rn, immed)
+        }
+        ("add", Operands::Rd_Rm { Rd, Rm, .. }) => narrow_add_register(extension, Rd, Rd, Rm),
+        ("add", Operands::Rd_Rn_Rm { Rd, Rn, Rm, .. }) => {
+            narrow_add_register(extension, Rd, Rn, Rm)
+        }
+        ("cmp", Operands::Rd_immed { Rd, immed }) => {
+            low_registers(&[Rd])?;
+            if immed > 0xFF {
+                return Err("the immediate must be 0-255".into());
+            }
+            Ok(0x2800 | u16::from(Rd) << 8 | immed as u16)
+        }
+        ("cmp", Operands::Rd_Rm { Rd, Rm, .. }) => {
+            if Rd < 8 && Rm < 8 {
+                Ok(0x4280 | u16::from(Rm) << 3 | u16::from(Rd))
+            } else {
+                Ok(0x4500 | u16::from(Rd >> 3) << 7 | u16::from(Rm) << 3 | u16::from(Rd & 7))
+            }
+        }
+        ("b", Operands::label { label }) => {
+            let offset = branch_offset(context, label);
+            match extension.cc {
+                Some(cc) if cc != ConditionCode::AL && extension.it_status == ITStatus::OUT => {
+                    if !(-256..=254).contains(&offset) {
+                        return Err("the label must be within 256 bytes".into());
+                    }
+                    Ok(0xD000 | condition(cc) << 8 | (offset >> 1) as u16 & 0xFF)
+                }
+                _ => {
+                    if !(-2048..=2046).contains(&offset) {
+                        return Err("the label must be within 2 KB".into());
+                    }
+                    Ok(0xE000 | (offset >> 1) as u16 & 0x7FF)
+                }
+            }
+        }
+        ("bl", _) => Err("bl is always 32 bits".into()),
+        (_, Operands::Rt_Rn_imm { Rt, Rn, imm }) => {
+            let (load, size) = memory_access(mnemonic);
+            let offset = imm.unwrap_or(0);
+            if Rn == 13 && size == 4 && Rt < 8 && offset % 4 == 0 && (0..=1020).contains(&offset) {
+                let base = if load { 0x9800 } else { 0x9000 };
+                return Ok(base | u16::from(Rt) << 8 | (offset / 4) as u16);
+            }
+            low_registers(&[Rt, Rn])?;
+            if offset < 0 || offset % size != 0 || offset / size > 31 {
+                return Err(format!(
+                    "the offset must be a multiple of {} between 0 and {}",
+                    size,
+                    31 * size
+                ));
+            }
+            let base: u16 = match (load, size) {
+                (false, 4) => 0x6000,
+                (true, 4) => 0x6800,
+                (false, 1) => 0x7000,
+                (true, 1) => 0x7800,
+                (false, _) => 0x8000,
+                (true, _) => 0x8800,
+            };
+            Ok(base | ((offset / size) as u16) << 6 | u16::from(Rn) << 3 | u16::from(Rt))
+        }
+        (_, Operands::Rt_Rn_Rm { Rt, Rn, Rm, shift }) => {
+            if shift.unwrap_or(0) != 0 {
+                return Err("a shifted register only has a 32-bit encoding".into());
+            }
+            low_registers(&[Rt, Rn, Rm])?;
+            let base: u16 = match memory_access(mnemonic) {
+                (false, 4) => 0x5000,
+                (false, 2) => 0x5200,
+                (false, _) => 0x5400,
+                (true, 4) => 0x5800,
+                (true, 2) => 0x5A00,
+                (true, _) => 0x5C00,
+            };
+            Ok(base | u16::from(Rm) << 6 | u16::from(Rn) << 3 | u16::from(Rt))
+        }
+        ("ldr", Operands::Rt_imm { Rt, .. }) => {
+            low_registers(&[Rt])?;
+            let offset = literal_offset(context, operands);
+            if !(0..=1020).contains(&offset) {
+                return Err("the literal pool must be within 1 KB after the instruction".into());
+            }
+            Ok(0x4800 | u16::from(Rt) << 8 | (offset / 4) as u16)
+        }
+        (_, Operands::Rt_Rn_imm_pre { .. }) | (_, Operands::Rt_Rn_imm_post { .. }) => {
+            Err("pre-indexed and post-indexed addressing only have a 32-bit encoding".into())
+        }
+        _ => Err("the operands only have a 32-bit encoding".into()),
+    }
+}
+
+fn narrow_add_immediate(
+    extension: &MnemonicExtension,
+    rd: u8,
+    rn: u8,
+    immed: u32,
+) -> Result<u16, String> {
+    let word_aligned = immed % 4 == 0;
+    if rd == 13 && rn == 13 && !extension.s && word_aligned && immed <= 508 {
+        return Ok(0xB000 | (immed / 4) as u16);
+    }
+    if rn == 13 && rd < 8 && !extension.s && word_aligned && immed <= 1020 {
+        return Ok(0xA800 | u16::from(rd) << 8 | (immed / 4) as u16);
+    }
+    narrow_flags(extension)?;
+    low_registers(&[rd, rn])?;
+    if rd == rn && immed <= 0xFF {
+        Ok(0x3000 | u16::from(rd) << 8 | immed as u16)
+    } else if immed <= 7 {
+        Ok(0x1C00 | (immed as u16) << 6 | u16::from(rn) << 3 | u16::from(rd))
+    } else {
+        Err("the immediate must be 0-7, or 0-255 when Rd and Rn are the same".into())
+    }
+}
+
+fn narrow_add_register(
+    extension: &MnemonicExtension,
+    rd: u8,
+    rn: u8,
+    rm: u8,
+) -> Result<u16, String> {
+    if !extension.s && rd == rn {
+        // ADD Rdn, Rm can use every register, and never sets the flags
+        return Ok(0x4400 | u16::from(rd >> 3) << 7 | u16::from(rm) << 3 | u16::from(rd & 7));
+    }
+    narrow_flags(extension)?;
+    low_registers(&[rd, rn, rm])?;
+    Ok(0x1800 | u16::from(rm) << 6 | u16::from(rn) << 3 | u16::from(rd))
+}
+
+/// Encodes the 32-bit instruction.
+fn wide(
+    mnemonic: &str,
+    extension: &MnemonicExtension,
+    operands: &Operands,
+    context: &Context,
+) -> Result<u32, String> {
+    let r = u32::from;
+    match (mnemonic, *operands) {
+        ("mov", Operands::Rd_immed { Rd, immed }) => {
+            if let Some(fields) = modified_immediate(immed) {
+                Ok(0xF04F_0000 | s_bit(extension) | r(Rd) << 8 | fields)
+            } else if !extension.s && immed <= 0xFFFF {
+                // MOVW
+                Ok(0xF240_0000 | r(Rd) << 8 | imm16_fields(immed))
+            } else {
+                Err(format!("#{} cannot be encoded with the S flag, only values that are an 8-bit pattern shifted or repeated can be.", immed))
+            }
+        }
+        ("mov", Operands::Rd_Rm { Rd, Rm, .. }) => {
+            Ok(0xEA4F_0000 | s_bit(extension) | r(Rd) << 8 | r(Rm))
+        }
+        ("add", Operands::Rd_immed { Rd, immed }) => wide_add_immediate(extension, Rd, Rd, immed),
+        ("add", Operands::Rd_Rn_immed { Rd, Rn, immed }) => {
+            wide_add_immediate(extension, Rd, Rn, immed)
+        }
+        ("add", Operands::Rd_Rm { Rd, Rm, .. }) => {
+            Ok(0xEB00_0000 | s_bit(extension) | r(Rd) << 16 | r(Rd) << 8 | r(Rm))
+        }
+        ("add", Operands::Rd_Rn_Rm { Rd, Rn, Rm, .. }) => {
+            Ok(0xEB00_0000 | s_bit(extension) | r(Rn) << 16 | r(Rd) << 8 | r(Rm))
+        }
+        ("cmp", Operands::Rd_immed { Rd, immed }) => match modified_immediate(immed) {
+            Some(fields) => Ok(0xF1B0_0F00 | r(Rd) << 16 | fields),
+            None => Err(format!("#{} cannot be encoded, only values that are an 8-bit pattern shifted or repeated can be compared.", immed)),
+        },
+        ("cmp", Operands::Rd_Rm { Rd, Rm, .. }) => Ok(0xEBB0_0F00 | r(Rd) << 16 | r(Rm)),
+        ("b", Operands::label { label }) | ("bl", Operands::label { label }) => {
+            let offset = branch_offset(context, label);
+            match extension.cc {
+                Some(cc)
+                    if mnemonic == "b"
+                        && cc != ConditionCode::AL
+                        && extension.it_status == ITStatus::OUT =>
+                {
+                    if !(-1_048_576..=1_048_574).contains(&offset) {
+                        return Err("The label is too far away, a conditional branch can only reach 1 MB.".into());
+                    }
+                    let offset = offset as u32;
+                    Ok(0xF000_8000
+                        | (offset >> 20 & 1) << 26
+                        | r16(condition(cc)) << 22
+                        | (offset >> 12 & 0x3F) << 16
+                        | (offset >> 18 & 1) << 13
+                        | (offset >> 19 & 1) << 11
+                        | (offset >> 1 & 0x7FF))
+                }
+                _ => {
+                    if !(-16_777_216..=16_777_214).contains(&offset) {
+                        return Err("The label is too far away, a branch can only reach 16 MB.".into());
+                    }
+                    let offset = offset as u32;
+                    let s = offset >> 24 & 1;
+                    let j1 = !(offset >> 23 ^ s) & 1;
+                    let j2 = !(offset >> 22 ^ s) & 1;
+                    let base = if mnemonic == "bl" { 0xF000_D000 } else { 0xF000_9000 };
+                    Ok(base
+                        | s << 26
+                        | (offset >> 12 & 0x3FF) << 16
+                        | j1 << 13
+                        | j2 << 11
+                        | (offset >> 1 & 0x7FF))
+                }
+            }
+        }
+        ("ldr", Operands::Rt_imm { Rt, .. }) => {
+            let offset = literal_offset(context, operands);
+            if !(-4095..=4095).contains(&offset) {
+                return Err("The literal pool is too far away, it must be within 4 KB of the instruction.".into());
+            }
+            Ok(0xF85F_0000 | u32::from(offset >= 0) << 23 | r(Rt) << 12 | offset.unsigned_abs() as u32)
+        }
+        (_, Operands::Rt_Rn_imm { Rt, Rn, imm }) => {
+            let offset = imm.unwrap_or(0);
+            let base = memory_base(mnemonic) | r(Rn) << 16 | r(Rt) << 12;
+            if (0..=4095).contains(&offset) {
+                Ok(base | 0x0080_0000 | offset as u32)
+            } else if (-255..0).contains(&offset) {
+                Ok(base | 0x0C00 | offset.unsigned_abs())
+            } else {
+                Err("The offset must be between -255 and 4095.".into())
+            }
+        }
+        (_, Operands::Rt_Rn_imm_pre { Rt, Rn, imm }) => indexed(mnemonic, Rt, Rn, imm, true),
+        (_, Operands::Rt_Rn_imm_post { Rt, Rn, imm }) => indexed(mnemonic, Rt, Rn, imm, false),
+        (_, Operands::Rt_Rn_Rm { Rt, Rn, Rm, shift }) => Ok(memory_base(mnemonic)
+            | r(Rn) << 16
+            | r(Rt) << 12
+            | u32::from(shift.unwrap_or(0)) << 4
+            | r(Rm)),
+        _ => Err(format!("\"{}\" cannot be encoded with these operands.", mnemonic)),
+    }
+}
+
+fn r16(value: u16) -> u32 {
+    u32::from(value)
+}
+
+fn wide_add_immediate(
+    extension: &MnemonicExtension,
+    rd: u8,
+    rn: u8,
+    immed: u32,
+) -> Result<u32, String> {
+    if let Some(fields) = modified_immediate(immed) {
+        Ok(0xF100_0000 | s_bit(extension) | u32::from(rn) << 16 | u32::from(rd) << 8 | fields)
+    } else if !extension.s && immed <= 0xFFF {
+        // ADDW
+        Ok(0xF200_0000 | u32::from(rn) << 16 | u32::from(rd) << 8 | imm12_fields(immed))
+    } else {
+        Err(format!("#{} cannot be encoded with the S flag, only values that are an 8-bit pattern shifted or repeated can be.", immed))
+    }
+}
+
+/// Encodes pre-indexed and post-indexed addressing, ex: "[r0, #4]!" and "[r0], #4"
+fn indexed(mnemonic: &str, rt: u8, rn: u8, imm: i32, pre: bool) -> Result<u32, String> {
+    if !(-255..=255).contains(&imm) {
+        return Err("The offset must be between -255 and 255.".into());
+    }
+    Ok(memory_base(mnemonic)
+        | u32::from(rn) << 16
+        | u32::from(rt) << 12
+        | 0x0900
+        | u32::from(pre) << 10
+        | u32::from(imm >= 0) << 9
+        | imm.unsigned_abs())
+}
+
+/// Returns if a memory instruction is a load, and the number of bytes it accesses.
+fn memory_access(mnemonic: &str) -> (bool, i32) {
+    let size = match mnemonic.trim_start_matches("ldr").trim_start_matches("str") {
+        "b" => 1,
+        "h" => 2,
+        _ => 4,
+    };
+    (mnemonic.starts_with("ldr"), size)
+}
+
+/// The first bits of the 32-bit load and store encodings, the size is in bits 22-21 and a load sets bit 20.
+fn memory_base(mnemonic: &str) -> u32 {
+    let (load, size) = memory_access(mnemonic);
+    let size_bits = match size {
+        1 => 0,
+        2 => 1,
+        _ => 2,
+    };
+    0xF800_0000 | size_bits << 21 | u32::from(load) << 20
+}
+
+/// Offset from the PC to a branch target, the PC is 4 bytes after the branch.
+fn branch_offset(context: &Context, label: Label) -> i64 {
+    i64::from((context.target)(label)) - i64::from(context.address) - 4
+}
+
+/// Offset from the word-aligned PC to the literal loaded by "ldr Rt, =value"
+fn literal_offset(context: &Context, operands: &Operands) -> i64 {
+    let value = match *operands {
+        Operands::Rt_imm { label, .. } => label,
+        _ => 0,
+    };
+    let pc = (context.address + 4) & !3;
+    i64::from((context.literal)(value)) - i64::from(pc)
+}
@@ -1,14 +1,16 @@
 use std::collections::VecDeque;
 
 /// Contains functions to help with error handling.
-use crate::arm7::{ConditionCode, ITStatus, MemSize, Operands};
+use crate::arm7::{ConditionCode, ITStatus, Operands};
+use crate::preprocess::{Frame, SourceLine};
 use std::str::FromStr;
 
 /// Stores and handles general compile time errors
 pub struct CompileErr {
     errors: Vec<String>,
-    line_number: usize,   // line number of current file
-    current_file: String, // index to file map of current file
+    line_number: usize,    // line number of current file
+    current_file: String,  // index to file map of current file
+    backtrace: Vec<Frame>, // macro expansions of the current line
 }
 impl CompileErr {
     pub fn new() -> Self {
@@ -16,6 +18,7 @@ impl CompileErr {
             errors: Vec::new(),
             line_number: 1,
             current_file: "main.s".into(),
+            backtrace: Vec::new(),
         }
     }
     /// Returns the operands if there are no compile errors, otherwise returns a list of compile errors.
@@ -37,84 +40,101 @@ impl CompileErr {
     pub fn update_line_number(&mut self, line_number: usize) {
         // line_number from enumerator, so starts at one.
         self.line_number = line_number;
+        self.backtrace.clear();
     }
     pub fn update_current_file(&mut self, file_name: String) {
         self.current_file = file_name;
+        self.backtrace.clear();
+    }
+    /// Updates the file, line number, and macro backtrace for error messages
+    pub fn update_location(&mut self, line: &SourceLine) {
+        self.current_file = line.location.file_name.clone();
+        self.line_number = line.location.line_number;
+        self.backtrace = line.backtrace.clone();
+    }
+    /// Formats an error message with the current location.
+    /// ex: "main.s" line 3: Invalid instruction. (macro "print" invoked from "main.s" line 10)
+    fn format(&self, message: &str) -> String {
+        let mut error = format!(
+            "\"{}\" line {}: {}",
+            self.current_file, self.line_number, message
+        );
+        if !self.backtrace.is_empty() {
+            let frames: Vec<String> = self.backtrace.iter().map(Frame::to_string).collect();
+            error += &format!(" ({})", frames.join(", "));
+        }
+        error
+    }
+    /// Number of errors found so far.
+    pub fn count(&self) -> usize {
+        self.errors.len()
     }
     /// Appends a compile time error message
     pub fn push_message(&mut self, message: &str) {
-        self.errors.push(format!(
-            "\"{}\" line {}: {}",
-            self.current_file, self.line_number, message
-        ));
+        self.errors.push(self.format(message));
     }
     /// Appends a list of compile time error messages.
     pub fn extend(&mut self, errors: Vec<String>) {
-        self.errors.extend(errors.into_iter().map(|err| {
-            format!(
-                "\"{}\" line {}: {}",
-                self.current_file, self.line_number, err
-            )
-        }))
+        for err in errors {
+            self.errors.push(self.format(&err));
+        }
     }
     pub fn message(error: String) -> Vec<String> {
         vec![error]
     }
     /// Handles an IT instruction
-    /// Because an IT instruction affects future instructions, if there is an error in the IT statement
-    /// we cannot verify the correctness of the instructions within an IT block, so we return the compile
-    /// errors immediately.
+    /// Because an IT instruction affects future instructions, an IT statement with errors still opens
+    /// a best-effort IT block so the instructions inside it are diagnosed, and the rest of the file is still compiled.
+    /// A condition of None means the base condition was invalid, so the instruction's condition code is not validated.
     pub fn handle_it_instruction(
-        mut self,
-        it_block: &mut VecDeque<ConditionCode>,
+        &mut self,
+        it_block: &mut VecDeque<Option<ConditionCode>>,
         line: String,
-    ) -> Result<Self, Vec<String>> {
-        // check if IT block is within another IT block
+    ) {
+        // check if IT block is within another IT block, skip the nested IT statement.
         if !it_block.is_empty() {
             self.push_message("IT statement cannot be inside another IT block.");
-            return Err(self.errors);
+            return;
         }
         // split line into IT<x<y<z>>> and condition code.
         let line = line.split_whitespace().collect::<Vec<&str>>();
 
         // get the default condition statement
-        let default_cc = if let Some(cc) = line.get(1) {
-            match ConditionCode::from_str(cc) {
-                Ok(cc) => cc,
-                Err(err) => {
-                    self.push_message(err.as_str());
-                    return Err(self.errors);
-                }
+        let default_cc = match line.get(1).map(|cc| ConditionCode::from_str(cc)) {
+            Some(Ok(cc)) => Some(cc),
+            Some(Err(err)) => {
+                self.push_message(err.as_str());
+                None
+            }
+            None => {
+                self.push_message("IT statement must have a base condition.");
+                None
             }
-        } else {
-            self.push_message("IT statement must have a base condition.");
-            return Err(self.errors);
         };
         // get the list of if else conditions
         for (index, c) in line[0][1..].chars().enumerate() {
-            if index > 4 {
+            if index >= 4 {
                 self.push_message("An IT statement can only have conditions for 4 instructions.");
-                return Err(self.errors);
+                break;
             }
             if c == 't' {
                 it_block.push_back(default_cc);
             } else {
                 // c == 'e'
-                it_block.push_back(default_cc.opposite_condition());
+                it_block.push_back(default_cc.map(|cc| cc.opposite_condition()));
             }
         }
-        Ok(self)
     }
     pub fn get_it_status(
         &mut self,
-        it_block: &mut VecDeque<ConditionCode>,
+        it_block: &mut VecDeque<Option<ConditionCode>>,
         cc: Option<ConditionCode>,
     ) -> ITStatus {
         // if in IT block, validate condition code
         if let Some(correct_cc) = it_block.pop_front() {
             // check condition code for errors
             if let Some(cc) = cc {
-                if cc != correct_cc {
+                if correct_cc.map_or(false, |correct_cc| cc != correct_cc) {
                     self.push_message("The condition code must be the same or opposite of the IT block's condition code.");
                 }
             } else {
@@ -205,30 +225,14 @@ impl InstructionCompileErr {
 pub fn invalid_args(line: &str) -> Vec<String> {
     vec![format!("\"{}\" contains invalid arguments", line)]
 }
+/// Appends a "did you mean" suggestion to a message, if there is one.
+pub fn with_suggestion(message: String, suggestion: Option<&str>) -> String {
+    match suggestion {
+        Some(suggestion) => format!("{} Did you mean \"{}\"?", message, suggestion),
+        None => message,
+    }
+}
 /// Run Time Error Message for incorrect operand types, ideally it will never be called.
 pub fn invalid_operands() -> String {
     "Wrong arguments given.".into()
 }
-
-/// Check if address being accessed is out of bounds.
-pub fn check_memory_bounds(
-    address: u32,
-    memory_size: usize,
-    bytes: MemSize,
-) -> Result<usize, String> {
-    let offset = bytes.bytes() as u32 - 1;
-    if address as usize >= memory_size {
-        Err(format!(
-            "Address \"{:#010x}\" is out of bounds, address can only be in range (0, {})",
-            address,
-            memory_size - 1
-        ))
-    } else if (address + offset) as usize >= memory_size {
-        Err(format!(
-            "Address accesses memory that is out of bounds, address can only be in range (0, {})",
-            memory_size - 1
-        ))
-    } else {
-        Ok(address as usize)
-    }
-}
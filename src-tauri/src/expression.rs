@@ -0,0 +1,162 @@
+// Evaluates constant expressions, used by conditional assembly.
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Symbol(String),
+    Operator(&'static str),
+}
+
+/// Operators sorted so that longer operators are matched first.
+const OPERATORS: [&str; 22] = [
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+", "-", "*", "/", "%", "&", "|", "^", "~",
+    "!", "<", ">", "(", ")",
+];
+
+fn tokenize(expr: &str) -> Result<Vec<Token>, String> {
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut rest = expr.trim_start();
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+        if c.is_ascii_digit() {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            tokens.push(Token::Number(parse_number(&rest[..end])?));
+            rest = &rest[end..];
+        } else if c.is_ascii_alphabetic() || c == '_' || c == '.' || c == '$' {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Symbol(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            tokens.push(Token::Operator(op));
+            rest = &rest[op.len()..];
+        } else {
+            return Err(format!("Unexpected character '{}' in expression.", c));
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+/// Parses a decimal, hexadecimal (0x), or binary (0b) number.
+pub fn parse_number(num: &str) -> Result<i64, String> {
+    let lowercase = num.to_lowercase();
+    let result = if let Some(hex) = lowercase.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16)
+    } else if let Some(bin) = lowercase.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2)
+    } else {
+        lowercase.parse::<i64>()
+    };
+    result.map_err(|_| format!("\"{}\" is not a valid number.", num))
+}
+
+/// Returns the precedence of a binary operator, higher binds tighter.
+fn precedence(op: &str) -> Option<u8> {
+    match op {
+        "||" => Some(1),
+        "&&" => Some(2),
+        "|" => Some(3),
+        "^" => Some(4),
+        "&" => Some(5),
+        "==" | "!=" => Some(6),
+        "<" | "<=" | ">" | ">=" => Some(7),
+        "<<" | ">>" => Some(8),
+        "+" | "-" => Some(9),
+        "*" | "/" | "%" => Some(10),
+        _ => None,
+    }
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    symbol: &'a dyn Fn(&str) -> Option<i64>,
+}
+impl<'a> Parser<'a> {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+    fn parse_binary(&mut self, min_precedence: u8) -> Result<i64, String> {
+        let mut lhs = self.parse_unary()?;
+        while let Some(Token::Operator(op)) = self.tokens.get(self.position).cloned() {
+            let prec = match precedence(op) {
+                Some(prec) if prec >= min_precedence => prec,
+                _ => break,
+            };
+            self.position += 1;
+            let rhs = self.parse_binary(prec + 1)?;
+            lhs = apply(op, lhs, rhs)?;
+        }
+        Ok(lhs)
+    }
+    fn parse_unary(&mut self) -> Result<i64, String> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(n),
+            Some(Token::Symbol(name)) => {
+                (self.symbol)(&name).ok_or_else(|| format!("Symbol \"{}\" is not defined.", name))
+            }
+            Some(Token::Operator("-")) => Ok(self.parse_unary()?.wrapping_neg()),
+            Some(Token::Operator("+")) => self.parse_unary(),
+            Some(Token::Operator("~")) => Ok(!self.parse_unary()?),
+            Some(Token::Operator("!")) => Ok(i64::from(self.parse_unary()? == 0)),
+            Some(Token::Operator("(")) => {
+                let value = self.parse_binary(1)?;
+                match self.next() {
+                    Some(Token::Operator(")")) => Ok(value),
+                    _ => Err("Missing closing parenthesis in expression.".into()),
+                }
+            }
+            Some(Token::Operator(op)) => Err(format!("Unexpected \"{}\" in expression.", op)),
+            None => Err("Expression is incomplete.".into()),
+        }
+    }
+}
+
+fn apply(op: &str, a: i64, b: i64) -> Result<i64, String> {
+    Ok(match op {
+        "||" => i64::from(a != 0 || b != 0),
+        "&&" => i64::from(a != 0 && b != 0),
+        "|" => a | b,
+        "^" => a ^ b,
+        "&" => a & b,
+        "==" => i64::from(a == b),
+        "!=" => i64::from(a != b),
+        "<" => i64::from(a < b),
+        "<=" => i64::from(a <= b),
+        ">" => i64::from(a > b),
+        ">=" => i64::from(a >= b),
+        "<<" => a.wrapping_shl(b as u32),
+        ">>" => a.wrapping_shr(b as u32),
+        "+" => a.wrapping_add(b),
+        "-" => a.wrapping_sub(b),
+        "*" => a.wrapping_mul(b),
+        "/" | "%" if b == 0 => return Err("Division by zero in expression.".into()),
+        "/" => a.wrapping_div(b),
+        "%" => a.wrapping_rem(b),
+        _ => return Err(format!("Unknown operator \"{}\".", op)),
+    })
+}
+
+/// Evaluates a constant expression, ex: "(DEBUG && LEVEL > 2) | 0x10"
+/// Supports decimal, hexadecimal and binary numbers, symbols, parentheses, and the C operators:
+/// unary - + ! ~, then * / %, + -, << >>, < <= > >=, == !=, &, ^, |, &&, ||
+/// Comparisons and logical operators result in 1 if true, and 0 if false.
+/// The symbol function returns the value of a symbol, or None if it is not defined.
+pub fn evaluate(expr: &str, symbol: &dyn Fn(&str) -> Option<i64>) -> Result<i64, String> {
+    let mut parser = Parser {
+        tokens: tokenize(expr)?,
+        position: 0,
+        symbol,
+    };
+    let value = parser.parse_binary(1)?;
+    if parser.position < parser.tokens.len() {
+        return Err(format!("\"{}\" is not a valid expression.", expr.trim()));
+    }
+    Ok(value)
+}
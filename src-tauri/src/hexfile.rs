@@ -0,0 +1,337 @@
+/// Contains the readers and writers of memory image files: Intel HEX, Motorola S-record, and raw binary.
+/// Used to save the contents of memory, and to fill memory before the program starts.
+use crate::memory::MemoryMap;
+use std::ops::Range;
+use std::path::Path;
+
+/// Data bytes in each record of a text format.
+const RECORD_SIZE: usize = 16;
+
+/// The format of a memory image file.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Intel HEX, ex: ":0400000001000000FB"
+    Ihex,
+    /// Motorola S-record, ex: "S107000001000000F7"
+    Srec,
+    /// Raw bytes, the address of the first byte is not stored in the file.
+    Bin,
+}
+impl Format {
+    /// Picks the format from the file extension, ex: "table.hex"
+    pub fn from_path(path: &str) -> Option<Self> {
+        let extension = Path::new(path).extension()?.to_str()?.to_lowercase();
+        match extension.as_str() {
+            "hex" | "ihex" | "ihx" => Some(Self::Ihex),
+            "srec" | "s19" | "s28" | "s37" | "mot" => Some(Self::Srec),
+            "bin" => Some(Self::Bin),
+            _ => None,
+        }
+    }
+}
+
+/// A memory image file that fills memory before the program starts, read from config.json.
+/// ex: { "file": "table.hex", "start": 256, "end": 512 }
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct Preload {
+    /// Path relative to the project directory.
+    pub file: String,
+    /// Picked from the file extension if it is not set.
+    #[serde(default)]
+    pub format: Option<Format>,
+    /// Where a raw binary file is placed in memory.
+    #[serde(default)]
+    pub address: u32,
+    /// Only the bytes from start up to end are loaded, the whole file by default.
+    #[serde(default)]
+    pub start: Option<u32>,
+    #[serde(default)]
+    pub end: Option<u32>,
+}
+impl Preload {
+    /// Reads the file, and returns the blocks of bytes to write to memory as (address, bytes).
+    /// Read-only regions can be preloaded, ex: a table in flash.
+    pub fn read(
+        &self,
+        dir_path: &str,
+        memory_map: &MemoryMap,
+    ) -> Result<Vec<(u32, Vec<u8>)>, String> {
+        let format = format_of(&self.file, self.format)?;
+        let range = match (self.start, self.end) {
+            (None, None) => None,
+            (start, end) => Some(memory_range(start, end, memory_map)?),
+        };
+        let content = std::fs::read(format!("{}{}", dir_path, self.file))
+            .map_err(|err| format!("Could not read \"{}\": {}", self.file, err))?;
+        let blocks = read(format, self.address, &content)
+            .map_err(|err| format!("\"{}\" {}", self.file, err))?;
+        let blocks = match range {
+            Some(range) => clip(blocks, range),
+            None => blocks,
+        };
+        if let Some((address, _)) = blocks
+            .iter()
+            .find(|(address, bytes)| !memory_map.is_mapped(*address, bytes.len()))
+        {
+            return Err(format!(
+                "\"{}\" has data at {:#010x}, which is outside of the memory regions. Set \"start\" and \"end\" to load part of it.",
+                self.file, address
+            ));
+        }
+        Ok(blocks)
+    }
+}
+
+/// Returns the format of a file, picked from its extension if it is not given.
+pub fn format_of(path: &str, format: Option<Format>) -> Result<Format, String> {
+    format.or_else(|| Format::from_path(path)).ok_or_else(|| {
+        format!(
+            "Unknown memory image format of \"{}\", use a .hex, .srec, or .bin file.",
+            path
+        )
+    })
+}
+
+/// Checks an address range of memory, which must be inside a single region.
+/// start and end default to the start and end of the region, which is the data region if start is not set.
+pub fn memory_range(
+    start: Option<u32>,
+    end: Option<u32>,
+    memory_map: &MemoryMap,
+) -> Result<Range<u32>, String> {
+    let region = match start {
+        Some(start) => memory_map.region_of(start, 1),
+        None => memory_map.data_region(),
+    };
+    let error = || {
+        format!(
+            "The address range {:#x}..{:#x} is not inside a memory region.",
+            start.unwrap_or(0),
+            end.unwrap_or(0)
+        )
+    };
+    let region = region.ok_or_else(error)?;
+    let range = start.unwrap_or(region.origin)
+        ..end.unwrap_or(region.range().end.min(u32::MAX as u64) as u32);
+    if range.start >= range.end || !region.contains(range.start, (range.end - range.start) as usize)
+    {
+        return Err(format!(
+            "The address range {:#x}..{:#x} is not inside the memory region \"{}\" ({:#x}..{:#x}).",
+            range.start,
+            range.end,
+            region.name,
+            region.range().start,
+            region.range().end
+        ));
+    }
+    Ok(range)
+}
+
+/// Writes bytes that start at an address to a memory image file.
+pub fn write(format: Format, address: u32, bytes: &[u8]) -> Vec<u8> {
+    match format {
+        Format::Ihex => write_ihex(address, bytes).into_bytes(),
+        Format::Srec => write_srec(address, bytes).into_bytes(),
+        Format::Bin => bytes.to_vec(),
+    }
+}
+
+/// Reads a memory image file, and returns its blocks of bytes as (address, bytes).
+/// address is where a raw binary file is placed, the text formats store their own addresses.
+pub fn read(format: Format, address: u32, content: &[u8]) -> Result<Vec<(u32, Vec<u8>)>, String> {
+    match format {
+        Format::Bin => Ok(vec![(address, content.to_vec())]),
+        Format::Ihex | Format::Srec => {
+            let text = std::str::from_utf8(content)
+                .map_err(|_| "The file is not a text file.".to_string())?;
+            let mut blocks = Vec::new();
+            // the upper address bits set by Intel HEX extended address records
+            let mut base = 0;
+            for (index, line) in text.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+                let block = match format {
+                    Format::Ihex => read_ihex_record(line, &mut base),
+                    _ => read_srec_record(line),
+                };
+                match block {
+                    Ok(Some(block)) => blocks.push(block),
+                    Ok(None) => (),
+                    Err(err) => return Err(format!("Line {}: {}", index + 1, err)),
+                }
+            }
+            Ok(blocks)
+        }
+    }
+}
+
+/// Keeps the bytes of the blocks that are inside an address range.
+pub fn clip(blocks: Vec<(u32, Vec<u8>)>, range: Range<u32>) -> Vec<(u32, Vec<u8>)> {
+    blocks
+        .into_iter()
+        .filter_map(|(address, bytes)| {
+            let end = address as u64 + bytes.len() as u64;
+            let start = address.max(range.start);
+            let end = end.min(range.end as u64) as u32;
+            if start >= end {
+                return None;
+            }
+            let offset = (start - address) as usize;
+            Some((
+                start,
+                bytes[offset..offset + (end - start) as usize].to_vec(),
+            ))
+        })
+        .collect()
+}
+
+fn write_ihex(address: u32, bytes: &[u8]) -> String {
+    let mut output = String::new();
+    let mut upper = 0;
+    let mut address = address as u64;
+    let mut bytes = bytes;
+    while !bytes.is_empty() {
+        // a record cannot cross a 64 KB boundary, its address is only the lower 16 bits
+        let size = bytes
+            .len()
+            .min(RECORD_SIZE)
+            .min(0x1_0000 - (address & 0xFFFF) as usize);
+        let (chunk, rest) = bytes.split_at(size);
+        // extended linear address record, sets the upper 16 bits of the addresses
+        if address >> 16 != upper {
+            upper = address >> 16;
+            output += &ihex_record(4, 0, &(upper as u16).to_be_bytes());
+        }
+        output += &ihex_record(0, address as u16, chunk);
+        address += size as u64;
+        bytes = rest;
+    }
+    output += &ihex_record(1, 0, &[]);
+    output
+}
+
+fn ihex_record(kind: u8, address: u16, data: &[u8]) -> String {
+    let mut record = vec![data.len() as u8];
+    record.extend(address.to_be_bytes());
+    record.push(kind);
+    record.extend(data);
+    let sum = record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    record.push(sum.wrapping_neg());
+    format!(":{}\n", hex(&record))
+}
+
+/// Reads an Intel HEX record. Returns the data bytes and their address, if the record is a data record.
+/// The extended address records set the base address of the next data records.
+fn read_ihex_record(line: &str, base: &mut u32) -> Result<Option<(u32, Vec<u8>)>, String> {
+    let record = line
+        .strip_prefix(':')
+        .ok_or_else(|| "An Intel HEX record must start with ':'".to_string())
+        .and_then(parse_hex)?;
+    if record.len() < 5 || record.len() != record[0] as usize + 5 {
+        return Err("The record length is wrong.".into());
+    }
+    if record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+        return Err("The checksum is wrong.".into());
+    }
+    let offset = u16::from_be_bytes([record[1], record[2]]) as u32;
+    let data = &record[4..record.len() - 1];
+    match record[3] {
+        0 => return Ok(Some((base.wrapping_add(offset), data.to_vec()))),
+        // extended segment address, in units of 16 bytes
+        2 if data.len() == 2 => *base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4,
+        // extended linear address, the upper 16 bits
+        4 if data.len() == 2 => *base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16,
+        // end of file and start address records
+        1 | 3 | 5 => (),
+        kind => return Err(format!("Unknown record type {:02X}.", kind)),
+    }
+    Ok(None)
+}
+
+fn write_srec(address: u32, bytes: &[u8]) -> String {
+    let end = address as u64 + bytes.len() as u64;
+    // S1 records have 16-bit addresses, S3 records have 32-bit addresses
+    let (data_kind, end_kind, address_size) = if end <= 0x1_0000 {
+        (1, 9, 2)
+    } else {
+        (3, 7, 4)
+    };
+    let mut output = srec_record(0, 0, 2, b"memory");
+    let mut count = 0u32;
+    for (index, chunk) in bytes.chunks(RECORD_SIZE).enumerate() {
+        let address = address + (index * RECORD_SIZE) as u32;
+        output += &srec_record(data_kind, address, address_size, chunk);
+        count += 1;
+    }
+    if count <= 0xFFFF {
+        output += &srec_record(5, count, 2, &[]);
+    }
+    output += &srec_record(end_kind, 0, address_size, &[]);
+    output
+}
+
+fn srec_record(kind: u8, address: u32, address_size: usize, data: &[u8]) -> String {
+    let mut record = vec![(address_size + data.len() + 1) as u8];
+    record.extend(&address.to_be_bytes()[4 - address_size..]);
+    record.extend(data);
+    let sum = record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    record.push(!sum);
+    format!("S{}{}\n", kind, hex(&record))
+}
+
+/// Reads a Motorola S-record. Returns the data bytes and their address, if the record is a data record.
+fn read_srec_record(line: &str) -> Result<Option<(u32, Vec<u8>)>, String> {
+    let mut chars = line.chars();
+    if chars.next().map(|c| c.to_ascii_uppercase()) != Some('S') {
+        return Err("An S-record must start with 'S'".into());
+    }
+    let kind = chars
+        .next()
+        .and_then(|c| c.to_digit(10))
+        .ok_or_else(|| "The record type is missing.".to_string())?;
+    let record = parse_hex(chars.as_str())?;
+    if record.is_empty() || record.len() != record[0] as usize + 1 {
+        return Err("The record length is wrong.".into());
+    }
+    if record.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0xFF {
+        return Err("The checksum is wrong.".into());
+    }
+    let address_size = match kind {
+        1 => 2,
+        2 => 3,
+        3 => 4,
+        // header, count, and start address records
+        0 | 5..=9 => return Ok(None),
+        _ => return Err(format!("Unknown record type S{}.", kind)),
+    };
+    if record.len() < address_size + 2 {
+        return Err("The record length is wrong.".into());
+    }
+    let address = record[1..=address_size]
+        .iter()
+        .fold(0u32, |address, byte| address << 8 | *byte as u32);
+    Ok(Some((
+        address,
+        record[address_size + 1..record.len() - 1].to_vec(),
+    )))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+fn parse_hex(text: &str) -> Result<Vec<u8>, String> {
+    if text.len() % 2 != 0 || !text.is_ascii() {
+        return Err("A record must have an even number of hex digits.".into());
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|index| {
+            u8::from_str_radix(&text[index..index + 2], 16)
+                .map_err(|_| format!("\"{}\" is not a hex number.", &text[index..index + 2]))
+        })
+        .collect()
+}
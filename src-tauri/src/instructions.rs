@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 use std::str::FromStr;
 
-use crate::arm7::{Label, MemSize, MnemonicExtension, Operands, Processor};
+use crate::arm7::{ConditionCode, Label, MemSize, MnemonicExtension, Operands, Processor};
 use crate::error::InstructionCompileErr;
 use crate::error::{self};
 use crate::utils as hp;
@@ -45,6 +45,10 @@ pub trait Instruction: Send + Sync {
         operands: &Operands,
         chip: &mut Processor,
     ) -> Result<(), String>;
+    /// Estimated number of cycles on a Cortex-M4, shown in listing files. ex: "2-4" for a branch.
+    fn cycles(&self, _extension: &MnemonicExtension) -> &'static str {
+        "1"
+    }
 }
 
 // Implement Instructions
@@ -248,6 +252,13 @@ impl Instruction for B {
         }
         Ok(())
     }
+    /// A conditional branch that is not taken takes a single cycle.
+    fn cycles(&self, extension: &MnemonicExtension) -> &'static str {
+        match extension.cc {
+            Some(ConditionCode::AL) | None => "2-4",
+            Some(_) => "1-4",
+        }
+    }
 }
 
 pub struct BL;
@@ -285,6 +296,9 @@ impl Instruction for BL {
         }
         Ok(())
     }
+    fn cycles(&self, _extension: &MnemonicExtension) -> &'static str {
+        "2-4"
+    }
 }
 
 pub struct STRB;
@@ -308,6 +322,9 @@ impl Instruction for STRB {
     ) -> Result<(), String> {
         hp::store_bytes(operands, chip, MemSize::BYTE)
     }
+    fn cycles(&self, _extension: &MnemonicExtension) -> &'static str {
+        "2"
+    }
 }
 
 pub struct STRH;
@@ -331,6 +348,9 @@ impl Instruction for STRH {
     ) -> Result<(), String> {
         hp::store_bytes(operands, chip, MemSize::HALFWORD)
     }
+    fn cycles(&self, _extension: &MnemonicExtension) -> &'static str {
+        "2"
+    }
 }
 
 pub struct STR;
@@ -354,6 +374,9 @@ impl Instruction for STR {
     ) -> Result<(), String> {
         hp::store_bytes(operands, chip, MemSize::WORD)
     }
+    fn cycles(&self, _extension: &MnemonicExtension) -> &'static str {
+        "2"
+    }
 }
 
 pub struct LDRB;
@@ -377,6 +400,9 @@ impl Instruction for LDRB {
     ) -> Result<(), String> {
         hp::load_bytes(operands, chip, MemSize::BYTE)
     }
+    fn cycles(&self, _extension: &MnemonicExtension) -> &'static str {
+        "2"
+    }
 }
 pub struct LDRH;
 
@@ -399,6 +425,9 @@ impl Instruction for LDRH {
     ) -> Result<(), String> {
         hp::load_bytes(operands, chip, MemSize::HALFWORD)
     }
+    fn cycles(&self, _extension: &MnemonicExtension) -> &'static str {
+        "2"
+    }
 }
 
 pub struct LDR;
@@ -432,4 +461,7 @@ impl Instruction for LDR {
             _ => hp::load_bytes(operands, chip, MemSize::WORD),
         }
     }
+    fn cycles(&self, _extension: &MnemonicExtension) -> &'static str {
+        "2"
+    }
 }
@@ -1,18 +1,90 @@
 pub mod arm7;
+pub mod armasm;
 pub mod backend_api;
+pub mod cache;
+pub mod cpp;
+pub mod decode;
+pub mod directive;
+pub mod dwarf;
+pub mod elf;
+pub mod encode;
 pub mod error;
+pub mod expression;
 pub mod fc;
+pub mod hexfile;
 pub mod instructions;
+pub mod link;
+pub mod lint;
+pub mod listing;
+pub mod mapfile;
+pub mod memory;
+pub mod preprocess;
 pub mod utils;
 
 #[cfg(test)]
 mod tests {
     use super::arm7::*;
+    use super::memory::MemoryMap;
     use super::utils as hp;
     use regex::Regex;
+    use std::collections::HashMap;
+    use std::str::FromStr;
 
     const MOV: MOV = MOV {};
 
+    /// A path in the temp directory that is unique to this test run.
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("{}_{}", std::process::id(), name))
+    }
+
+    /// Compiles a single file the same way as the compile command.
+    fn compile_program(content: &str) -> Result<Program, Vec<String>> {
+        compile_file(content).map(|(program, ..)| program)
+    }
+
+    /// Compiles a single file with the compile command's build, and returns the program with the results of the link step.
+    fn compile_file(
+        content: &str,
+    ) -> Result<
+        (
+            Program,
+            Vec<super::preprocess::SourceFile>,
+            super::link::LinkedData,
+            Labels,
+        ),
+        Vec<String>,
+    > {
+        compile_project(&[("main.s", content)], None)
+            .map(|build| (build.program, build.sources, build.linked, build.labels))
+    }
+
+    /// Writes the files of a project, and an optional config.json, to a new directory and builds it.
+    fn compile_project(
+        files: &[(&str, &str)],
+        config: Option<&str>,
+    ) -> Result<super::backend_api::Build, Vec<String>> {
+        use super::backend_api::{build, compile::Config};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // every build gets its own directory, since tests run in parallel
+        static BUILDS: AtomicUsize = AtomicUsize::new(0);
+        let dir = temp_path(&format!(
+            "arm_build_{}",
+            BUILDS.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (name, content) in files {
+            std::fs::write(dir.join(name), content).unwrap();
+        }
+        if let Some(config) = config {
+            std::fs::write(dir.join("config.json"), config).unwrap();
+        }
+        let dir_path = format!("{}/", dir.to_str().unwrap());
+        let result = Config::new(&dir_path).and_then(|config| build(&config, &|_| false));
+        std::fs::remove_dir_all(dir).unwrap();
+        result
+    }
+
     #[test]
     fn test_mov1() {
         let en = MOV.get_operands(&MnemonicExtension::new(), "mov r14, #0x20");
@@ -161,6 +233,7 @@ mod tests {
                     cc: None,
                     s: true,
                     w: false,
+                    n: false,
                     it_status: ITStatus::OUT
                 }
             ))
@@ -173,6 +246,7 @@ mod tests {
                     cc: Some(ConditionCode::VS),
                     s: false,
                     w: false,
+                    n: false,
                     it_status: ITStatus::OUT
                 }
             ))
@@ -185,6 +259,7 @@ mod tests {
                     cc: None,
                     s: false,
                     w: false,
+                    n: false,
                     it_status: ITStatus::OUT
                 }
             ))
@@ -205,6 +280,7 @@ mod tests {
                     cc: Some(ConditionCode::VS),
                     s: true,
                     w: false,
+                    n: false,
                     it_status: ITStatus::OUT
                 }
             ))
@@ -217,6 +293,7 @@ mod tests {
                     cc: Some(ConditionCode::VS),
                     s: true,
                     w: true,
+                    n: false,
                     it_status: ITStatus::OUT
                 }
             ))
@@ -229,6 +306,7 @@ mod tests {
                     cc: None,
                     s: false,
                     w: true,
+                    n: false,
                     it_status: ITStatus::OUT
                 }
             ))
@@ -241,6 +319,7 @@ mod tests {
                     cc: None,
                     s: true,
                     w: false,
+                    n: false,
                     it_status: ITStatus::OUT
                 }
             ))
@@ -256,4 +335,1462 @@ mod tests {
             .collect();
         assert_eq!(globals.len(), 3);
     }
+    #[test]
+    fn lint_warnings() {
+        use super::lint::{Lint, Linter};
+        use super::preprocess::{preprocess_file, PreprocessOptions};
+        let program = Program::new();
+        let content =
+            "mov r0, #1\nmes:\n.string \"hi\"\nb end\nadd r0, #1\nend:\nbl.w end\nunused:\n";
+
+        let lines = preprocess_file("main.s", content, &PreprocessOptions::default()).unwrap();
+
+        let mut linter = Linter::new(&[]);
+        linter.lint_file(&lines, &program);
+        let warnings = linter.finish();
+        assert_eq!(warnings.len(), 5);
+        assert!(warnings[0].starts_with("\"main.s\" line 3:"));
+        assert!(warnings[0].ends_with("[fall-through-data]"));
+        assert!(warnings[1].ends_with("[unreachable-code]"));
+        assert!(warnings[2].ends_with("[meaningless-wide]"));
+        assert!(warnings[3].contains("\"mes\""));
+        assert!(warnings[4].contains("\"unused\""));
+
+        let mut linter = Linter::new(&[Lint::UnusedLabel, Lint::MeaninglessWide]);
+        linter.lint_file(&lines, &program);
+        assert_eq!(linter.finish().len(), 2);
+
+        // labels used by any operand, and the entry label, are used
+        let content =
+            "main:\ncbz r0, skip\nadr r1, table\nskip:\n.data\ntable:\n.word other\nother:\n";
+        let lines = preprocess_file("main.s", content, &PreprocessOptions::default()).unwrap();
+        let mut linter = Linter::new(&[]);
+        linter.lint_file(&lines, &program);
+        assert_eq!(linter.finish(), Vec::<String>::new());
+
+        // conditional instructions outside an IT block still compile, with a warning
+        let content = "main:\ncmp r0, #1\naddeq r0, #1\nit eq\nmoveq r1, #2\nbne main\n";
+        assert!(compile_program(content).is_ok());
+        let lines = preprocess_file("main.s", content, &PreprocessOptions::default()).unwrap();
+        let mut linter = Linter::new(&[]);
+        linter.lint_file(&lines, &program);
+        let warnings = linter.finish();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("\"main.s\" line 3: \"addeq\""));
+        assert!(warnings[0].ends_with("[conditional-outside-it]"));
+    }
+    #[test]
+    fn it_block_recovery() {
+        use super::error::CompileErr;
+        use std::collections::VecDeque;
+
+        let mut errors = CompileErr::new();
+        let mut it_block = VecDeque::new();
+        // invalid base condition still opens a block of the right size
+        errors.handle_it_instruction(&mut it_block, "ite xx".into());
+        assert_eq!(it_block, VecDeque::from([None, None]));
+        assert_eq!(
+            errors.get_it_status(&mut it_block, Some(ConditionCode::EQ)),
+            ITStatus::IN
+        );
+        // nested IT statement is skipped
+        errors.handle_it_instruction(&mut it_block, "it eq".into());
+        assert_eq!(it_block.len(), 1);
+        assert_eq!(errors.get_it_status(&mut it_block, None), ITStatus::LAST);
+        // too many conditions are truncated
+        errors.handle_it_instruction(&mut it_block, "ittttt eq".into());
+        assert_eq!(it_block.len(), 4);
+        assert_eq!(errors.result().unwrap_err().len(), 4);
+    }
+    #[test]
+    fn suggestions() {
+        assert_eq!(hp::edit_distance("mvo", "mov"), 1);
+        assert_eq!(hp::edit_distance("Lopp", "Loop"), 1);
+        assert_eq!(hp::closest_match("Lopp", ["exit", "Loop"]), Some("Loop"));
+        assert_eq!(hp::closest_match("xyz", ["exit", "Loop"]), None);
+
+        let program = Program::new();
+        assert_eq!(program.suggest_mnemonic("mvo r0, r1"), Some("mov"));
+        assert_eq!(program.suggest_mnemonic("cmpp r0, r1"), Some("cmp"));
+        assert_eq!(program.suggest_mnemonic("foo r0, r1"), None);
+        // unknown mnemonics fail the build
+        assert_eq!(
+            compile_program("mvo r0, r1\n").err().unwrap(),
+            vec!["\"main.s\" line 1: Invalid instruction. Did you mean \"mov\"?"]
+        );
+
+        let err = Operands::from_str("mov r0, rl").unwrap_err();
+        assert_eq!(err[1], "\"rl\" is not a register. Did you mean \"lr\"?");
+
+        // a misspelled label suggests the closest label
+        let content = "Loop:\nadd r0, #1\nb Loop\n";
+        let (_, _, _, labels) = compile_file(content).unwrap();
+        assert_eq!(labels.suggest("Lopp"), Some("Loop"));
+        let errors = compile_program(&content.replace("b Loop", "b Lopp"))
+            .err()
+            .unwrap();
+        assert_eq!(
+            errors,
+            vec!["\"main.s\" line 3: Label \"Lopp\" may not exist. Did you mean \"Loop\"?"]
+        );
+    }
+    #[test]
+    fn macro_expansion() {
+        use super::preprocess::{preprocess_file, PreprocessOptions};
+        let content = ".macro inc reg, amount=#1\n\
+            add \\reg, \\amount\n\
+            .endm\n\
+            .macro loop count:req\n\
+            l\\@:\n\
+            inc r0\n\
+            .exitm\n\
+            inc r1\n\
+            .endm\n\
+            inc r2, #5\n\
+            inc amount=#3, reg=r4\n\
+            loop 10\n\
+            loop 10\n";
+        let lines: Vec<String> = preprocess_file("main.s", content, &PreprocessOptions::default())
+            .unwrap()
+            .into_iter()
+            .map(|line| line.text)
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "add r2, #5",
+                "add r4, #3",
+                "l2:",
+                "add r0, #1",
+                "l4:",
+                "add r0, #1"
+            ]
+        );
+
+        // errors point to the macro body and the invocation
+        let content = ".macro bad\nmvo r0, r1\n.endm\nbad\n";
+        let lines = preprocess_file("main.s", content, &PreprocessOptions::default()).unwrap();
+        let mut errors = super::error::CompileErr::new();
+        errors.update_location(&lines[0]);
+        errors.push_message("Invalid instruction.");
+        assert_eq!(
+            errors.result().unwrap_err()[0],
+            "\"main.s\" line 2: Invalid instruction. (macro \"bad\" invoked from \"main.s\" line 4)"
+        );
+
+        assert!(preprocess_file(
+            "main.s",
+            ".macro m a:req\n.endm\nm\n",
+            &PreprocessOptions::default()
+        )
+        .is_err());
+        assert!(preprocess_file(
+            "main.s",
+            ".macro m\nm\n.endm\nm\n",
+            &PreprocessOptions::default()
+        )
+        .is_err());
+        assert!(preprocess_file(
+            "main.s",
+            ".macro m\nmov r0, r1\n",
+            &PreprocessOptions::default()
+        )
+        .is_err());
+    }
+    #[test]
+    fn conditional_assembly() {
+        use super::expression::evaluate;
+        use super::preprocess::{preprocess_file, PreprocessOptions};
+
+        let symbols = HashMap::from([("DEBUG".to_string(), 1), ("LEVEL".to_string(), 3)]);
+        let symbol = |name: &str| symbols.get(name).copied();
+        let options = PreprocessOptions {
+            symbols: symbols.clone(),
+            ..Default::default()
+        };
+        assert_eq!(evaluate("1 + 2 * 3", &symbol), Ok(7));
+        assert_eq!(evaluate("(DEBUG && LEVEL > 2) | 0x10", &symbol), Ok(17));
+        assert_eq!(evaluate("-0b10 << 2", &symbol), Ok(-8));
+        assert!(evaluate("UNKNOWN", &symbol).is_err());
+        assert!(evaluate("1 / 0", &symbol).is_err());
+        assert!(evaluate("(1", &symbol).is_err());
+
+        let content = ".ifdef DEBUG\n\
+            bl value\n\
+            .if LEVEL > 5\n\
+            mov r0, #5\n\
+            .elseif LEVEL > 2\n\
+            mov r0, #2\n\
+            .else\n\
+            mov r0, #0\n\
+            .endif\n\
+            .endif\n\
+            .ifndef DEBUG\n\
+            .if UNDEFINED\n\
+            .endif\n\
+            mov r1, r1\n\
+            .endif\n\
+            .equ SIZE, LEVEL * 2\n\
+            .ifeq SIZE - 6\n\
+            mov r2, #6\n\
+            .endif\n";
+        let lines: Vec<String> = preprocess_file("main.s", content, &options)
+            .unwrap()
+            .into_iter()
+            .map(|line| line.text)
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "bl value",
+                "mov r0, #2",
+                ".equ SIZE, LEVEL * 2",
+                "mov r2, #6"
+            ]
+        );
+        let lines = preprocess_file("main.s", content, &PreprocessOptions::default()).unwrap_err();
+        // UNDEFINED and SIZE are not defined, SIZE is not recorded since LEVEL is not defined
+        assert_eq!(lines.len(), 2);
+        // a .equ that uses labels is left to the assembler
+        assert!(
+            preprocess_file("main.s", "start:\nend:\n.equ LEN, end - start\n", &options).is_ok()
+        );
+
+        assert!(preprocess_file("main.s", ".if 1\n", &options).is_err());
+        assert!(preprocess_file("main.s", ".endif\n", &options).is_err());
+        assert!(preprocess_file("main.s", ".if 1\n.else\n.else\n.endif\n", &options).is_err());
+    }
+    #[test]
+    fn include_files() {
+        use super::preprocess::{preprocess_file, PreprocessOptions};
+        use std::fs;
+
+        let dir = temp_path("arm_include_files_test");
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("consts.s"), ".equ SIZE, 4\n.include \"util.s\"\n").unwrap();
+        fs::write(dir.join("lib/util.s"), "mov r1, #SIZE\nbad instruction\n").unwrap();
+        fs::write(dir.join("a.s"), ".include \"b.s\"\n").unwrap();
+        fs::write(dir.join("b.s"), ".include \"a.s\"\n").unwrap();
+        let options = PreprocessOptions {
+            dir_path: dir.clone(),
+            include_dirs: vec!["lib".into()],
+            ..Default::default()
+        };
+
+        let lines =
+            preprocess_file("main.s", ".include \"consts.s\"\nmov r0, #SIZE\n", &options).unwrap();
+        let text: Vec<&str> = lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(
+            text,
+            vec![
+                ".equ SIZE, 4",
+                "mov r1, #SIZE",
+                "bad instruction",
+                "mov r0, #SIZE"
+            ]
+        );
+        // the text is unchanged, the symbol is resolved when the operands are parsed
+        assert_eq!(lines[3].symbols, vec![("SIZE".to_string(), 4)]);
+        // errors in included files point at the include chain
+        let mut errors = super::error::CompileErr::new();
+        errors.update_location(&lines[2]);
+        errors.push_message("Invalid instruction.");
+        assert_eq!(
+            errors.result().unwrap_err()[0],
+            "\"lib/util.s\" line 2: Invalid instruction. (included from \"consts.s\" line 2, included from \"main.s\" line 1)"
+        );
+
+        let errors = preprocess_file("a.s", ".include \"b.s\"\n", &options).unwrap_err();
+        assert!(errors[0].contains("Include cycle: \"a.s\" includes \"b.s\" includes \"a.s\""));
+        assert!(preprocess_file("main.s", ".include \"missing.s\"\n", &options).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+    #[test]
+    fn equ_symbols() {
+        let content = ".equ COUNT, 10\n\
+            .set NEGATIVE, -COUNT\n\
+            main:\n\
+            mov r0, #COUNT\n\
+            add r1, r0, #count\n\
+            ldr r2, =COUNT\n\
+            .data\n\
+            table: .word COUNT, NEGATIVE\n\
+            .byte COUNT + 1\n";
+        let (program, ..) = compile_file(content).unwrap();
+        let expected =
+            compile_program("main:\nmov r0, #10\nadd r1, r0, #10\nldr r2, =10\n").unwrap();
+        assert_eq!(program.code_image(), expected.code_image());
+        let mut memory = [0u8; MEMORY_SIZE];
+        program.memory_image.load(&mut memory, 0);
+        assert_eq!(&memory[0..9], &[10, 0, 0, 0, 0xf6, 0xff, 0xff, 0xff, 11]);
+
+        // only symbols defined before the line can be used
+        assert!(compile_program("mov r0, #COUNT\n.equ COUNT, 10\n").is_err());
+        assert!(compile_program(".data\n.word COUNT\n.equ COUNT, 10\n").is_err());
+    }
+    #[test]
+    fn link_sections() {
+        use super::link::{self, SectionLayout};
+        use super::preprocess::{preprocess_file, PreprocessOptions, SourceFile};
+
+        let source = |name: &str, content: &str| SourceFile {
+            name: name.into(),
+            lines: preprocess_file(name, content, &PreprocessOptions::default()).unwrap(),
+        };
+        let sources = vec![
+            source(
+                "main.s",
+                "mov r0, #1\n.data\ncount:\n.word 0x11223344, table\n.bss\nbuffer:\n.space 8\n.text\nbx lr\n",
+            ),
+            source(
+                "table.s",
+                ".global table\n.section .rodata\ntable:\n.byte 1, 2\n.asciz \"hi\\n\"\n",
+            ),
+        ];
+        let layout = SectionLayout {
+            bss: Some(512),
+            ..Default::default()
+        };
+        let mut linked = link::link(&sources, &layout, 0..MEMORY_SIZE).unwrap();
+        let mut labels = Labels::collect(&sources, &linked).unwrap();
+        let image = linked
+            .relocate(&|file_index, label| labels.value(file_index, label))
+            .unwrap();
+        // .rodata is placed first, then .data, then .bss at its configured address
+        assert_eq!(labels.get_address("count"), Some(8));
+        assert_eq!(labels.get_address("buffer"), Some(512));
+        assert_eq!(labels.get_address("table"), Some(0));
+        labels.set_current_file(1);
+        assert_eq!(labels.get_address("table"), Some(0));
+        assert_eq!(labels.get_address("count"), None);
+
+        let mut memory = [0xffu8; MEMORY_SIZE];
+        image.load(&mut memory, 0);
+        assert_eq!(&memory[0..6], &[1, 2, b'h', b'i', b'\n', 0]);
+        assert_eq!(&memory[8..16], &[0x44, 0x33, 0x22, 0x11, 0, 0, 0, 0]);
+        assert_eq!(&memory[512..520], &[0; 8]);
+
+        let errors = |content: &str, layout: &SectionLayout| {
+            let sources = [source("main.s", content)];
+            let result = link::link(&sources, layout, 0..MEMORY_SIZE).and_then(|mut linked| {
+                let labels = Labels::collect(&sources, &linked)?;
+                linked.relocate(&|file_index, label| labels.value(file_index, label))
+            });
+            result.unwrap_err()
+        };
+        let default = SectionLayout::default();
+        assert_eq!(errors(".word 1\n", &default).len(), 1);
+        assert_eq!(errors(".bss\n.word 1\n", &default).len(), 1);
+        assert_eq!(errors(".data\n.byte 256\n", &default).len(), 1);
+        assert_eq!(errors(".data\n.word missing\n", &default).len(), 1);
+        assert_eq!(errors(".section .init\n", &default).len(), 1);
+        assert_eq!(errors(".bss\n.space 2000\n", &default).len(), 1);
+        // the end of the data region is reserved for the stack
+        assert_eq!(
+            errors(".bss\n.space 800\n", &default),
+            vec!["The .bss section (0..800) reaches into the stack (768..1024), reduce the layout's 'stack_size' or move the section."]
+        );
+        let no_stack = SectionLayout {
+            stack_size: 0,
+            ..Default::default()
+        };
+        assert!(link::link(
+            &[source("main.s", ".bss\n.space 800\n")],
+            &no_stack,
+            0..MEMORY_SIZE
+        )
+        .is_ok());
+        let overlapping = SectionLayout {
+            data: Some(0),
+            bss: Some(4),
+            ..Default::default()
+        };
+        assert_eq!(
+            errors(".data\n.space 8\n.bss\n.space 8\n", &overlapping).len(),
+            1
+        );
+    }
+    #[test]
+    fn symbol_table() {
+        use super::link::{self, SectionLayout};
+        use super::preprocess::{preprocess_file, PreprocessOptions, SourceFile};
+
+        let collect = |files: &[(&str, &str)]| {
+            let sources: Vec<SourceFile> = files
+                .iter()
+                .map(|(name, content)| SourceFile {
+                    name: name.to_string(),
+                    lines: preprocess_file(name, content, &PreprocessOptions::default()).unwrap(),
+                })
+                .collect();
+            let linked = link::link(&sources, &SectionLayout::default(), 0..MEMORY_SIZE)?;
+            Labels::collect(&sources, &linked)
+        };
+        // local labels with the same name in different files, and a weak label overridden by a global label
+        let mut labels = collect(&[
+            (
+                "a.s",
+                ".global main\n.weak handler\nmain:\nbx lr\nhelper:\nbx lr\nhandler:\nbx lr\n",
+            ),
+            (
+                "b.s",
+                ".extern main\n.global handler\nhelper:\nbl main\nhandler:\nbx lr\n",
+            ),
+        ])
+        .unwrap();
+        assert_eq!(labels.value(0, "helper"), Some(1));
+        assert_eq!(labels.value(1, "helper"), Some(3));
+        assert_eq!(labels.value(1, "main"), Some(0));
+        // the global handler in b.s replaces the weak handler in a.s
+        assert_eq!(labels.value(0, "handler"), Some(4));
+        labels.set_current_file(1);
+        assert_eq!(labels.value(1, "handler"), Some(4));
+        assert_eq!(labels.value(0, "missing"), None);
+
+        let errors = |files: &[(&str, &str)]| collect(files).unwrap_err();
+        assert_eq!(
+            errors(&[("main.s", "loop:\nbx lr\nloop:\n")]),
+            vec!["\"main.s\" line 3: Label \"loop\" is already defined on \"main.s\" line 1."]
+        );
+        assert_eq!(
+            errors(&[("a.s", ".global f\nf:\n"), ("b.s", "f:\n.global f\n")]),
+            vec!["\"b.s\" line 2: Global label \"f\" is defined twice, on \"a.s\" line 2 and on \"b.s\" line 1."]
+        );
+        assert_eq!(errors(&[("main.s", "printf:\nbx lr\n")]).len(), 1);
+        assert_eq!(errors(&[("main.s", ".extern missing\n")]).len(), 1);
+        assert_eq!(errors(&[("main.s", ".global missing\n")]).len(), 1);
+        assert_eq!(
+            errors(&[("main.s", "data:\n.data\ndata:\n.word 1\n")]).len(),
+            1
+        );
+
+        // string labels are local to their file unless they are exported
+        let mut labels = collect(&[
+            (
+                "a.s",
+                "msg:\n.string \"a\"\n.global hello\nhello:\n.string \"hello\"\n",
+            ),
+            ("b.s", ".extern hello\nmsg:\n.string \"b\"\n"),
+        ])
+        .unwrap();
+        // strings are stored in .rodata, their labels are data labels
+        let a_msg = labels.get_address("msg").unwrap();
+        let hello = labels.get_address("hello").unwrap();
+        assert_eq!(hello, a_msg + 2);
+        labels.set_current_file(1);
+        assert_eq!(labels.get_address("hello"), Some(hello));
+        assert_eq!(labels.get_address("msg"), Some(hello + 6));
+        assert_eq!(labels.value(0, "msg"), Some(i64::from(a_msg)));
+        assert_eq!(
+            errors(&[("main.s", "msg:\n.string \"a\"\nmsg:\n.string \"b\"\n")]),
+            vec!["\"main.s\" line 3: Label \"msg\" is already defined on \"main.s\" line 1."]
+        );
+        assert_eq!(
+            errors(&[
+                ("a.s", ".global msg\nmsg:\n.string \"a\"\n"),
+                ("b.s", ".global msg\nmsg:\n.string \"b\"\n")
+            ])
+            .len(),
+            1
+        );
+
+        // "ldr r0, =msg" loads the address of the string, and printf prints it from memory
+        let (program, _, _, labels) =
+            compile_file("ldr r0, =msg\nbl printf\nmsg:\n.string \"hello\"\n").unwrap();
+        let mut processor = Processor::new();
+        program.load_memory(&mut processor);
+        let address = labels.get_address("msg").unwrap();
+        // the address is stored in the literal pool after the code
+        assert!(program
+            .code_image()
+            .windows(4)
+            .any(|word| word == address.to_le_bytes()));
+        let mut std_out = String::new();
+        let operands = Operands::label {
+            label: Label::PRINTF,
+        };
+        hp::run_branch_instruction(
+            &mut std_out,
+            address,
+            &operands,
+            "main.s",
+            2,
+            &processor.memory,
+        )
+        .unwrap();
+        assert_eq!(std_out, "hello");
+    }
+    #[test]
+    fn numeric_labels() {
+        use super::preprocess::{preprocess_file, PreprocessOptions};
+
+        let content = "1:\n\
+            cmp r0, #10\n\
+            beq 1f\n\
+            add r0, #1\n\
+            b 1b\n\
+            1:\n\
+            b 1b\n\
+            .data\n\
+            2:\n\
+            .word 2b, 0x1f\n\
+            .ascii \"1f\"\n";
+        let lines: Vec<String> = preprocess_file("main.s", content, &PreprocessOptions::default())
+            .unwrap()
+            .into_iter()
+            .map(|line| line.text)
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "_local_1$0:",
+                "cmp r0, #10",
+                "beq _local_1$1",
+                "add r0, #1",
+                "b _local_1$0",
+                "_local_1$1:",
+                "b _local_1$1",
+                ".data",
+                "_local_2$0:",
+                ".word _local_2$0, 0x1f",
+                ".ascii \"1f\""
+            ]
+        );
+        let options = PreprocessOptions::default();
+        assert!(preprocess_file("main.s", "b 1b\n1:\n", &options).is_err());
+        assert!(preprocess_file("main.s", "1:\nb 1f\n", &options).is_err());
+        assert!(preprocess_file("main.s", "_local_1$0:\n", &options).is_err());
+        // a label of the file cannot have the same name as a numeric label
+        let program = compile_program("_local_1_0:\nmov r0, #1\n1:\nb _local_1_0\nb 1b\n").unwrap();
+        assert_eq!(program.line_count(), 3);
+    }
+    #[test]
+    fn label_prefix() {
+        use super::link::{self, SectionLayout};
+        use super::preprocess::{preprocess_file, PreprocessOptions, SourceFile};
+
+        let content = ".macro count reg\n\
+            loop\\@: add \\reg, #1\n\
+            .endm\n\
+            start: mov r0, #1\n\
+            count r0\n\
+            1: b 1b\n\
+            .data\n\
+            msg: .asciz \"a: b\"\n";
+        let lines = preprocess_file("main.s", content, &PreprocessOptions::default()).unwrap();
+        let text: Vec<&str> = lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(
+            text,
+            vec![
+                "start:",
+                "mov r0, #1",
+                "loop0:",
+                "add r0, #1",
+                "_local_1$0:",
+                "b _local_1$0",
+                ".data",
+                "msg:",
+                ".asciz \"a: b\""
+            ]
+        );
+        // both lines keep the location of the original line
+        assert_eq!(lines[0].location, lines[1].location);
+
+        let sources = vec![SourceFile {
+            name: "main.s".into(),
+            lines,
+        }];
+        let linked = link::link(&sources, &SectionLayout::default(), 0..MEMORY_SIZE).unwrap();
+        let labels = Labels::collect(&sources, &linked).unwrap();
+        assert_eq!(labels.value(0, "start"), Some(0));
+        assert_eq!(labels.value(0, "loop0"), Some(1));
+        assert_eq!(labels.value(0, "_local_1$0"), Some(2));
+        assert_eq!(labels.get_address("msg"), Some(0));
+    }
+    #[test]
+    fn comments() {
+        use super::backend_api::compile::preprocess_line;
+        use super::preprocess::{preprocess_file, PreprocessOptions};
+
+        let mut in_block = false;
+        let mut strip = |line: &str| preprocess_line(line, &mut in_block);
+        assert_eq!(strip("  mov r0, #1 // comment"), "mov r0, #1");
+        assert_eq!(strip("mov r0, #1 @ comment"), "mov r0, #1");
+        assert_eq!(strip("mov r0, #1 ; comment"), "mov r0, #1");
+        assert_eq!(strip("  # comment"), "");
+        assert_eq!(
+            strip(".ascii \"a;b@c//d\\\"e\" @ comment"),
+            ".ascii \"a;b@c//d\\\"e\""
+        );
+        assert_eq!(strip("loop\\@: add r0, #1"), "loop\\@: add r0, #1");
+        assert_eq!(strip("mov r0,/* inline */r1"), "mov r0, r1");
+
+        // line numbers stay correct after a block comment
+        let content = "/* first\n\
+            second */ mov r0, #1\n\
+            /*\n\
+            */\n\
+            add r0, #1\n";
+        let lines = preprocess_file("main.s", content, &PreprocessOptions::default()).unwrap();
+        let lines: Vec<(&str, usize)> = lines
+            .iter()
+            .filter(|line| !line.text.is_empty())
+            .map(|line| (line.text.as_str(), line.location.line_number))
+            .collect();
+        assert_eq!(lines, vec![("mov r0, #1", 2), ("add r0, #1", 5)]);
+        assert!(preprocess_file(
+            "main.s",
+            "mov r0, #1\n/* open\n",
+            &PreprocessOptions::default()
+        )
+        .is_err());
+    }
+    #[test]
+    fn register_aliases() {
+        use super::preprocess::{preprocess_file, PreprocessOptions};
+
+        let content = "count .req r4\n\
+            total .req A1\n\
+            mov count, #10\n\
+            add total, count, fp\n\
+            str total, [sp, #4]\n\
+            ldr ip, [count]\n\
+            ldr sl, =count\n\
+            b count\n\
+            .unreq count\n\
+            mov count, #1\n";
+        let lines: Vec<String> = preprocess_file("main.s", content, &PreprocessOptions::default())
+            .unwrap()
+            .into_iter()
+            .map(|line| line.text)
+            .collect();
+        // the text is kept as written, the aliases are resolved when the operands are parsed
+        assert_eq!(
+            lines,
+            vec![
+                "mov count, #10",
+                "add total, count, fp",
+                "str total, [sp, #4]",
+                "ldr ip, [count]",
+                "ldr sl, =count",
+                "b count",
+                "mov count, #1"
+            ]
+        );
+        let program = compile_program(
+            "count .req r4\ntotal .req A1\nmov count, #10\nadd total, count, fp\nldr ip, [count]\n",
+        )
+        .unwrap();
+        let expected = compile_program("mov r4, #10\nadd r0, r4, r11\nldr r12, [r4]\n").unwrap();
+        assert_eq!(program.code_image(), expected.code_image());
+        // diagnostics show the alias as written
+        assert_eq!(
+            compile_program("mov fp, #1, #2\n").err().unwrap(),
+            vec!["\"main.s\" line 1: \"mov fp, #1, #2\" contains invalid arguments"]
+        );
+        let errors = |content: &str| {
+            preprocess_file("main.s", content, &PreprocessOptions::default())
+                .unwrap_err()
+                .len()
+        };
+        assert_eq!(errors("x .req r99\n"), 1);
+        assert_eq!(errors("fp .req r4\n"), 1);
+        assert_eq!(errors("x .req r1\nx .req r2\n"), 1);
+        assert_eq!(errors(".unreq fp\n"), 1);
+    }
+
+    #[test]
+    fn gnu_directives() {
+        use super::link::{self, SectionLayout};
+        use super::preprocess::{preprocess_file, PreprocessOptions, SourceFile};
+
+        let content = ".syntax unified\n\
+            .cpu cortex-m4\n\
+            .thumb\n\
+            .section .text\n\
+            .align 1\n\
+            .global main\n\
+            .TYPE main, %function\n\
+            main:\n\
+            .cfi_startproc\n\
+            mov r0, #1\n\
+            .cfi_endproc\n\
+            .size main, .-main\n\
+            .THUMB_FUNC\n\
+            helper:\n\
+            add r0, #1\n\
+            other:\n\
+            .end\n\
+            this is not assembled\n";
+        let lines = preprocess_file("main.s", content, &PreprocessOptions::default()).unwrap();
+        let text: Vec<&str> = lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(
+            text,
+            vec![
+                ".section .text",
+                ".align 1",
+                ".global main",
+                ".TYPE main, %function",
+                "main:",
+                "mov r0, #1",
+                ".THUMB_FUNC",
+                "helper:",
+                "add r0, #1",
+                "other:"
+            ]
+        );
+        let sources = vec![SourceFile {
+            name: "main.s".into(),
+            lines,
+        }];
+        let linked = link::link(&sources, &SectionLayout::default(), 0..MEMORY_SIZE).unwrap();
+        let labels = Labels::collect(&sources, &linked).unwrap();
+        let is_function = |label: &str| labels.lookup(0, label).unwrap().function;
+        assert!(is_function("main") && is_function("helper") && !is_function("other"));
+        // instructions are only aligned to 2 bytes
+        let sources = vec![SourceFile {
+            name: "main.s".into(),
+            lines: preprocess_file("main.s", ".align 2\nbx lr\n", &PreprocessOptions::default())
+                .unwrap(),
+        }];
+        assert_eq!(
+            link::link(&sources, &SectionLayout::default(), 0..MEMORY_SIZE).err().unwrap(),
+            vec!["\"main.s\" line 1: .align cannot align instructions to 4 bytes, only to 2 bytes. Use it in the .rodata, .data, or .bss section."]
+        );
+
+        let errors = |content: &str| {
+            preprocess_file("main.s", content, &PreprocessOptions::default()).unwrap_err()
+        };
+        assert_eq!(
+            errors(".wrod 1\n"),
+            vec!["\"main.s\" line 1: Unknown directive \".wrod\". Did you mean \".word\"?"]
+        );
+        assert_eq!(errors(".arm\n").len(), 1);
+        assert_eq!(errors(".syntax divided\n").len(), 1);
+        assert_eq!(errors(".type main\n").len(), 1);
+    }
+
+    #[test]
+    fn armasm_dialect() {
+        use super::armasm::Dialect;
+        use super::preprocess::{preprocess_file, PreprocessOptions};
+
+        let content = "COUNT   EQU 3\n\
+            \x20       AREA main, CODE, READONLY\n\
+            \x20       THUMB\n\
+            \x20       EXPORT main\n\
+            main    PROC\n\
+            \x20       LDR r1, =table   ; address of the table\n\
+            1       ADD r0, #COUNT\n\
+            \x20       B %b1\n\
+            \x20       ENDP\n\
+            \x20       AREA data, DATA, READWRITE\n\
+            table   DCD 1, COUNT\n\
+            msg     DCB \"hi\", 0\n\
+            \x20   IF COUNT = 3 :LAND: COUNT <> 4\n\
+            buffer  SPACE 8\n\
+            \x20   ENDIF\n\
+            \x20       END\n\
+            ignored\n";
+        let lines: Vec<String> = preprocess_file("main.s", content, &PreprocessOptions::default())
+            .unwrap()
+            .into_iter()
+            .map(|line| line.text)
+            .filter(|text| !text.is_empty())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                ".equ COUNT, 3",
+                ".text",
+                ".global main",
+                ".thumb_func",
+                "main:",
+                "LDR r1, =table",
+                "_local_1$0:",
+                "ADD r0, #COUNT",
+                "B _local_1$0",
+                ".data",
+                ".balign 4",
+                "table:",
+                ".word 1, COUNT",
+                "msg:",
+                ".ascii \"hi\"",
+                ".byte 0",
+                "buffer:",
+                ".space 8"
+            ]
+        );
+        // EQU constants are immediate values and data
+        let (program, ..) = compile_file(content).unwrap();
+        let (expected, ..) = compile_file(&content.replace("#COUNT", "#3")).unwrap();
+        assert_eq!(program.code_image(), expected.code_image());
+        let mut memory = [0u8; MEMORY_SIZE];
+        program.memory_image.load(&mut memory, 0);
+        assert_eq!(&memory[0..8], &[1, 0, 0, 0, 3, 0, 0, 0]);
+        // the dialect in config.json turns off detection
+        let options = PreprocessOptions {
+            dialect: Some(Dialect::Gnu),
+            ..Default::default()
+        };
+        let lines = preprocess_file("main.s", "  AREA main, CODE\n", &options).unwrap();
+        assert_eq!(lines[0].text, "AREA main, CODE");
+        let errors = |content: &str| {
+            preprocess_file("main.s", content, &PreprocessOptions::default())
+                .unwrap_err()
+                .len()
+        };
+        assert_eq!(errors(" AREA main, READONLY\n"), 1);
+        assert_eq!(errors(" AREA main, CODE\n EQU 3\n"), 1);
+        assert_eq!(errors(" AREA main, CODE\n ARM\n"), 1);
+    }
+
+    #[test]
+    fn c_preprocessor() {
+        use super::preprocess::{preprocess_file, PreprocessOptions};
+        use std::fs;
+
+        let dir = temp_path("arm_c_preprocessor_test");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("config.h"),
+            "#ifndef CONFIG_H\n#define CONFIG_H\n#define SIZE 4 /* bytes */\n#ifndef __ASSEMBLER__\nint size;\n#endif\n#endif\n",
+        )
+        .unwrap();
+        let options = PreprocessOptions {
+            dir_path: dir.clone(),
+            ..Default::default()
+        };
+        let content = "#include \"config.h\"\n\
+            #include \"config.h\"\n\
+            #define LOAD(reg, value) mov reg, #value\n\
+            #define ADD_ALL(reg, ...) add reg, __VA_ARGS__\n\
+            #define LABEL(name) name ## _start:\n\
+            #if defined(SIZE) && SIZE > 2\n\
+            LABEL(loop)\n\
+            LOAD(r0, SIZE) // comment\n\
+            #elif SIZE\n\
+            mov r0, #0\n\
+            #else\n\
+            mov r0, #1\n\
+            #endif\n\
+            ADD_ALL(r1, r1, \\\n\
+            #SIZE)\n\
+            #line 100 \"other.s\"\n\
+            add r2, #0x1F\n";
+        let lines = preprocess_file("main.S", content, &options).unwrap();
+        let text: Vec<(&str, String)> = lines
+            .iter()
+            .map(|line| (line.text.as_str(), line.location.to_string()))
+            .collect();
+        assert_eq!(
+            text,
+            vec![
+                ("loop_start:", "\"main.S\" line 7".to_string()),
+                ("mov r0, #4", "\"main.S\" line 8".to_string()),
+                ("add r1, r1, #4", "\"main.S\" line 14".to_string()),
+                ("add r2, #0x1F", "\"other.s\" line 100".to_string())
+            ]
+        );
+        let errors = |content: &str| preprocess_file("main.S", content, &options).unwrap_err();
+        assert_eq!(
+            errors("#define MAX(a, b) a\nMAX(1)\n"),
+            vec!["\"main.S\" line 2: Macro \"MAX\" takes 2 arguments, but 1 were given."]
+        );
+        assert_eq!(errors("#if 1\n").len(), 1);
+        assert_eq!(errors("#error stop\n").len(), 1);
+        assert_eq!(errors("#include \"missing.h\"\n").len(), 1);
+        // headers can include each other when they are guarded, otherwise the cycle is an error
+        fs::write(
+            dir.join("a.h"),
+            "#ifndef A_H\n#define A_H\n#include \"b.h\"\n#endif\n",
+        )
+        .unwrap();
+        fs::write(dir.join("b.h"), "#include \"a.h\"\n").unwrap();
+        fs::write(dir.join("c.h"), "#include \"d.h\"\n").unwrap();
+        fs::write(dir.join("d.h"), "#include \"c.h\"\n").unwrap();
+        assert!(preprocess_file("main.S", "#include \"a.h\"\n", &options).is_ok());
+        let cycle = errors("#include \"c.h\"\n");
+        assert_eq!(cycle.len(), 1);
+        assert!(cycle[0].contains("Include cycle: \"c.h\" includes \"d.h\" includes \"c.h\""));
+        // .s files are not run through the C preprocessor
+        assert!(preprocess_file("main.s", "#define X 1\nmov r0, #X\n", &options).is_ok());
+        fs::remove_dir_all(dir).unwrap();
+    }
+    #[test]
+    fn thumb_encoding() {
+        use super::encode::{self, Context, Encoding};
+
+        let program = compile_program(
+            "main:\nmovs r0, #1\nite eq\nmoveq r1, r0\naddne r1, #300\nldr r2,=number\nmov.w r3, #256\n\
+             bl printf\nb main\n.data\nnumber:\n.word 5\n",
+        )
+        .unwrap();
+        let encodings: Vec<(u32, String)> = program
+            .encodings()
+            .into_iter()
+            .map(|encoding| {
+                (
+                    encoding.address - encode::TEXT_ADDRESS,
+                    encoding.machine_code,
+                )
+            })
+            .collect();
+        let expected = [
+            (0, "2001"),
+            (2, "bf0c"),
+            (4, "4601"),
+            (6, "f501 7196"),
+            (10, "4a09"),
+            (12, "f44f 7380"),
+            (16, "f000 f80b"),
+            (20, "e7f4"),
+        ];
+        let expected: Vec<(u32, String)> = expected
+            .iter()
+            .map(|&(address, code)| (address, code.to_string()))
+            .collect();
+        assert_eq!(encodings, expected);
+
+        // narrow encodings set the flags outside an IT block
+        let context = Context {
+            address: encode::TEXT_ADDRESS,
+            target: &|_| encode::TEXT_ADDRESS,
+            literal: &|_| encode::TEXT_ADDRESS,
+        };
+        let encode = |line: &str| {
+            let program = Program::new();
+            let (mnemonic, extension) = program.find_mnemonic(&line.to_string()).unwrap();
+            let operands = MOV.get_operands(&extension, line).unwrap();
+            encode::encode(&mnemonic, &extension, &operands, &context, false)
+        };
+        assert_eq!(encode("mov r0, #1"), Ok(Encoding::Wide(0xF04F_0001)));
+        assert_eq!(encode("mov r8, r1"), Ok(Encoding::Narrow(0x4688)));
+        assert!(encode("movs.n r8, #1").unwrap_err().contains("r0-r7"));
+        assert!(encode("mov.n r0, #1").unwrap_err().contains("S flag"));
+        assert_eq!(encode("moveq r0, #1"), encode("mov r0, #1"));
+        assert_eq!(encode::it_instruction("it ne"), Some(0xBF18));
+
+        // encoding errors keep the macro backtrace of the line
+        let errors = compile_program(".macro narrow\nmov.n r0, #1\n.endm\nnarrow\n")
+            .err()
+            .unwrap();
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("\"main.s\" line 2:"));
+        assert!(errors[0].ends_with("(macro \"narrow\" invoked from \"main.s\" line 4)"));
+    }
+    #[test]
+    fn thumb_disassembler() {
+        let content = "main:\nmovs r0, #1\nmov r8, r0\nmov r1, #0x100\nmov r2, #0xff0\nadds r3, r0, #2\n\
+            add sp, #8\nadd r4, sp, #16\nadd r8, r1\nadds r1, r2, r3\nadd r9, r1, r2\nadd.w r0, r0, #1\n\
+            cmp r0, #10\ncmp r8, r1\ncmp.w r0, #0x100\nitte eq\nmoveq r0, #2\naddeq r0, r1\nmovne r0, #3\n\
+            bl printf\nldr r2,=number\nstr r0, [r1]\nstrb r0, [r1, #1]\nstrh r0, [r1, #2]\nldr r0, [sp, #8]\n\
+            ldrb r0, [r1, r2]\nldrh r0, [r1, #-2]\nldr r0, [r1, #4]!\nldr r0, [r1], #4\n\
+            str r0, [r1, r2, lsl #2]\nldr r9, [r1, #100]\nbeq main\nb main\n.data\nnumber:\n.word 5\n";
+        let program = compile_program(content).unwrap();
+        let name = |label: Label| match label {
+            Label::Index(index) => format!("l{}", index),
+            Label::PRINTF => "printf".into(),
+            _ => "builtin".into(),
+        };
+        let disassembly = program.disassemble(&name);
+        let text: Vec<&str> = disassembly.iter().map(|(_, text)| text.as_str()).collect();
+        assert_eq!(
+            text[..12],
+            [
+                "movs r0, #1",
+                "mov r8, r0",
+                "mov r1, #256",
+                "mov r2, #4080",
+                "adds r3, r0, #2",
+                "add sp, #8",
+                "add r4, sp, #16",
+                "add r8, r1",
+                "adds r1, r2, r3",
+                "add r9, r1, r2",
+                "add r0, r0, #1",
+                "cmp r0, #10",
+            ]
+        );
+        assert_eq!(
+            text[14..18],
+            ["itte eq", "moveq r0, #2", "addeq r0, r1", "movne r0, #3"]
+        );
+        assert_eq!(text[19], "ldr r2, =0x0");
+        assert_eq!(text[25], "ldrh r0, [r1, #-2]");
+        assert_eq!(text[30], "beq l0");
+        // assembling the disassembly gives the same machine code
+        let mut source = String::new();
+        for (address, text) in &disassembly {
+            if *address >= program.builtins_address() {
+                break;
+            }
+            if let Some(Label::Index(index)) = program.label_at(*address) {
+                source += &format!("l{}:\n", index);
+            }
+            source += text;
+            source.push('\n');
+        }
+        assert_eq!(
+            compile_program(&source).unwrap().code_image(),
+            program.code_image()
+        );
+    }
+    #[test]
+    fn elf_loader() {
+        use super::elf::Elf;
+
+        let bytes = include_bytes!("../fixtures/count.elf");
+        let elf = Elf::parse(bytes).unwrap();
+        assert_eq!(elf.entry, 0x0800_0008);
+        let mut program = Program::new();
+        let executable = program
+            .load_elf("count.elf", &elf, &MemoryMap::default())
+            .unwrap();
+        assert_eq!(
+            executable.listing.lines().collect::<Vec<&str>>(),
+            [
+                "reset:",
+                "    mov r1, #256",
+                "    ldr r0, [r1]",
+                "loop:",
+                "    adds r0, #1",
+                "    cmp r0, #5",
+                "    bne loop",
+                "    ite eq",
+                "    moveq r2, #1",
+                "    movne r2, #0",
+                "    ldr r3, =0x12345678",
+                "    str r0, [r1, #4]",
+                "    bl finish",
+                "    movs r0, r0",
+                "finish:",
+                "    strb r2, [r1, #8]",
+            ]
+        );
+        assert_eq!(executable.stack_pointer, 0x400);
+        assert_eq!(executable.entry, 0);
+        let mut processor = Processor::new();
+        program.load_memory(&mut processor);
+        assert_eq!(processor.memory.bytes(0x100..0x104), Some(vec![2, 0, 0, 0]));
+        // the code segment is loaded too, it starts with the vector table
+        let map: MemoryMap = serde_json::from_str(
+            r#"[{ "name": "FLASH", "origin": "0x08000000", "length": 4096, "access": "rx" }, { "name": "RAM", "origin": 0, "length": 1024, "access": "rw" }]"#,
+        )
+        .unwrap();
+        processor.memory = super::memory::Memory::new(map);
+        program.load_memory(&mut processor);
+        assert_eq!(processor.memory.read(0x0800_0000, MemSize::WORD), Ok(0x400));
+
+        let mut bytes = bytes.to_vec();
+        bytes[18] = 3;
+        assert_eq!(
+            Elf::parse(&bytes).unwrap_err(),
+            "The ELF file is not for an ARM processor."
+        );
+        assert_eq!(
+            Elf::parse(b"MZ").unwrap_err(),
+            "The file is not an ELF file."
+        );
+    }
+
+    #[test]
+    fn elf_export() {
+        use super::elf::{self, Elf};
+
+        let program = compile_program(
+            ".data\nnumber: .word 7\n.text\n.global main\n.thumb_func\nmain:\n    ldr r0,=number\n    ldr r1, [r0]\nloop:\n    add r1, r1, #1\n    b loop\n",
+        )
+        .unwrap();
+        let bytes = program.to_elf();
+        let elf = Elf::parse(&bytes).unwrap();
+        assert_eq!(elf.entry, 0x0800_0000);
+        assert_eq!(elf.segments[0].data, program.code_image());
+        assert!(elf.segments[0].is_executable());
+        assert_eq!(elf.word(elf.segments[1].address), Some(7));
+        let names: Vec<&str> = elf.sections.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "",
+                ".text",
+                ".data",
+                ".debug_line",
+                ".ARM.attributes",
+                ".symtab",
+                ".strtab",
+                ".shstrtab"
+            ]
+        );
+        let symbol = |name: &str| elf.symbols.iter().find(|s| s.name == name).unwrap();
+        assert_eq!(
+            (
+                symbol("main").value,
+                symbol("main").kind,
+                symbol("main").global
+            ),
+            (0x0800_0000, elf::STT_FUNC, true)
+        );
+        assert_eq!(symbol("loop").value, 0x0800_0004);
+        assert_eq!(
+            (symbol("number").kind, symbol("number").section),
+            (elf::STT_OBJECT, 2)
+        );
+        assert_eq!(symbol("$d").mapping(), Some('d'));
+
+        // data far from the other data is written as its own section, without the gap
+        let mut program = program;
+        program
+            .memory_image
+            .add_bytes(0x2000_0000, vec![1, 2, 3, 4]);
+        let bytes = program.to_elf();
+        assert!(bytes.len() < 0x1_0000);
+        let elf = Elf::parse(&bytes).unwrap();
+        assert_eq!(elf.segments.len(), 3);
+        assert_eq!(elf.word(elf.segments[1].address), Some(7));
+        assert_eq!(elf.segments[2].address, 0x2000_0000);
+        assert_eq!(elf.word(0x2000_0000), Some(0x0403_0201));
+        assert_eq!(elf.sections[3].name, ".data.1");
+    }
+
+    #[test]
+    fn assembler_listing() {
+        let content = ".macro inc reg\n    add \\reg, \\reg, #1\n.endm\n.data\ntable: .word 1, 2, 3, 4, 5\n.text\n.global main\nmain:\n    ldr r0,=table @ load the address\n    inc r1\n    cmp r1, #5\n    beq main\n";
+        let (program, sources, linked, labels) = compile_file(content).unwrap();
+        let mut rows = program.listed_instructions().into();
+        let listing = super::listing::listing(
+            &sources[0],
+            content,
+            0,
+            &mut rows,
+            &program,
+            &linked,
+            &labels,
+        );
+        let lines: Vec<&str> = listing.lines().collect();
+        assert!(rows.is_empty());
+        assert_eq!(lines[2], " Line  Address  Code       Cyc  Source");
+        assert_eq!(
+            lines[7],
+            "    5  00000000 01000000        table: .word 1, 2, 3, 4, 5"
+        );
+        assert_eq!(lines[8], "       00000004 02000000");
+        assert_eq!(
+            lines[14..19],
+            [
+                "    9  08000000 4808         2      ldr r0,=table @ load the address",
+                "       08000024 00000000        .word 0x00000000  @ literal pool",
+                "   10                               inc r1",
+                "       08000002 f101 0101    1  +   add r1, r1, #1",
+                "   11  08000006 2905         1      cmp r1, #5",
+            ]
+        );
+        assert_eq!(lines[19], "   12  08000008 d0fa       1-4      beq main");
+        assert_eq!(
+            lines.last(),
+            Some(&" main                 08000000 global   code      \"main.s\" line 8")
+        );
+    }
+
+    #[test]
+    fn linker_map() {
+        let (program, _, linked, labels) = compile_file(
+            ".section .rodata\nmessage: .asciz \"hi\"\n.data\ncount: .word 0\n.bss\nbuffer: .space 100\n.text\n.global main\nmain:\n    ldr r0,=count\n    b main\n",
+        )
+        .unwrap();
+        let map = super::mapfile::map_file(&program, &linked, &labels, &MemoryMap::default());
+        let lines: Vec<&str> = map.lines().collect();
+        assert_eq!(
+            lines[3],
+            "RAM              0x00000000 0x00000400 0x0000006b  10.4%  rw-"
+        );
+        assert_eq!(
+            lines[6],
+            "  .bss                                 0x00000064   9.8%"
+        );
+        assert_eq!(
+            lines[10..14],
+            [
+                ".text            0x08000000 0x00000020",
+                " main.s          0x08000000 0x00000004",
+                " (built-in)      0x08000004 0x00000018",
+                " (literal pool)  0x0800001c 0x00000004",
+            ]
+        );
+        assert_eq!(lines[16], ".data            0x00000004 0x00000004");
+        assert_eq!(
+            lines[lines.len() - 2..],
+            [
+                "0x00000008 buffer               local    main.s",
+                "0x08000000 main                 global   main.s",
+            ]
+        );
+    }
+
+    #[test]
+    fn memory_image_files() {
+        use super::hexfile::{self, Format};
+
+        let bytes: Vec<u8> = (0..40).collect();
+        let ihex = hexfile::write(Format::Ihex, 0x100, &bytes);
+        let text = String::from_utf8(ihex.clone()).unwrap();
+        assert_eq!(
+            text.lines().next(),
+            Some(":10010000000102030405060708090A0B0C0D0E0F77")
+        );
+        assert_eq!(text.lines().last(), Some(":00000001FF"));
+        let srec = hexfile::write(Format::Srec, 0x100, &bytes);
+        for (format, content) in [(Format::Ihex, ihex), (Format::Srec, srec)] {
+            let blocks = hexfile::read(format, 0, &content).unwrap();
+            let blocks = hexfile::clip(blocks, 0x104..0x128);
+            let loaded: Vec<u8> = blocks.iter().flat_map(|(_, bytes)| bytes.clone()).collect();
+            assert_eq!(blocks[0].0, 0x104);
+            assert_eq!(loaded, bytes[4..0x28].to_vec());
+        }
+        // records are split at every 64 KB boundary
+        let ihex = hexfile::write(Format::Ihex, 0xfff8, &bytes);
+        let text = String::from_utf8(ihex.clone()).unwrap();
+        assert_eq!(
+            text.lines().take(3).collect::<Vec<&str>>(),
+            [
+                ":08FFF8000001020304050607E5",
+                ":020000040001F9",
+                ":1000000008090A0B0C0D0E0F1011121314151617F8"
+            ]
+        );
+        let blocks = hexfile::read(Format::Ihex, 0, &ihex).unwrap();
+        assert!(blocks
+            .iter()
+            .all(|(address, bytes)| address >> 16 == (address + bytes.len() as u32 - 1) >> 16));
+        assert_eq!(blocks[0].0, 0xfff8);
+        assert_eq!(blocks[1].0, 0x1_0000);
+        let loaded: Vec<u8> = blocks.iter().flat_map(|(_, bytes)| bytes.clone()).collect();
+        assert_eq!(loaded, bytes);
+        assert_eq!(
+            hexfile::read(Format::Bin, 0x20, &bytes[..2]).unwrap(),
+            [(0x20, vec![0, 1])]
+        );
+        assert_eq!(
+            hexfile::read(Format::Ihex, 0, b":0400000001000000FA\n").unwrap_err(),
+            "Line 1: The checksum is wrong."
+        );
+        assert_eq!(
+            hexfile::memory_range(Some(0), Some(2000), &MemoryMap::default()).unwrap_err(),
+            "The address range 0x0..0x7d0 is not inside the memory region \"RAM\" (0x0..0x400)."
+        );
+
+        // preloading a file set in config.json
+        let dir = temp_path("arm_memory_image_test");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("table.bin"), &bytes).unwrap();
+        let preload: hexfile::Preload =
+            serde_json::from_str(r#"{ "file": "table.bin", "address": 1000 }"#).unwrap();
+        let dir_path = format!("{}/", dir.to_str().unwrap());
+        let dir_path = dir_path.as_str();
+        assert!(preload
+            .read(dir_path, &MemoryMap::default())
+            .unwrap_err()
+            .contains("outside of the memory regions"));
+        let preload: hexfile::Preload =
+            serde_json::from_str(r#"{ "file": "table.bin", "address": 1000, "start": 1020 }"#)
+                .unwrap();
+        assert_eq!(
+            preload.read(dir_path, &MemoryMap::default()).unwrap(),
+            [(1020, vec![20, 21, 22, 23])]
+        );
+    }
+
+    #[test]
+    fn program_cache() {
+        use super::cache::{self, ProgramCache};
+        use super::preprocess::SourceLine;
+
+        let content = "main:\n\tmov r0, #1\n\tadd r0, r0, #2\n\tbl done\ndone:\n\tb done\n";
+        let (program, sources, _, labels) = compile_file(content).unwrap();
+        let no_breakpoints = |_: &SourceLine| false;
+        let key = cache::file_key(&sources[0], 0, &labels, &no_breakpoints);
+        let mut cache = ProgramCache::default();
+        cache.insert("main.s", key, program.lines_from(0));
+
+        let path = temp_path("arm_program_cache_test.json");
+        let path = path.to_str().unwrap();
+        cache.save(path).unwrap();
+        let cache = ProgramCache::load(path);
+        // the cached lines encode to the same machine code
+        let mut cached = Program::new();
+        cached.extend_lines(cache.get("main.s", key).unwrap());
+        cached.encode().unwrap();
+        assert_eq!(cached.code_image(), program.code_image());
+
+        // a changed file, or a breakpoint, misses the cache
+        let (_, sources, _, labels) = compile_file(&content.replace("#2", "#3")).unwrap();
+        let changed = cache::file_key(&sources[0], 0, &labels, &no_breakpoints);
+        assert!(cache.get("main.s", changed).is_none());
+        let breakpoint = cache::file_key(&sources[0], 0, &labels, &|_| true);
+        assert_ne!(breakpoint, changed);
+
+        // a cache hit builds the same program as a cold compile
+        use super::backend_api::{build, compile::Config};
+        let dir = temp_path("arm_program_cache_build");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("main.s"),
+            ".data\ncount: .word 3, table\n.text\nmain:\n\tldr r0, =count\n\tbl print\nprint:\n\tmov r1, #1\ntable:\n\t.string \"hi\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("config.json"),
+            r#"{ "files": ["main.s"], "cache": true }"#,
+        )
+        .unwrap();
+        let dir_path = format!("{}/", dir.to_str().unwrap());
+        let config = Config::new(&dir_path).unwrap();
+        let cold = build(&config, &|_| false).unwrap();
+        let key = cache::file_key(&cold.sources[0], 0, &cold.labels, &no_breakpoints);
+        let cache = ProgramCache::load(dir.join(cache::CACHE_FILE).to_str().unwrap());
+        assert!(cache.get("main.s", key).is_some());
+        let cached = build(&config, &|_| false).unwrap();
+        assert_eq!(
+            format!("{:?}", cached.program.lines_from(0)),
+            format!("{:?}", cold.program.lines_from(0))
+        );
+        assert_eq!(cached.program.code_image(), cold.program.code_image());
+        assert_eq!(
+            format!("{:?}", cached.program.memory_image),
+            format!("{:?}", cold.program.memory_image)
+        );
+        assert_eq!(
+            format!("{:?}", cached.program.symbols),
+            format!("{:?}", cold.program.symbols)
+        );
+
+        // the cache is off unless config.json turns it on
+        std::fs::remove_file(dir.join(cache::CACHE_FILE)).unwrap();
+        std::fs::remove_file(dir.join("config.json")).unwrap();
+        build(&Config::new(&dir_path).unwrap(), &|_| false).unwrap();
+        assert!(!dir.join(cache::CACHE_FILE).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn memory_regions() {
+        let map: MemoryMap = serde_json::from_str(
+            r#"[
+                { "name": "FLASH", "origin": "0x08000000", "length": "0x10000", "access": "rx" },
+                { "name": "SRAM", "origin": "0x20000000", "length": 4096, "access": "rw" }
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(map.check(), Ok(()));
+        assert_eq!(map.data_region().unwrap().name, "SRAM");
+        assert_eq!(map.check_code(0x0800_0000..0x0800_0010), Ok(()));
+
+        let mut processor = Processor::with_memory_map(map.clone());
+        assert_eq!(processor.R[13], 0x2000_1000);
+        let memory = &mut processor.memory;
+        memory
+            .write(0x2000_0010, MemSize::WORD, 0x1122_3344)
+            .unwrap();
+        assert_eq!(memory.read(0x2000_0012, MemSize::HALFWORD), Ok(0x1122));
+        assert!(memory
+            .write(0x0800_0000, MemSize::BYTE, 1)
+            .unwrap_err()
+            .contains("read-only memory region \"FLASH\""));
+        assert!(memory
+            .read(0x1000_0000, MemSize::WORD)
+            .unwrap_err()
+            .contains("not in any memory region"));
+        assert!(memory
+            .read(0x2000_0ffe, MemSize::WORD)
+            .unwrap_err()
+            .contains("past the end of the memory region \"SRAM\""));
+
+        // the machine code is loaded into the region at its address
+        let program = compile_program("main:\n    mov r0, #1\n").unwrap();
+        program.load_memory(&mut processor);
+        let code = program.code_image();
+        assert_eq!(
+            processor.memory.read(0x0800_0000, MemSize::HALFWORD),
+            Ok(u16::from_le_bytes([code[0], code[1]]) as u32)
+        );
+
+        let errors = |json: &str| {
+            serde_json::from_str::<MemoryMap>(json)
+                .map_err(|err| vec![err.to_string()])
+                .and_then(|map| map.check())
+                .unwrap_err()
+        };
+        assert_eq!(
+            errors(
+                r#"[{ "name": "A", "origin": 0, "length": 8, "access": "rw" }, { "name": "B", "origin": 4, "length": 8, "access": "rw" }]"#
+            ),
+            ["The memory region \"B\" overlaps the region \"A\"."]
+        );
+        assert_eq!(
+            errors(r#"[{ "name": "ROM", "origin": 0, "length": 8, "access": "r" }]"#),
+            ["The memory map needs a read-write region for the data sections and the stack."]
+        );
+        assert!(
+            errors(r#"[{ "name": "A", "origin": 0, "length": 8, "access": "rq" }]"#)[0]
+                .contains("Unknown access")
+        );
+        let map: MemoryMap = serde_json::from_str(
+            r#"[{ "name": "ROM", "origin": "0x08000000", "length": 64, "access": "r" }]"#,
+        )
+        .unwrap();
+        assert!(map.check_code(0x0800_0000..0x0800_0004).is_err());
+    }
+
+    #[test]
+    fn paged_memory() {
+        use super::memory::{Memory, PAGE_SIZE};
+        use std::collections::BTreeSet;
+
+        // a 256 MB region is only allocated where it is written
+        let map: MemoryMap = serde_json::from_str(
+            r#"[{ "name": "SDRAM", "origin": "0x40000000", "length": "0x10000000", "access": "rw" }]"#,
+        )
+        .unwrap();
+        let mut memory = Memory::new(map);
+        assert_eq!(memory.take_changed_pages(), None);
+        assert_eq!(memory.read(0x4800_0000, MemSize::WORD), Ok(0));
+        assert!(memory.page(0x48000).is_none());
+
+        // a word that crosses a page boundary
+        memory
+            .write(0x4000_0ffe, MemSize::WORD, 0xaabb_ccdd)
+            .unwrap();
+        memory
+            .write(0x4000_2000, MemSize::HALFWORD, 0x1234)
+            .unwrap();
+        assert_eq!(memory.read(0x4000_0ffe, MemSize::WORD), Ok(0xaabb_ccdd));
+        assert_eq!(memory.read(0x4000_1000, MemSize::HALFWORD), Ok(0xaabb));
+        assert_eq!(memory.read(0x4000_2001, MemSize::BYTE), Ok(0x12));
+        assert_eq!(memory.page(0x40000).unwrap()[PAGE_SIZE - 2..], [0xdd, 0xcc]);
+        assert_eq!(
+            memory.take_changed_pages(),
+            Some(BTreeSet::from([0x40000, 0x40001, 0x40002]))
+        );
+        assert_eq!(memory.take_changed_pages(), Some(BTreeSet::new()));
+        assert_eq!(
+            memory.bytes(0x4000_0ffc..0x4000_1002),
+            Some(vec![0, 0, 0xdd, 0xcc, 0xbb, 0xaa])
+        );
+
+        // a region that ends at the top of the address space
+        let map: MemoryMap = serde_json::from_str(
+            r#"[{ "name": "TOP", "origin": "0xfffff000", "length": "0x1000", "access": "rw" }]"#,
+        )
+        .unwrap();
+        let mut memory = Memory::new(map);
+        memory
+            .write(0xffff_fffc, MemSize::WORD, 0x1122_3344)
+            .unwrap();
+        assert_eq!(
+            memory
+                .bytes(0xffff_f000..0x1_0000_0000)
+                .map(|bytes| bytes[0xffc..].to_vec()),
+            Some(vec![0x44, 0x33, 0x22, 0x11])
+        );
+    }
 }
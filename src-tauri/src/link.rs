@@ -0,0 +1,643 @@
+/// Contains the section handling and the link step.
+/// Code is placed in the .text section, which is the list of compiled instructions, so the PC is an index into it.
+/// The .rodata, .data, and .bss sections of every file are laid out in memory.
+use crate::error::CompileErr;
+use crate::expression;
+use crate::preprocess::{self, SourceFile, SourceLine};
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Section {
+    Text,
+    RoData,
+    Data,
+    Bss,
+}
+impl Section {
+    /// The sections placed in memory, in their default order.
+    pub const DATA_SECTIONS: [Section; 3] = [Section::RoData, Section::Data, Section::Bss];
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Self::Text => ".text",
+            Self::RoData => ".rodata",
+            Self::Data => ".data",
+            Self::Bss => ".bss",
+        }
+    }
+    /// Returns the section selected by a directive, ex: ".data" or ".section .rodata.str"
+    /// Returns None if the line is not a section directive.
+    pub fn from_directive(line: &str) -> Option<Result<Self, String>> {
+        let line = line.to_lowercase();
+        let mut words = line.split_whitespace();
+        let name = match words.next()? {
+            ".section" => words
+                .next()
+                .unwrap_or("")
+                .split(',')
+                .next()
+                .unwrap_or("")
+                .to_string(),
+            directive => directive.to_string(),
+        };
+        let section = [Self::Text, Self::RoData, Self::Data, Self::Bss]
+            .into_iter()
+            .find(|section| {
+                name == section.name() || name.starts_with(&format!("{}.", section.name()))
+            });
+        match section {
+            Some(section) => Some(Ok(section)),
+            None if line.starts_with(".section") => Some(Err(format!(
+                "Unknown section \"{}\", sections can only be .text, .rodata, .data, or .bss",
+                name
+            ))),
+            None => None,
+        }
+    }
+    fn index(&self) -> usize {
+        match *self {
+            Self::Text => 0,
+            Self::RoData => 1,
+            Self::Data => 2,
+            Self::Bss => 3,
+        }
+    }
+}
+
+/// Pairs every line with the section it is in. Files start in the .text section.
+pub fn sections(lines: &[SourceLine]) -> impl Iterator<Item = (Section, &SourceLine)> {
+    lines.iter().scan(Section::Text, |section, line| {
+        if let Some(Ok(new_section)) = Section::from_directive(&line.text) {
+            *section = new_section;
+        }
+        Some((*section, line))
+    })
+}
+
+/// Directives that place data in memory.
+pub const DATA_DIRECTIVES: [&str; 13] = [
+    ".byte", ".hword", ".short", ".word", ".long", ".ascii", ".asciz", ".string", ".space",
+    ".skip", ".align", ".p2align", ".balign",
+];
+
+/// Returns true if the first word of a line is a directive that places data in memory.
+pub fn is_data_directive(directive: &str) -> bool {
+    DATA_DIRECTIVES.contains(&directive)
+}
+
+/// Returns true if the directive aligns the next data.
+fn is_align_directive(directive: &str) -> bool {
+    matches!(directive, ".align" | ".p2align" | ".balign")
+}
+
+/// The bytes at the end of the data region that are kept free for the stack, unless config.json sets another size.
+pub const DEFAULT_STACK_SIZE: usize = 256;
+
+/// Start addresses of the memory sections, read from config.json.
+/// A section without an address is placed right after the previous section.
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct SectionLayout {
+    pub rodata: Option<usize>,
+    pub data: Option<usize>,
+    pub bss: Option<usize>,
+    /// The bytes at the end of the data region reserved for the stack, the data sections cannot reach into them.
+    #[serde(default = "default_stack_size")]
+    pub stack_size: usize,
+}
+fn default_stack_size() -> usize {
+    DEFAULT_STACK_SIZE
+}
+impl Default for SectionLayout {
+    fn default() -> Self {
+        Self {
+            rodata: None,
+            data: None,
+            bss: None,
+            stack_size: DEFAULT_STACK_SIZE,
+        }
+    }
+}
+impl SectionLayout {
+    fn start(&self, section: Section) -> Option<usize> {
+        match section {
+            Section::Text => None,
+            Section::RoData => self.rodata,
+            Section::Data => self.data,
+            Section::Bss => self.bss,
+        }
+    }
+}
+
+/// Where the section of a single file was placed in memory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SectionPlacement {
+    pub section: Section,
+    pub file_name: String,
+    pub start: usize,
+    pub size: usize,
+}
+
+/// The initial contents of memory, loaded when the program is compiled.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryImage {
+    /// The .rodata and .data bytes of each file, as (address, bytes).
+    initialized: Vec<(usize, Vec<u8>)>,
+    /// The .bss address ranges, zero-initialized at reset.
+    zeroed: Vec<Range<usize>>,
+}
+impl MemoryImage {
+    /// Returns the blocks of bytes to write to memory as (address, bytes), in the order they are written.
+    /// The .bss sections are zeroed first, then the initialized sections are written.
+    pub fn blocks(&self) -> impl Iterator<Item = (usize, Vec<u8>)> + '_ {
+        self.zeroed
+            .iter()
+            .map(|range| (range.start, vec![0; range.len()]))
+            .chain(self.initialized.iter().cloned())
+    }
+    /// Writes the image to a buffer that holds the memory starting at the base address.
+    pub fn load(&self, memory: &mut [u8], base: usize) {
+        for (address, bytes) in self.blocks() {
+            memory[address - base..address - base + bytes.len()].copy_from_slice(&bytes);
+        }
+    }
+    /// The contiguous parts of the image as (address, bytes), in address order.
+    /// Blocks that overlap or touch are merged, the gaps between them are not stored.
+    pub fn contiguous_blocks(&self) -> Vec<(usize, Vec<u8>)> {
+        let mut ranges: Vec<Range<usize>> = self
+            .initialized
+            .iter()
+            .map(|(address, bytes)| *address..*address + bytes.len())
+            .chain(self.zeroed.iter().cloned())
+            .filter(|range| !range.is_empty())
+            .collect();
+        ranges.sort_unstable_by_key(|range| range.start);
+        let mut merged: Vec<Range<usize>> = Vec::new();
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        merged
+            .into_iter()
+            .map(|range| {
+                let mut memory = vec![0; range.len()];
+                // later blocks are written over earlier ones, the same as when the image is loaded
+                for (address, bytes) in self.blocks().filter(|(address, _)| range.contains(address))
+                {
+                    let offset = address - range.start;
+                    memory[offset..offset + bytes.len()].copy_from_slice(&bytes);
+                }
+                (range.start, memory)
+            })
+            .collect()
+    }
+    /// Adds bytes that are written over the sections, ex: the contents of a preloaded file.
+    pub fn add_bytes(&mut self, address: usize, bytes: Vec<u8>) {
+        self.initialized.push((address, bytes));
+    }
+    /// Adds a segment of an executable file, the bytes after the file contents are zeroed.
+    pub fn add_segment(&mut self, address: usize, bytes: Vec<u8>, size: usize) {
+        self.zeroed.push(address + bytes.len()..address + size);
+        self.initialized.push((address, bytes));
+    }
+}
+
+/// A label in the .rodata, .data, or .bss section.
+#[derive(Debug, Clone)]
+pub struct DataLabel {
+    pub name: String,
+    pub address: u32,
+    /// The line that defines the label.
+    pub line: SourceLine,
+}
+
+/// The result of the link step.
+pub struct LinkedData {
+    pub placements: Vec<SectionPlacement>,
+    /// The data labels defined in each file, in the same order as the files.
+    labels: Vec<Vec<DataLabel>>,
+    objects: Vec<ObjectFile>,
+    /// The start address of each section of each file, .text is always 0 since it is not placed in memory.
+    bases: Vec<[usize; 4]>,
+}
+impl LinkedData {
+    /// Returns the data labels defined in a file.
+    pub fn labels(&self, file_index: usize) -> &[DataLabel] {
+        &self.labels[file_index]
+    }
+    /// Returns the address and bytes placed by each data directive of a file, as (line index, address, bytes).
+    /// The values that use labels are only written after LinkedData::relocate.
+    pub fn directive_data(&self, file_index: usize) -> Vec<(usize, u32, &[u8])> {
+        let (object, base) = (&self.objects[file_index], &self.bases[file_index]);
+        object
+            .directives
+            .iter()
+            .map(|(line_index, section, range)| {
+                let address = base[section.index()] + range.start;
+                let bytes = &object.sections[section.index()][range.clone()];
+                (*line_index, address as u32, bytes)
+            })
+            .collect()
+    }
+    /// Writes the values of .byte, .hword, and .word directives, and returns the initial contents of memory.
+    /// The symbol function returns the value of a label used in the file with the given index.
+    pub fn relocate(
+        &mut self,
+        symbol: &dyn Fn(usize, &str) -> Option<i64>,
+    ) -> Result<MemoryImage, Vec<String>> {
+        let mut errors = CompileErr::new();
+        let mut image = MemoryImage::default();
+        for (file_index, (object, base)) in self.objects.iter_mut().zip(&self.bases).enumerate() {
+            for fixup in std::mem::take(&mut object.fixups) {
+                errors.update_location(&fixup.line);
+                // .equ/.set symbols defined before the directive, then labels
+                let value = match expression::evaluate(&fixup.expr, &|name| {
+                    fixup.line.symbol(name).or_else(|| symbol(file_index, name))
+                }) {
+                    Ok(value) => value,
+                    Err(err) => {
+                        errors.push_message(&err);
+                        continue;
+                    }
+                };
+                let bits = fixup.size as u32 * 8;
+                let (min, max) = (-(1i64 << (bits - 1)), (1i64 << bits) - 1);
+                if value < min || value > max {
+                    errors.push_message(&format!(
+                        "Value {} does not fit in {} bytes.",
+                        value, fixup.size
+                    ));
+                    continue;
+                }
+                let bytes = &mut object.sections[fixup.section.index()];
+                bytes[fixup.offset..fixup.offset + fixup.size]
+                    .copy_from_slice(&(value as u32).to_le_bytes()[..fixup.size]);
+            }
+            for section in Section::DATA_SECTIONS {
+                let index = section.index();
+                let start = base[index];
+                let bytes = &object.sections[index];
+                if bytes.is_empty() {
+                    continue;
+                }
+                if section == Section::Bss {
+                    image.zeroed.push(start..start + bytes.len());
+                } else {
+                    image.initialized.push((start, bytes.clone()));
+                }
+            }
+        }
+        errors.result()?;
+        Ok(image)
+    }
+}
+
+/// A value that is written once every label has an address.
+struct Fixup {
+    section: Section,
+    offset: usize,
+    size: usize,
+    expr: String,
+    line: SourceLine,
+}
+
+/// The data sections of a single file, before they are placed in memory.
+#[derive(Default)]
+struct ObjectFile {
+    name: String,
+    /// .text, .rodata, .data, and .bss contents. The .text section is always empty, since instructions are not stored in memory.
+    /// The .bss contents are always zero, only their size is used.
+    sections: [Vec<u8>; 4],
+    /// The largest alignment requested in each section.
+    alignments: [usize; 4],
+    /// Data labels, their section, and their offset into the section.
+    labels: Vec<(String, Section, usize, SourceLine)>,
+    fixups: Vec<Fixup>,
+    /// The bytes placed by each data directive, as (line index, section, range in the section).
+    directives: Vec<(usize, Section, Range<usize>)>,
+}
+impl ObjectFile {
+    /// Collects the data sections of a file.
+    /// A .string in the .text section is stored in the .rodata section, and the label on the line before it gets its address.
+    fn assemble(source: &SourceFile, errors: &mut CompileErr) -> Self {
+        let mut object = ObjectFile {
+            name: source.name.clone(),
+            alignments: [4; 4],
+            ..Default::default()
+        };
+        // the label on the previous line in the .text section.
+        let mut text_label: Option<(String, &SourceLine)> = None;
+        for (line_index, (section, line)) in sections(&source.lines).enumerate() {
+            errors.update_location(line);
+            let text = line.text.as_str();
+            if text.is_empty() {
+                continue;
+            }
+            let (directive, args) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+            let directive = directive.to_lowercase();
+            let label_before = text_label.take();
+
+            if let Some(Err(err)) = Section::from_directive(text) {
+                errors.push_message(&err);
+            } else if section == Section::Text {
+                if let Some(label) = text.strip_suffix(':') {
+                    text_label = Some((label.trim().to_string(), line));
+                } else if directive == ".string" {
+                    match label_before {
+                        Some((label, label_line)) => {
+                            let offset = object.sections[Section::RoData.index()].len();
+                            object.labels.push((
+                                label,
+                                Section::RoData,
+                                offset,
+                                label_line.clone(),
+                            ));
+                            object.add_data(
+                                line_index,
+                                line,
+                                Section::RoData,
+                                &directive,
+                                args,
+                                errors,
+                            );
+                        }
+                        None => {
+                            errors.push_message(".string variable should have a label beforehand.")
+                        }
+                    }
+                } else if is_align_directive(&directive) {
+                    // instructions are always aligned to 2 bytes, code is never padded to a larger alignment.
+                    match alignment(&directive, args.trim(), line) {
+                        Ok(alignment) if alignment > 2 => errors.push_message(&format!(
+                            "{} cannot align instructions to {} bytes, only to 2 bytes. Use it in the .rodata, .data, or .bss section.",
+                            directive, alignment
+                        )),
+                        Ok(_) => (),
+                        Err(err) => errors.push_message(&err),
+                    }
+                } else if is_data_directive(&directive) {
+                    errors.push_message(&format!(
+                        "{} must be in the .rodata, .data, or .bss section.",
+                        directive
+                    ));
+                }
+            } else if let Some(label) = text.strip_suffix(':') {
+                let offset = object.sections[section.index()].len();
+                object
+                    .labels
+                    .push((label.trim().to_string(), section, offset, line.clone()));
+            } else if is_data_directive(&directive) {
+                object.add_data(line_index, line, section, &directive, args, errors);
+            }
+        }
+        object
+    }
+    /// Appends the data of a directive to a section, and records the bytes it placed.
+    fn add_data(
+        &mut self,
+        line_index: usize,
+        line: &SourceLine,
+        section: Section,
+        directive: &str,
+        args: &str,
+        errors: &mut CompileErr,
+    ) {
+        let start = self.sections[section.index()].len();
+        if let Err(err) = self.data_directive(section, directive, args.trim(), line) {
+            errors.push_message(&err);
+        }
+        let end = self.sections[section.index()].len();
+        if end > start {
+            self.directives.push((line_index, section, start..end));
+        }
+    }
+    /// Appends the data of a directive to a section.
+    fn data_directive(
+        &mut self,
+        section: Section,
+        directive: &str,
+        args: &str,
+        line: &SourceLine,
+    ) -> Result<(), String> {
+        let index = section.index();
+        let bytes = &mut self.sections[index];
+        match directive {
+            ".space" | ".skip" => {
+                let args = preprocess::split_arguments(args, false);
+                let size = evaluate_constant(args.first().map_or("", String::as_str), line)?;
+                let fill = match args.get(1) {
+                    Some(fill) => evaluate_constant(fill, line)?,
+                    None => 0,
+                };
+                if size < 0 {
+                    return Err(format!("{} size cannot be negative.", directive));
+                }
+                if fill != 0 && section == Section::Bss {
+                    return Err("The .bss section can only contain zeros.".into());
+                }
+                bytes.resize(bytes.len() + size as usize, fill as u8);
+            }
+            _ if is_align_directive(directive) => {
+                let alignment = alignment(directive, args, line)?;
+                bytes.resize((bytes.len() + alignment - 1) / alignment * alignment, 0);
+                self.alignments[index] = self.alignments[index].max(alignment);
+            }
+            _ if section == Section::Bss => {
+                return Err(format!(
+                    "{} cannot be used in the .bss section, use .space instead.",
+                    directive
+                ));
+            }
+            ".ascii" | ".asciz" | ".string" => {
+                for string in preprocess::split_arguments(args, false) {
+                    bytes.extend(parse_string(&string)?);
+                    if directive != ".ascii" {
+                        bytes.push(0);
+                    }
+                }
+            }
+            _ => {
+                let size = match directive {
+                    ".byte" => 1,
+                    ".hword" | ".short" => 2,
+                    _ => 4,
+                };
+                for expr in preprocess::split_arguments(args, false) {
+                    if expr.is_empty() {
+                        return Err(format!("{} is missing a value.", directive));
+                    }
+                    self.fixups.push(Fixup {
+                        section,
+                        offset: bytes.len(),
+                        size,
+                        expr,
+                        line: line.clone(),
+                    });
+                    bytes.resize(bytes.len() + size, 0);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the number of bytes an .align, .p2align, or .balign directive aligns to.
+fn alignment(directive: &str, args: &str, line: &SourceLine) -> Result<usize, String> {
+    let value = evaluate_constant(args, line)?;
+    if directive != ".balign" {
+        // .align n aligns to 2^n bytes
+        if !(0..=10).contains(&value) {
+            return Err(format!("{} must be between 0 and 10.", directive));
+        }
+        Ok(1usize << value)
+    } else {
+        if value <= 0 || value & (value - 1) != 0 {
+            return Err(".balign must be a power of 2.".into());
+        }
+        Ok(value as usize)
+    }
+}
+
+/// Evaluates an expression that cannot contain labels, only the .equ/.set symbols defined before the line.
+fn evaluate_constant(expr: &str, line: &SourceLine) -> Result<i64, String> {
+    expression::evaluate(expr, &|name| line.symbol(name))
+}
+
+/// Converts a string literal in quotes to bytes. Supports the escapes \n \t \r \0 \\ \"
+fn parse_string(literal: &str) -> Result<Vec<u8>, String> {
+    let content = literal
+        .strip_prefix('"')
+        .and_then(|literal| literal.strip_suffix('"'))
+        .ok_or_else(|| format!("{} must be a string in quotes.", literal))?;
+    let mut bytes: Vec<u8> = Vec::new();
+    let mut chars = content.chars();
+    while let Some(c) = chars.next() {
+        let c = if c == '\\' {
+            match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some('0') => '\0',
+                Some(c @ ('\\' | '"')) => c,
+                Some(c) => return Err(format!("Unknown escape sequence \"\\{}\".", c)),
+                None => return Err("String cannot end with \\".into()),
+            }
+        } else {
+            c
+        };
+        let mut buffer = [0u8; 4];
+        bytes.extend(c.encode_utf8(&mut buffer).bytes());
+    }
+    Ok(bytes)
+}
+
+/// Lays out the .rodata, .data, and .bss sections of every file in the memory range, and gives every data label an address.
+/// Sections of the same kind are placed one after another in file order, aligned to 4 bytes.
+/// The end of the memory range is reserved for the stack, as set by the layout.
+/// Values that use labels are written by LinkedData::relocate, once the labels of every file are known.
+pub fn link(
+    sources: &[SourceFile],
+    layout: &SectionLayout,
+    memory: Range<usize>,
+) -> Result<LinkedData, Vec<String>> {
+    let mut errors = CompileErr::new();
+    let objects: Vec<ObjectFile> = sources
+        .iter()
+        .map(|source| ObjectFile::assemble(source, &mut errors))
+        .collect();
+    errors.result()?;
+
+    // place the sections
+    let mut errors: Vec<String> = Vec::new();
+    let mut placements: Vec<SectionPlacement> = Vec::new();
+    // the start address of each section of each file.
+    let mut bases: Vec<[usize; 4]> = vec![[0; 4]; objects.len()];
+    let mut section_ranges: Vec<(Section, Range<usize>)> = Vec::new();
+    let stack = memory
+        .end
+        .saturating_sub(layout.stack_size)
+        .max(memory.start)..memory.end;
+    let mut next = memory.start;
+    for section in Section::DATA_SECTIONS {
+        let index = section.index();
+        let start = layout.start(section).unwrap_or(next);
+        let mut address = start;
+        for (object, base) in objects.iter().zip(bases.iter_mut()) {
+            let alignment = object.alignments[index];
+            address = (address + alignment - 1) / alignment * alignment;
+            base[index] = address;
+            let size = object.sections[index].len();
+            if size > 0 {
+                placements.push(SectionPlacement {
+                    section,
+                    file_name: object.name.clone(),
+                    start: address,
+                    size,
+                });
+            }
+            address += size;
+        }
+        if start < memory.start || address > memory.end {
+            errors.push(format!(
+                "The {} section ({}..{}) is outside the data memory region ({}..{}).",
+                section.name(),
+                start,
+                address,
+                memory.start,
+                memory.end
+            ));
+        } else if address > stack.start {
+            errors.push(format!(
+                "The {} section ({}..{}) reaches into the stack ({}..{}), reduce the layout's 'stack_size' or move the section.",
+                section.name(),
+                start,
+                address,
+                stack.start,
+                stack.end
+            ));
+        }
+        for (other, range) in &section_ranges {
+            if start < range.end && range.start < address {
+                errors.push(format!(
+                    "The {} section ({}..{}) overlaps the {} section ({}..{}).",
+                    section.name(),
+                    start,
+                    address,
+                    other.name(),
+                    range.start,
+                    range.end
+                ));
+            }
+        }
+        if address > start {
+            section_ranges.push((section, start..address));
+        }
+        next = (address + 3) / 4 * 4;
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    let labels = objects
+        .iter()
+        .zip(&bases)
+        .map(|(object, base)| {
+            object
+                .labels
+                .iter()
+                .map(|(name, section, offset, line)| DataLabel {
+                    name: name.clone(),
+                    address: (base[section.index()] + offset) as u32,
+                    line: line.clone(),
+                })
+                .collect()
+        })
+        .collect();
+    Ok(LinkedData {
+        placements,
+        labels,
+        objects,
+        bases,
+    })
+}
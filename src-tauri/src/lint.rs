@@ -0,0 +1,248 @@
+/// Contains the warnings pass that runs after a successful compile.
+/// Warnings never fail the build, they only point out code that is most likely a mistake.
+use crate::arm7::{ConditionCode, Program};
+use crate::backend_api::compile::{self, Config};
+use crate::encode;
+use crate::link::{self, Section};
+use crate::preprocess::{self, SourceFile, SourceLine};
+use regex::Regex;
+use std::collections::HashSet;
+
+/// Every warning the lint pass can report.
+/// The kebab-case name is used to suppress a warning in config.json, ex: "suppress_warnings": ["unused-label"]
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum Lint {
+    /// An instruction is followed by .string data without a branch in between.
+    FallThroughData,
+    /// An instruction other than a branch is conditional outside an IT block, its machine code has no condition.
+    ConditionalOutsideIt,
+    /// The .w extension does not change how the instruction is compiled.
+    MeaninglessWide,
+    /// Instructions after an unconditional branch that no label points to.
+    UnreachableCode,
+    /// A label that is never branched to, loaded, or exported.
+    UnusedLabel,
+}
+impl Lint {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Self::ConditionalOutsideIt => "conditional-outside-it",
+            Self::FallThroughData => "fall-through-data",
+            Self::MeaninglessWide => "meaningless-wide",
+            Self::UnreachableCode => "unreachable-code",
+            Self::UnusedLabel => "unused-label",
+        }
+    }
+}
+
+/// Stores compile time warnings, and the warnings that were suppressed in the configuration.
+pub struct CompileWarnings {
+    warnings: Vec<String>,
+    suppressed: HashSet<Lint>,
+}
+impl CompileWarnings {
+    pub fn new(suppressed: &[Lint]) -> Self {
+        CompileWarnings {
+            warnings: Vec::new(),
+            suppressed: suppressed.iter().copied().collect(),
+        }
+    }
+    /// Appends a warning message, unless the warning is suppressed.
+    pub fn push(&mut self, lint: Lint, file_name: &str, line_number: usize, message: &str) {
+        if !self.suppressed.contains(&lint) {
+            self.warnings.push(format!(
+                "\"{}\" line {}: {} [{}]",
+                file_name,
+                line_number,
+                message,
+                lint.name()
+            ));
+        }
+    }
+    pub fn result(self) -> Vec<String> {
+        self.warnings
+    }
+}
+
+/// Labels where the program starts, which are used even if nothing refers to them.
+const ENTRY_LABELS: [&str; 2] = ["main", "_start"];
+
+/// A label definition, used to find unused labels.
+struct LabelDef {
+    name: String,
+    file_name: String,
+    line_number: usize,
+}
+
+/// Runs every lint over all preprocessed files.
+/// Should only be called after the program compiled successfully.
+pub fn lint(config: &Config, sources: &[SourceFile], program: &Program) -> Vec<String> {
+    let mut linter = Linter::new(config.get_suppressed_warnings());
+    for source in sources {
+        linter.lint_file(&source.lines, program);
+    }
+    linter.finish()
+}
+
+/// Collects warnings across files. Labels are checked once all files are linted, since they can be used across files.
+pub struct Linter {
+    warnings: CompileWarnings,
+    definitions: Vec<LabelDef>,
+    references: HashSet<String>,
+}
+impl Linter {
+    pub fn new(suppressed: &[Lint]) -> Self {
+        Linter {
+            warnings: CompileWarnings::new(suppressed),
+            definitions: Vec::new(),
+            references: HashSet::new(),
+        }
+    }
+    /// Lints a single file.
+    pub fn lint_file(&mut self, lines: &[SourceLine], program: &Program) {
+        let re_word = Regex::new(r"\b[a-zA-Z_]\w*").unwrap();
+        // line number of the last instruction that can continue into the next line.
+        let mut falls_through: Option<usize> = None;
+        // line number of the last unconditional branch, reset by a label.
+        let mut unconditional_branch: Option<usize> = None;
+        // number of instructions left in the current IT block.
+        let mut it_remaining = 0usize;
+
+        for source_line in lines {
+            let (file_name, line_number) = (
+                source_line.location.file_name.as_str(),
+                source_line.location.line_number,
+            );
+            let original_line = source_line.text.as_str();
+            let line = original_line.to_lowercase();
+
+            if line.is_empty() {
+                continue;
+            }
+            if line.ends_with(':') {
+                let name = original_line.trim_end_matches(':').trim();
+                // numeric local labels are often left unused, ex: "1:"
+                if !preprocess::is_numeric_label(name) {
+                    self.definitions.push(LabelDef {
+                        name: name.to_string(),
+                        file_name: file_name.to_string(),
+                        line_number,
+                    });
+                }
+                unconditional_branch = None;
+                continue;
+            }
+            if line.starts_with(".string") {
+                if let Some(instruction_line) = falls_through.take() {
+                    self.warnings.push(
+                        Lint::FallThroughData,
+                        file_name,
+                        line_number,
+                        &format!("Execution can fall through from line {} into .string data, add a branch before the data.", instruction_line),
+                    );
+                }
+                continue;
+            }
+            if Section::from_directive(&line).is_some() {
+                // code in another section does not continue into this one.
+                falls_through = None;
+                unconditional_branch = None;
+                continue;
+            }
+            if line.starts_with('.') {
+                // labels stored in data count as used.
+                let directive = line.split_whitespace().next().unwrap_or("");
+                if link::is_data_directive(directive) {
+                    let args = &original_line[directive.len()..];
+                    self.references
+                        .extend(re_word.find_iter(args).map(|m| m.as_str().to_string()));
+                }
+                // exported labels count as used.
+                if matches!(directive, ".global" | ".globl" | ".weak") {
+                    let labels = original_line[directive.len()..].split(',');
+                    self.references
+                        .extend(labels.map(|label| label.trim().to_string()));
+                }
+                continue;
+            }
+            // collect label references, any operand can name a label, ex: "cbz r0, done" or "adr r1, table"
+            let operands = original_line
+                .split_once(char::is_whitespace)
+                .map_or("", |(_, operands)| operands);
+            self.references
+                .extend(re_word.find_iter(operands).map(|m| m.as_str().to_string()));
+            if let Some(branch_line) = unconditional_branch.take() {
+                self.warnings.push(
+                    Lint::UnreachableCode,
+                    file_name,
+                    line_number,
+                    &format!(
+                        "Unreachable code after the unconditional branch on line {}.",
+                        branch_line
+                    ),
+                );
+            }
+            if compile::is_if_then_block(&line) {
+                it_remaining = line.split_whitespace().next().map_or(0, |it| it.len() - 1);
+                falls_through = Some(line_number);
+                continue;
+            }
+            let (mnemonic, extension) = match program.find_mnemonic(&line) {
+                Some(found) => found,
+                None => continue,
+            };
+            let in_it_block = it_remaining > 0;
+            it_remaining = it_remaining.saturating_sub(1);
+
+            let is_conditional = extension.cc.map_or(false, |cc| cc != ConditionCode::AL);
+            if is_conditional && !in_it_block && mnemonic != "b" {
+                self.warnings.push(
+                    Lint::ConditionalOutsideIt,
+                    file_name,
+                    line_number,
+                    &format!(
+                        "\"{}\" is conditional outside an IT block, its machine code runs unconditionally. Add an IT instruction before it.",
+                        line.split_whitespace().next().unwrap_or(&mnemonic)
+                    ),
+                );
+            }
+            // instructions that only have a 32-bit encoding are always wide.
+            if extension.w && encode::is_always_wide(&mnemonic, &line) {
+                self.warnings.push(
+                    Lint::MeaninglessWide,
+                    file_name,
+                    line_number,
+                    &format!("The .w extension has no effect on \"{}\".", mnemonic),
+                );
+            }
+            let is_unconditional_branch = mnemonic == "b"
+                && !in_it_block
+                && extension.cc.map_or(true, |cc| cc == ConditionCode::AL);
+            if is_unconditional_branch {
+                unconditional_branch = Some(line_number);
+                falls_through = None;
+            } else {
+                falls_through = Some(line_number);
+            }
+        }
+    }
+    /// Warns about labels that are never referenced in any file, and returns all warnings.
+    pub fn finish(mut self) -> Vec<String> {
+        let mut reported: HashSet<(&str, &str)> = HashSet::new();
+        for def in &self.definitions {
+            if !self.references.contains(&def.name)
+                && !ENTRY_LABELS.contains(&def.name.as_str())
+                && reported.insert((def.name.as_str(), def.file_name.as_str()))
+            {
+                self.warnings.push(
+                    Lint::UnusedLabel,
+                    &def.file_name,
+                    def.line_number,
+                    &format!("Label \"{}\" is never used.", def.name),
+                );
+            }
+        }
+        self.warnings.result()
+    }
+}
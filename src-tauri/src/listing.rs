@@ -0,0 +1,152 @@
+/// Contains the listing file generator, which shows the address, machine code, and cycle estimate of every source line.
+/// Macro expansions and included lines are listed under the line that produced them, marked with "+".
+use crate::arm7::{Labels, ListedInstruction, Program, SymbolValue};
+use crate::link::LinkedData;
+use crate::preprocess::SourceFile;
+use std::collections::VecDeque;
+
+/// Number of data bytes shown in a row.
+const BYTES_PER_ROW: usize = 4;
+/// Rows shown for a single data directive, longer data is cut off.
+const MAX_DATA_ROWS: usize = 4;
+
+/// The machine code or data of a source line.
+struct Row {
+    address: u32,
+    code: String,
+    cycles: &'static str,
+    /// Shown instead of the source text, used for literal pool entries.
+    comment: Option<String>,
+}
+
+/// Builds the listing of a file. text is the file contents before preprocessing.
+/// rows is the output of Program::listed_instructions, the rows of this file are removed from its front.
+pub fn listing(
+    source: &SourceFile,
+    text: &str,
+    file_index: usize,
+    rows: &mut VecDeque<ListedInstruction>,
+    program: &Program,
+    linked: &LinkedData,
+    labels: &Labels,
+) -> String {
+    // the rows of each preprocessed line
+    let mut line_rows: Vec<Vec<Row>> = source.lines.iter().map(|_| Vec::new()).collect();
+    for (index, line) in source.lines.iter().enumerate() {
+        // the same lines are skipped when compiling
+        let line_text = &line.text;
+        if line_text.is_empty() || line_text.ends_with(':') || line_text.starts_with('.') {
+            continue;
+        }
+        let location = &line.location;
+        let is_next = rows.front().map_or(false, |row| {
+            row.file_name == location.file_name && row.line_number == location.line_number
+        });
+        if !is_next {
+            continue;
+        }
+        let row = rows.pop_front().unwrap();
+        line_rows[index].push(Row {
+            address: row.address,
+            code: row.machine_code,
+            cycles: row.cycles,
+            comment: None,
+        });
+        if let Some((address, value)) = row.literal {
+            line_rows[index].push(Row {
+                address,
+                code: format!("{:08x}", value),
+                cycles: "",
+                comment: Some(format!(".word {:#010x}  @ literal pool", value)),
+            });
+        }
+    }
+    for (index, address, bytes) in linked.directive_data(file_index) {
+        for (row, chunk) in bytes.chunks(BYTES_PER_ROW).take(MAX_DATA_ROWS).enumerate() {
+            line_rows[index].push(Row {
+                address: address + (row * BYTES_PER_ROW) as u32,
+                code: chunk.iter().map(|byte| format!("{:02x}", byte)).collect(),
+                cycles: "",
+                comment: None,
+            });
+        }
+    }
+
+    // group the rows by the line of this file that produced them, ex: the macro invocation
+    let text_lines: Vec<&str> = text.lines().collect();
+    let mut direct: Vec<Vec<Row>> = text_lines.iter().map(|_| Vec::new()).collect();
+    let mut expansions: Vec<Vec<(&str, Vec<Row>)>> =
+        text_lines.iter().map(|_| Vec::new()).collect();
+    let mut outer = 1;
+    for (line, rows) in source.lines.iter().zip(line_rows) {
+        if let Some(location) = line
+            .locations()
+            .filter(|location| location.file_name == source.name)
+            .last()
+        {
+            outer = location.line_number.min(text_lines.len()).max(1);
+        }
+        if line.location.file_name != source.name || !line.backtrace.is_empty() {
+            expansions[outer - 1].push((&line.text, rows));
+        } else {
+            direct[outer - 1].extend(rows);
+        }
+    }
+
+    let mut output = format!(
+        "{}\n\n Line  Address  Code       Cyc  Source\n",
+        source.name
+    );
+    for (index, (rows, expanded)) in direct.into_iter().zip(expansions).enumerate() {
+        let mut rows = rows.into_iter();
+        let line_number = (index + 1).to_string();
+        output += &format_row(&line_number, rows.next().as_ref(), text_lines[index]);
+        output += &format_rows(rows);
+        for (line, rows) in expanded {
+            let mut rows = rows.into_iter();
+            output += &format_row("", rows.next().as_ref(), &format!("+   {}", line));
+            output += &format_rows(rows);
+        }
+    }
+
+    output += "\nSymbols\n\n Name                 Address  Binding  Kind      Defined\n";
+    for symbol in labels.file_symbols(file_index) {
+        let kind = match symbol.value {
+            SymbolValue::Code(_) if symbol.function => "function",
+            SymbolValue::Code(_) => "code",
+            SymbolValue::Data(_) => "data",
+        };
+        output += &format!(
+            " {:<20} {:08x} {:<8} {:<9} {}\n",
+            symbol.name,
+            program.symbol_address(symbol),
+            symbol.binding.name(),
+            kind,
+            symbol.line.location
+        );
+    }
+    output
+}
+
+/// Formats the rows after the first row of a line, which have no source text.
+fn format_rows(rows: impl Iterator<Item = Row>) -> String {
+    rows.map(|row| format_row("", Some(&row), row.comment.as_deref().unwrap_or("")))
+        .collect()
+}
+
+/// Formats a row of the listing: line number, address, machine code, cycles, and source text.
+fn format_row(line_number: &str, row: Option<&Row>, source: &str) -> String {
+    let (address, code, cycles) = match row {
+        Some(row) => (
+            format!("{:08x}", row.address),
+            row.code.as_str(),
+            row.cycles,
+        ),
+        None => (String::new(), "", ""),
+    };
+    let row = format!(
+        "{:>5}  {:<8} {:<10} {:>3}  {}",
+        line_number, address, code, cycles, source
+    );
+    format!("{}\n", row.trim_end())
+}
@@ -40,10 +40,15 @@ fn main() {
             get_file_content,
             write_file,
             backend_api::compile,
+            backend_api::load_executable,
             backend_api::run,
             backend_api::debug_run,
             backend_api::display_cpu,
             backend_api::display_memory,
+            backend_api::machine_code,
+            backend_api::disassemble,
+            backend_api::export_elf,
+            backend_api::export_memory,
             backend_api::kill_process,
         ])
         .run(tauri::generate_context!())
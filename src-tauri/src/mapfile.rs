@@ -0,0 +1,122 @@
+/// Contains the linker map generator, which shows where the sections and labels of every file were placed.
+/// Code is placed at encode::TEXT_ADDRESS, the data sections are placed in the data region of memory.
+use crate::arm7::{Labels, Program};
+use crate::encode;
+use crate::link::{LinkedData, Section};
+use crate::memory::MemoryMap;
+
+/// Builds the map file of a linked program.
+/// Should only be called after the program was encoded.
+pub fn map_file(
+    program: &Program,
+    linked: &LinkedData,
+    labels: &Labels,
+    memory_map: &MemoryMap,
+) -> String {
+    let used = |section: Option<Section>| -> usize {
+        linked
+            .placements
+            .iter()
+            .filter(|placement| section.map_or(true, |section| placement.section == section))
+            .map(|placement| placement.size)
+            .sum()
+    };
+    let code_end = encode::TEXT_ADDRESS + program.code_image().len() as u32;
+
+    let mut output = String::from("Memory Configuration\n\n");
+    output += &format!(
+        "{:<16} {:<10} {:<10} {:<10} Usage   Attributes\n",
+        "Name", "Origin", "Length", "Used"
+    );
+    let data_region = memory_map.data_region();
+    for region in &memory_map.regions {
+        let usage = |size: usize| format!("{:>5.1}%", size as f64 * 100.0 / region.length as f64);
+        let mut size = 0;
+        if Some(region) == data_region {
+            size += used(None);
+        }
+        if region.contains(encode::TEXT_ADDRESS, 1) {
+            size += (code_end - encode::TEXT_ADDRESS) as usize;
+        }
+        output += &format!(
+            "{:<16} {:#010x} {:#010x} {:#010x} {}  {}\n",
+            region.name,
+            region.origin,
+            region.length,
+            size,
+            usage(size),
+            region.access
+        );
+        if Some(region) != data_region {
+            continue;
+        }
+        for section in Section::DATA_SECTIONS {
+            let size = used(Some(section));
+            output += &format!(
+                "  {:<14} {:<21} {:#010x} {}\n",
+                section.name(),
+                "",
+                size,
+                usage(size)
+            );
+        }
+    }
+
+    output += "\nMemory Map\n\n";
+    output += &row(".text", encode::TEXT_ADDRESS, code_end);
+    for (file_name, range) in program.text_ranges() {
+        output += &row(&format!(" {}", file_name), range.start, range.end);
+    }
+    let literal_pool = encode::literal_pool_address(program.builtins_address());
+    output += &row(" (built-in)", program.builtins_address(), literal_pool);
+    if literal_pool < code_end {
+        output += &row(" (literal pool)", literal_pool, code_end);
+    }
+    for section in Section::DATA_SECTIONS {
+        let placements: Vec<_> = linked
+            .placements
+            .iter()
+            .filter(|placement| placement.section == section)
+            .collect();
+        let (first, last) = match (placements.first(), placements.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => continue,
+        };
+        output += &row(
+            section.name(),
+            first.start as u32,
+            (last.start + last.size) as u32,
+        );
+        for placement in placements {
+            output += &row(
+                &format!(" {}", placement.file_name),
+                placement.start as u32,
+                (placement.start + placement.size) as u32,
+            );
+        }
+    }
+
+    output += "\nSymbols\n\n";
+    output += &format!("{:<10} {:<20} {:<8} File\n", "Address", "Name", "Binding");
+    let mut symbols: Vec<_> = labels
+        .symbols()
+        .into_iter()
+        .map(|symbol| (program.symbol_address(symbol), symbol))
+        .collect();
+    symbols.sort_by(|(a, x), (b, y)| a.cmp(b).then_with(|| x.name.cmp(&y.name)));
+    for (address, symbol) in symbols {
+        output += &format!(
+            "{:#010x} {:<20} {:<8} {}\n",
+            address,
+            symbol.name,
+            symbol.binding.name(),
+            symbol.line.location.file_name
+        );
+    }
+    output
+}
+
+/// Formats a section or input file of the memory map: name, start address, and size.
+fn row(name: &str, start: u32, end: u32) -> String {
+    format!("{:<16} {:#010x} {:#010x}\n", name, start, end - start)
+}
@@ -0,0 +1,388 @@
+/// Contains the memory map, the named regions of the address space read from config.json, and the simulated memory.
+/// Accesses outside every region, and writes to read-only regions, fault instead of changing memory.
+/// Memory is stored in pages that are allocated when they are written, so regions can be anywhere in the address space.
+use crate::arm7::{MemSize, MEMORY_SIZE};
+use crate::expression;
+use crate::link::MemoryImage;
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Range;
+
+/// The access permissions of a region, written like a linker script, ex: "rx" or "rw"
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Access {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+impl TryFrom<String> for Access {
+    type Error = String;
+    fn try_from(text: String) -> Result<Self, String> {
+        let mut access = Access {
+            read: false,
+            write: false,
+            execute: false,
+        };
+        for c in text.to_lowercase().chars() {
+            match c {
+                'r' => access.read = true,
+                'w' => access.write = true,
+                'x' => access.execute = true,
+                _ => {
+                    return Err(format!(
+                        "Unknown access \"{}\", use the letters r, w, and x.",
+                        text
+                    ))
+                }
+            }
+        }
+        Ok(access)
+    }
+}
+impl std::fmt::Display for Access {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let flag = |set: bool, c: char| if set { c } else { '-' };
+        write!(
+            f,
+            "{}{}{}",
+            flag(self.read, 'r'),
+            flag(self.write, 'w'),
+            flag(self.execute, 'x')
+        )
+    }
+}
+
+/// A named range of the address space.
+/// ex: { "name": "SRAM", "origin": "0x20000000", "length": 4096, "access": "rw" }
+#[derive(serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Region {
+    pub name: String,
+    /// A number, or a string such as "0x08000000"
+    #[serde(deserialize_with = "address")]
+    pub origin: u32,
+    #[serde(deserialize_with = "address")]
+    pub length: u32,
+    pub access: Access,
+}
+impl Region {
+    /// The addresses of the region. The end is a u64, so a region can end at the top of the address space.
+    pub fn range(&self) -> Range<u64> {
+        self.origin as u64..self.origin as u64 + self.length as u64
+    }
+    /// Returns true if size bytes starting at address are all inside the region.
+    pub fn contains(&self, address: u32, size: usize) -> bool {
+        let range = self.range();
+        range.start <= address as u64 && address as u64 + size as u64 <= range.end
+    }
+}
+
+/// Reads an address or size, which is a number or a string with a hex or binary number.
+fn address<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum Number {
+        Value(u32),
+        Text(String),
+    }
+    let value = match serde::Deserialize::deserialize(deserializer)? {
+        Number::Value(value) => value as i64,
+        Number::Text(text) => {
+            expression::parse_number(text.trim()).map_err(serde::de::Error::custom)?
+        }
+    };
+    u32::try_from(value)
+        .map_err(|_| serde::de::Error::custom(format!("{} is not a 32-bit address.", value)))
+}
+
+/// The regions of memory, read from the "memory" option in config.json.
+/// Defaults to a single read-write region of MEMORY_SIZE bytes at address 0.
+#[derive(serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct MemoryMap {
+    pub regions: Vec<Region>,
+}
+impl Default for MemoryMap {
+    fn default() -> Self {
+        MemoryMap {
+            regions: vec![Region {
+                name: "RAM".into(),
+                origin: 0,
+                length: MEMORY_SIZE as u32,
+                access: Access {
+                    read: true,
+                    write: true,
+                    execute: false,
+                },
+            }],
+        }
+    }
+}
+impl MemoryMap {
+    /// Checks that the regions have a size, do not overlap, and that the data sections have a writable region.
+    pub fn check(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        for (index, region) in self.regions.iter().enumerate() {
+            if region.length == 0 {
+                errors.push(format!("The memory region \"{}\" is empty.", region.name));
+            }
+            if region.range().end > 1 << 32 {
+                errors.push(format!(
+                    "The memory region \"{}\" ends past the end of the address space.",
+                    region.name
+                ));
+            }
+            if let Some(other) = self.regions[..index].iter().find(|other| {
+                region.range().start < other.range().end && other.range().start < region.range().end
+            }) {
+                errors.push(format!(
+                    "The memory region \"{}\" overlaps the region \"{}\".",
+                    region.name, other.name
+                ));
+            }
+        }
+        if self.data_region().is_none() {
+            errors.push(
+                "The memory map needs a read-write region for the data sections and the stack."
+                    .into(),
+            );
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+    /// The first read-write region, which holds the .rodata, .data, and .bss sections, and the stack.
+    pub fn data_region(&self) -> Option<&Region> {
+        self.regions
+            .iter()
+            .find(|region| region.access.read && region.access.write)
+    }
+    /// The initial SP, the end of the data region. The stack is full descending.
+    pub fn stack_top(&self) -> u32 {
+        self.data_region()
+            .map_or(0, |region| region.range().end.min(u32::MAX as u64) as u32)
+    }
+    /// Returns the region that contains size bytes starting at address.
+    pub fn region_of(&self, address: u32, size: usize) -> Option<&Region> {
+        self.regions
+            .iter()
+            .find(|region| region.contains(address, size))
+    }
+    /// Returns true if every byte of an address range is inside a region, the range can span adjacent regions.
+    pub fn is_mapped(&self, address: u32, size: usize) -> bool {
+        let (start, end) = (address as u64, address as u64 + size as u64);
+        let mapped: u64 = self
+            .regions
+            .iter()
+            .map(|region| {
+                region
+                    .range()
+                    .end
+                    .min(end)
+                    .saturating_sub(region.range().start.max(start))
+            })
+            .sum();
+        mapped == end - start
+    }
+    /// Checks that code placed at an address range can be run, if a region contains it.
+    pub fn check_code(&self, range: Range<u32>) -> Result<(), String> {
+        match self.regions.iter().find(|region| {
+            (range.start as u64) < region.range().end && region.range().start < range.end as u64
+        }) {
+            Some(region) if !region.access.execute => Err(format!(
+                "The code at {:#010x} is in the memory region \"{}\", which is not executable.",
+                range.start, region.name
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Size of a page of memory in bytes.
+pub const PAGE_SIZE: usize = 4096;
+/// An address is split into a page number and an offset into the page.
+const PAGE_BITS: u32 = 12;
+
+/// The simulated memory of the whole 32-bit address space, which stores the regions of a memory map in pages.
+/// A page is allocated when it is first written, pages that were never written read as zero.
+#[derive(Debug)]
+pub struct Memory {
+    map: MemoryMap,
+    /// The allocated pages, by page number.
+    pages: HashMap<u32, Box<[u8; PAGE_SIZE]>>,
+    /// The pages written since the last call to Memory::take_changed_pages, None if every page may have changed.
+    changed: Option<BTreeSet<u32>>,
+}
+impl Memory {
+    pub fn new(map: MemoryMap) -> Self {
+        Memory {
+            map,
+            pages: HashMap::new(),
+            changed: None,
+        }
+    }
+    pub fn map(&self) -> &MemoryMap {
+        &self.map
+    }
+    /// Checks an access, returns a fault if the access is outside every region or is not permitted.
+    fn access(&self, address: u32, size: MemSize, write: bool) -> Result<(), String> {
+        let region = self.map.region_of(address, size.bytes()).ok_or_else(|| {
+            match self.map.region_of(address, 1) {
+                Some(region) => format!(
+                    "Address \"{:#010x}\" accesses {} bytes past the end of the memory region \"{}\" ({:#010x}..{:#010x}).",
+                    address,
+                    size.bytes(),
+                    region.name,
+                    region.range().start,
+                    region.range().end
+                ),
+                None => format!(
+                    "Address \"{:#010x}\" is not in any memory region, the regions are: {}",
+                    address,
+                    self.map
+                        .regions
+                        .iter()
+                        .map(|region| format!(
+                            "{} ({:#010x}..{:#010x})",
+                            region.name,
+                            region.range().start,
+                            region.range().end
+                        ))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            }
+        })?;
+        if write && !region.access.write {
+            return Err(format!(
+                "Address \"{:#010x}\" is in the read-only memory region \"{}\", and cannot be written.",
+                address, region.name
+            ));
+        }
+        if !write && !region.access.read {
+            return Err(format!(
+                "Address \"{:#010x}\" is in the memory region \"{}\", which cannot be read.",
+                address, region.name
+            ));
+        }
+        Ok(())
+    }
+    /// Returns a page, if it was allocated.
+    pub fn page(&self, number: u32) -> Option<&[u8; PAGE_SIZE]> {
+        self.pages.get(&number).map(|page| &**page)
+    }
+    /// Returns a page to write to, allocating it if needed, and marks it as changed.
+    fn page_mut(&mut self, number: u32) -> &mut [u8; PAGE_SIZE] {
+        if let Some(changed) = &mut self.changed {
+            changed.insert(number);
+        }
+        self.pages
+            .entry(number)
+            .or_insert_with(|| Box::new([0; PAGE_SIZE]))
+    }
+    /// Reads a byte, halfword, or word, the value is zero extended.
+    pub fn read(&self, address: u32, size: MemSize) -> Result<u32, String> {
+        self.access(address, size, false)?;
+        let offset = address as usize % PAGE_SIZE;
+        // fast path, the value is inside a single page
+        if offset + size.bytes() <= PAGE_SIZE {
+            let page = match self.page(address >> PAGE_BITS) {
+                Some(page) => page,
+                None => return Ok(0),
+            };
+            return Ok(match size {
+                MemSize::BYTE => page[offset] as u32,
+                MemSize::HALFWORD => u16::from_le_bytes([page[offset], page[offset + 1]]) as u32,
+                MemSize::WORD => u32::from_le_bytes([
+                    page[offset],
+                    page[offset + 1],
+                    page[offset + 2],
+                    page[offset + 3],
+                ]),
+            });
+        }
+        let mut bytes = [0u8; 4];
+        self.read_bytes(address, &mut bytes[..size.bytes()]);
+        Ok(u32::from_le_bytes(bytes))
+    }
+    /// Writes the low bytes of a value.
+    pub fn write(&mut self, address: u32, size: MemSize, value: u32) -> Result<(), String> {
+        self.access(address, size, true)?;
+        let offset = address as usize % PAGE_SIZE;
+        let bytes = value.to_le_bytes();
+        // fast path, the value is inside a single page
+        if offset + size.bytes() <= PAGE_SIZE {
+            self.page_mut(address >> PAGE_BITS)[offset..offset + size.bytes()]
+                .copy_from_slice(&bytes[..size.bytes()]);
+        } else {
+            self.write_bytes(address, &bytes[..size.bytes()]);
+        }
+        Ok(())
+    }
+    /// Copies the bytes starting at an address into a buffer, without checking the regions.
+    fn read_bytes(&self, address: u32, buffer: &mut [u8]) {
+        let mut address = address as u64;
+        let mut buffer = buffer;
+        while !buffer.is_empty() {
+            let offset = address as usize % PAGE_SIZE;
+            let (chunk, rest) = buffer.split_at_mut(buffer.len().min(PAGE_SIZE - offset));
+            match self.page((address >> PAGE_BITS) as u32) {
+                Some(page) => chunk.copy_from_slice(&page[offset..offset + chunk.len()]),
+                None => chunk.fill(0),
+            }
+            buffer = rest;
+            address += chunk.len() as u64;
+        }
+    }
+    /// Copies bytes to memory starting at an address, without checking the regions.
+    fn write_bytes(&mut self, address: u32, bytes: &[u8]) {
+        let mut address = address as u64;
+        let mut bytes = bytes;
+        while !bytes.is_empty() {
+            let offset = address as usize % PAGE_SIZE;
+            let (chunk, rest) = bytes.split_at(bytes.len().min(PAGE_SIZE - offset));
+            self.page_mut((address >> PAGE_BITS) as u32)[offset..offset + chunk.len()]
+                .copy_from_slice(chunk);
+            bytes = rest;
+            address += chunk.len() as u64;
+        }
+    }
+    /// Returns the bytes of an address range, which must be inside a single region.
+    /// The end is a u64, so the range can end at the top of the address space, like Region::range.
+    pub fn bytes(&self, range: Range<u64>) -> Option<Vec<u8>> {
+        let start = u32::try_from(range.start).ok()?;
+        let size = range.end.checked_sub(range.start)? as usize;
+        self.map.region_of(start, size)?;
+        let mut bytes = vec![0; size];
+        self.read_bytes(start, &mut bytes);
+        Some(bytes)
+    }
+    /// Returns the page numbers written since the last call, None if every page may have changed, ex: after a reset.
+    pub fn take_changed_pages(&mut self) -> Option<BTreeSet<u32>> {
+        self.changed.replace(BTreeSet::new())
+    }
+    /// Writes bytes while loading a program, read-only regions can be written.
+    /// Bytes outside every region are dropped, the linker and loaders check addresses first.
+    pub fn load(&mut self, address: u32, bytes: &[u8]) {
+        let end = address as u64 + bytes.len() as u64;
+        let ranges: Vec<Range<u64>> = self
+            .map
+            .regions
+            .iter()
+            .map(|region| region.range().start.max(address as u64)..region.range().end.min(end))
+            .filter(|range| !range.is_empty())
+            .collect();
+        for range in ranges {
+            let source =
+                (range.start - address as u64) as usize..(range.end - address as u64) as usize;
+            self.write_bytes(range.start as u32, &bytes[source]);
+        }
+    }
+    /// Loads the data sections of a program.
+    pub fn load_image(&mut self, image: &MemoryImage) {
+        for (address, bytes) in image.blocks() {
+            self.load(address as u32, &bytes);
+        }
+    }
+}
@@ -0,0 +1,1013 @@
+/// Contains the preprocessor, which turns a file into the list of lines that get compiled.
+/// Comments are removed, files are included, macros are expanded, and conditional assembly is evaluated.
+use crate::armasm::{self, Dialect};
+use crate::backend_api::compile;
+use crate::cpp;
+use crate::directive::{self, Handling};
+use crate::error::CompileErr;
+use crate::expression;
+use crate::utils;
+use regex::Regex;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Maximum depth of nested macro invocations, guards against macros that invoke themselves forever.
+const MAX_EXPANSION_DEPTH: usize = 100;
+/// Numeric local labels are renamed to this prefix, followed by the number, a "$", and how many times the number was defined before.
+/// Labels cannot contain "$", so the new name never matches a label of the file.
+/// ex: the second "1:" in a file becomes "_local_1$1:"
+const NUMERIC_LABEL_PREFIX: &str = "_local_";
+
+/// Returns true if a label was renamed from a numeric local label, ex: "1:"
+pub fn is_numeric_label(label: &str) -> bool {
+    label.starts_with(NUMERIC_LABEL_PREFIX) && label.contains('$')
+}
+
+/// A line in a source file.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Location {
+    pub file_name: String,
+    /// starts at one
+    pub line_number: usize,
+}
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\" line {}", self.file_name, self.line_number)
+    }
+}
+
+/// Describes how a line was produced, ex: macro "print" invoked from "main.s" line 10
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Frame {
+    pub description: String,
+    pub location: Location,
+}
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.description, self.location)
+    }
+}
+
+/// A line of code after preprocessing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceLine {
+    /// Comments are removed and whitespace is trimmed, the case is unchanged.
+    pub text: String,
+    /// Where the text was written. For macro expansions, this is the line inside the macro body.
+    pub location: Location,
+    /// The macro invocations and includes the line came from, innermost first.
+    pub backtrace: Vec<Frame>,
+    /// The register aliases defined with .req before an instruction, as (alias, register number).
+    pub register_aliases: Vec<(String, u32)>,
+    /// The .equ/.set symbols defined before an instruction or data directive, as (name, value).
+    pub symbols: Vec<(String, i64)>,
+}
+impl SourceLine {
+    pub fn new(text: String, file_name: &str, line_number: usize) -> Self {
+        SourceLine {
+            text,
+            location: Location {
+                file_name: file_name.to_string(),
+                line_number,
+            },
+            backtrace: Vec::new(),
+            register_aliases: Vec::new(),
+            symbols: Vec::new(),
+        }
+    }
+    /// Returns the value of a .equ/.set symbol defined before the line.
+    pub fn symbol(&self, name: &str) -> Option<i64> {
+        self.symbols
+            .iter()
+            .find(|(symbol, _)| symbol == name)
+            .map(|(_, value)| *value)
+    }
+    /// The location of the line, followed by every location in the backtrace.
+    pub fn locations(&self) -> impl Iterator<Item = &Location> {
+        std::iter::once(&self.location).chain(self.backtrace.iter().map(|frame| &frame.location))
+    }
+}
+
+/// A preprocessed file.
+#[derive(Debug)]
+pub struct SourceFile {
+    pub name: String,
+    pub lines: Vec<SourceLine>,
+}
+
+/// A parameter of a macro, ex: ".macro print msg:req, reg=r0"
+#[derive(Debug, Clone)]
+struct MacroParam {
+    name: String,
+    default: Option<String>,
+    /// :req, the argument must be given
+    required: bool,
+    /// :vararg, takes all remaining arguments
+    vararg: bool,
+}
+
+/// A macro defined by .macro and .endm
+#[derive(Debug, Clone)]
+struct Macro {
+    name: String,
+    params: Vec<MacroParam>,
+    body: Vec<SourceLine>,
+}
+impl Macro {
+    /// Parses the ".macro name params..." line.
+    fn new(line: &str) -> Result<Self, String> {
+        let re_name = Regex::new(r"^[a-zA-Z_.$][\w.$]*$").unwrap();
+        let re_param = Regex::new(r"^([a-zA-Z_]\w*)(:req|:vararg)?(=(.*))?$").unwrap();
+        let mut words = split_arguments(line[".macro".len()..].trim(), true).into_iter();
+        let name = words
+            .next()
+            .filter(|name| re_name.is_match(name))
+            .ok_or_else(|| "A macro must have a valid name.".to_string())?;
+
+        let mut params: Vec<MacroParam> = Vec::new();
+        for word in words {
+            let cap = re_param
+                .captures(&word)
+                .ok_or_else(|| format!("Invalid macro parameter \"{}\".", word))?;
+            if params.iter().any(|param| param.vararg) {
+                return Err("Only the last macro parameter can be :vararg.".into());
+            }
+            if params.iter().any(|param| param.name == cap[1]) {
+                return Err(format!("Macro parameter \"{}\" is defined twice.", &cap[1]));
+            }
+            params.push(MacroParam {
+                name: cap[1].to_string(),
+                default: cap.get(4).map(|default| default.as_str().to_string()),
+                required: cap.get(2).map_or(false, |q| q.as_str() == ":req"),
+                vararg: cap.get(2).map_or(false, |q| q.as_str() == ":vararg"),
+            });
+        }
+        Ok(Macro {
+            name: name.to_lowercase(),
+            params,
+            body: Vec::new(),
+        })
+    }
+    /// Matches the arguments of an invocation to the macro's parameters.
+    /// Arguments are positional, or given by name, ex: "print reg=r1, msg"
+    fn bind_arguments(&self, args: &str) -> Result<HashMap<String, String>, Vec<String>> {
+        let mut args = split_arguments(args, false);
+        // allow arguments separated by spaces when there are no commas.
+        if args.len() == 1 && self.params.len() > 1 {
+            args = split_arguments(&args[0], true);
+        }
+        let mut errors: Vec<String> = Vec::new();
+        let mut values: HashMap<String, String> = HashMap::new();
+        let mut position = 0usize;
+
+        for (index, arg) in args.iter().enumerate() {
+            // keyword argument
+            if let Some((name, value)) = arg.split_once('=') {
+                if self.params.iter().any(|param| param.name == name.trim()) {
+                    values.insert(name.trim().to_string(), value.trim().to_string());
+                    continue;
+                }
+            }
+            match self.params.get(position) {
+                Some(param) if param.vararg => {
+                    values.insert(param.name.clone(), args[index..].join(", "));
+                    break;
+                }
+                Some(param) => {
+                    if !arg.is_empty() {
+                        values.insert(param.name.clone(), arg.clone());
+                    }
+                    position += 1;
+                }
+                None => {
+                    errors.push(format!(
+                        "Macro \"{}\" takes at most {} arguments.",
+                        self.name,
+                        self.params.len()
+                    ));
+                    break;
+                }
+            }
+        }
+        // fill in default values
+        for param in &self.params {
+            if values.contains_key(&param.name) {
+                continue;
+            }
+            if param.required {
+                errors.push(format!(
+                    "Missing value for required parameter \"{}\" of macro \"{}\".",
+                    param.name, self.name
+                ));
+            } else {
+                values.insert(
+                    param.name.clone(),
+                    param.default.clone().unwrap_or_default(),
+                );
+            }
+        }
+        if errors.is_empty() {
+            Ok(values)
+        } else {
+            Err(errors)
+        }
+    }
+    /// Replaces \param with its value, \@ with the unique invocation number, and removes the \() separator.
+    fn substitute(text: &str, values: &HashMap<String, String>, unique: usize) -> String {
+        let mut result = String::with_capacity(text.len());
+        let mut chars = text.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            if c != '\\' {
+                result.push(c);
+                continue;
+            }
+            match chars.peek() {
+                Some((_, '@')) => {
+                    chars.next();
+                    result += &unique.to_string();
+                }
+                Some((_, '(')) if text[i + 1..].starts_with("()") => {
+                    chars.next();
+                    chars.next();
+                }
+                _ => {
+                    let name: String = text[i + 1..]
+                        .chars()
+                        .take_while(|c| c.is_ascii_alphanumeric() || *c == '_')
+                        .collect();
+                    if let Some(value) = values.get(&name) {
+                        result += value;
+                        for _ in 0..name.len() {
+                            chars.next();
+                        }
+                    } else {
+                        result.push(c);
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+/// Splits arguments on commas that are not inside brackets, braces, or quotes.
+/// If split_whitespace is true, whitespace also separates arguments.
+pub fn split_arguments(args: &str, split_whitespace: bool) -> Vec<String> {
+    let mut result: Vec<String> = Vec::new();
+    let mut current = String::new();
+    let mut depth = 0i32;
+    let mut in_quotes = false;
+    for c in args.chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '[' | '{' if !in_quotes => depth += 1,
+            ']' | '}' if !in_quotes => depth -= 1,
+            _ => (),
+        }
+        let is_separator =
+            !in_quotes && depth <= 0 && (c == ',' || (split_whitespace && c.is_whitespace()));
+        if is_separator {
+            if c == ',' || !current.trim().is_empty() {
+                result.push(current.trim().to_string());
+            }
+            current.clear();
+        } else {
+            current.push(c);
+        }
+    }
+    if !current.trim().is_empty() || !result.is_empty() {
+        result.push(current.trim().to_string());
+    }
+    if split_whitespace {
+        result.retain(|arg| !arg.is_empty());
+    }
+    result
+}
+
+/// Renames numeric local labels, ex: "1:", so every definition is unique.
+/// References use the closest definition before the line with "b" (1b), or after the line with "f" (1f).
+/// A label on the same line as the reference counts as before the line.
+fn resolve_numeric_labels(lines: &mut [SourceLine], errors: &mut CompileErr) {
+    let re_definition = Regex::new(r"^(\d+)\s*:").unwrap();
+    let re_reference = Regex::new(r"\b(\d+)([bfBF])\b").unwrap();
+    let label_name =
+        |number: &str, count: usize| format!("{}{}${}", NUMERIC_LABEL_PREFIX, number, count);
+
+    // number of times each label is defined in the file.
+    let mut totals: HashMap<String, usize> = HashMap::new();
+    for line in lines.iter() {
+        if let Some(cap) = re_definition.captures(&line.text) {
+            *totals.entry(cap[1].to_string()).or_default() += 1;
+        }
+    }
+    // number of definitions seen so far.
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for line in lines.iter_mut() {
+        // the new names are reserved, so they cannot match a label of the file
+        if let Some(label) = line.text.split_once(':').map(|(label, _)| label.trim()) {
+            if is_numeric_label(label) {
+                errors.update_location(line);
+                errors.push_message(&format!("Label \"{}\" cannot contain \"$\".", label));
+            }
+        }
+        let mut text = line.text.clone();
+        let mut operands_start = 0;
+        if let Some(cap) = re_definition.captures(&line.text) {
+            let count = counts.entry(cap[1].to_string()).or_default();
+            let name = label_name(&cap[1], *count);
+            *count += 1;
+            operands_start = name.len() + 1;
+            text = format!("{}:{}", name, &line.text[cap[0].len()..]);
+        }
+        // the mnemonic or directive is never a reference
+        let rest = text[operands_start..].trim_start();
+        let (first_word, operands) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        if !operands.contains(|c: char| c.is_ascii_digit()) {
+            line.text = text;
+            continue;
+        }
+        errors.update_location(line);
+        // replace references outside of quotes
+        let replaced: Vec<String> = operands
+            .split('"')
+            .enumerate()
+            .map(|(i, part)| {
+                if i % 2 == 1 {
+                    return part.to_string();
+                }
+                re_reference
+                    .replace_all(part, |cap: &regex::Captures| {
+                        let number = &cap[1];
+                        let defined = counts.get(number).copied().unwrap_or(0);
+                        let total = totals.get(number).copied().unwrap_or(0);
+                        if cap[2].eq_ignore_ascii_case("b") {
+                            if defined == 0 {
+                                errors.push_message(&format!(
+                                    "Local label \"{}b\" has no \"{}:\" before it.",
+                                    number, number
+                                ));
+                            }
+                            label_name(number, defined.saturating_sub(1))
+                        } else {
+                            if defined >= total {
+                                errors.push_message(&format!(
+                                    "Local label \"{}f\" has no \"{}:\" after it.",
+                                    number, number
+                                ));
+                            }
+                            label_name(number, defined)
+                        }
+                    })
+                    .into_owned()
+            })
+            .collect();
+        let replaced = replaced.join("\"");
+        line.text = if replaced == operands {
+            text
+        } else {
+            let prefix_len = text.len() - rest.len();
+            format!("{}{} {}", &text[..prefix_len], first_word, replaced)
+        };
+    }
+}
+
+/// An open .if block.
+struct Conditional {
+    /// Lines in the current branch are kept.
+    active: bool,
+    /// A branch was already kept, so the remaining .elseif and .else branches are skipped.
+    taken: bool,
+    /// .else was seen, so no more branches are allowed.
+    has_else: bool,
+    /// The .if line, used for error messages.
+    line: SourceLine,
+}
+
+/// Settings shared by every file in a project.
+#[derive(Debug, Default, Clone)]
+pub struct PreprocessOptions {
+    /// The project directory, file names are relative to it.
+    pub dir_path: PathBuf,
+    /// Directories searched by .include, relative to the project directory.
+    pub include_dirs: Vec<String>,
+    /// Symbols for conditional assembly.
+    pub symbols: HashMap<String, i64>,
+    /// The assembler syntax of every file, detected from each file if it is not set.
+    pub dialect: Option<Dialect>,
+}
+
+/// A file on the include stack.
+struct IncludedFile {
+    path: PathBuf,
+    /// Used to detect include cycles.
+    canonical_path: PathBuf,
+    /// The file name shown in error messages, relative to the project directory.
+    name: String,
+}
+
+/// Splits the contents of a file into lines, numbered from one.
+fn file_lines(file_name: &str, content: &str) -> Vec<SourceLine> {
+    content
+        .lines()
+        .enumerate()
+        .map(|(index, line)| SourceLine::new(line.to_string(), file_name, index + 1))
+        .collect()
+}
+
+/// Searches for an included file in the directory of the current file, then in the include directories.
+/// Returns the path and the contents of the file.
+pub fn find_include(
+    options: &PreprocessOptions,
+    current_dir: Option<&Path>,
+    file: &str,
+) -> Option<(PathBuf, String)> {
+    current_dir
+        .into_iter()
+        .map(|dir| dir.join(file))
+        .chain(
+            options
+                .include_dirs
+                .iter()
+                .map(|dir| options.dir_path.join(dir).join(file)),
+        )
+        .find_map(|path| {
+            fs::read_to_string(&path)
+                .ok()
+                .map(|content| (path, content))
+        })
+}
+
+/// The name of a file shown in error messages, relative to the project directory.
+pub fn relative_name(options: &PreprocessOptions, path: &Path) -> String {
+    path.strip_prefix(&options.dir_path)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .replace('\\', "/")
+}
+
+/// Expands the macros, includes, and conditional assembly of a file.
+/// Macros and .equ/.set symbols are local to the file they are defined in, and the files it includes.
+pub struct Preprocessor {
+    options: PreprocessOptions,
+    /// The file being preprocessed, followed by the files it includes. Innermost last.
+    include_stack: Vec<IncludedFile>,
+    macros: HashMap<String, Macro>,
+    /// Counts macro invocations, used for the \@ unique suffix.
+    invocations: usize,
+    /// The macro currently being defined, and how many .macro directives are nested inside its body.
+    definition: Option<(Macro, SourceLine, usize)>,
+    /// Symbols used by conditional assembly, from the configuration and from .equ/.set directives.
+    symbols: HashMap<String, i64>,
+    /// Stack of open .if blocks, innermost last.
+    conditionals: Vec<Conditional>,
+    /// Register aliases from .req directives, and the number of the register each one refers to, ex: count -> 4
+    register_aliases: HashMap<String, u32>,
+    /// Set by .end, the rest of the file is not assembled.
+    ended: bool,
+    /// The assembler syntax of the file, and the files it includes.
+    dialect: Dialect,
+    errors: CompileErr,
+    lines: Vec<SourceLine>,
+}
+impl Preprocessor {
+    pub fn new(options: &PreprocessOptions) -> Self {
+        Preprocessor {
+            options: options.clone(),
+            include_stack: Vec::new(),
+            macros: HashMap::new(),
+            invocations: 0,
+            definition: None,
+            symbols: options.symbols.clone(),
+            conditionals: Vec::new(),
+            register_aliases: HashMap::new(),
+            ended: false,
+            dialect: Dialect::Gnu,
+            errors: CompileErr::new(),
+            lines: Vec::new(),
+        }
+    }
+    /// Preprocesses a file, returns all preprocessing errors if there are any.
+    pub fn run(mut self, file_name: &str, content: &str) -> Result<Vec<SourceLine>, Vec<String>> {
+        let path = self.options.dir_path.join(file_name);
+        self.dialect = self
+            .options
+            .dialect
+            .unwrap_or_else(|| armasm::detect(content));
+        // .S files are run through the C preprocessor first.
+        let raw_lines = if cpp::is_c_source(file_name) {
+            cpp::preprocess(file_name, content, &self.options)?
+        } else {
+            file_lines(file_name, content)
+        };
+        self.process_file(path, file_name.to_string(), raw_lines, &[], 0);
+        if let Some((mac, line, _)) = self.definition.take() {
+            self.errors.update_location(&line);
+            self.errors
+                .push_message(&format!("Macro \"{}\" is missing .endm", mac.name));
+        }
+        for conditional in std::mem::take(&mut self.conditionals) {
+            self.errors.update_location(&conditional.line);
+            self.errors.push_message(".if block is missing .endif");
+        }
+        resolve_numeric_labels(&mut self.lines, &mut self.errors);
+        self.errors.result()?;
+        Ok(self.lines)
+    }
+    /// Preprocesses every line of a file. Returns true if the file contains .exitm, which stops the current macro expansion.
+    /// The lines still contain comments, and are either the lines of the file or the output of the C preprocessor.
+    fn process_file(
+        &mut self,
+        path: PathBuf,
+        name: String,
+        raw_lines: Vec<SourceLine>,
+        backtrace: &[Frame],
+        depth: usize,
+    ) -> bool {
+        self.include_stack.push(IncludedFile {
+            canonical_path: fs::canonicalize(&path).unwrap_or_else(|_| path.clone()),
+            path,
+            name,
+        });
+        let mut exited = false;
+        let mut in_block_comment = false;
+        // the line where the last block comment started.
+        let mut comment_start = SourceLine::new(String::new(), "", 0);
+        for raw_line in raw_lines {
+            let was_in_block_comment = in_block_comment;
+            let text = compile::preprocess_line(&raw_line.text, &mut in_block_comment);
+            let mut line = SourceLine {
+                text,
+                ..raw_line.clone()
+            };
+            line.backtrace.extend(backtrace.iter().cloned());
+            if in_block_comment && !was_in_block_comment {
+                comment_start = line.clone();
+            }
+            if self.dialect == Dialect::Armasm {
+                // ARMASM labels start in column 1
+                let in_column_1 = !was_in_block_comment
+                    && raw_line.text.starts_with(|c: char| !c.is_whitespace());
+                match armasm::translate(&line.text, in_column_1) {
+                    Ok(texts) => {
+                        exited = texts.into_iter().any(|text| {
+                            self.process_line(
+                                SourceLine {
+                                    text,
+                                    ..line.clone()
+                                },
+                                depth,
+                            )
+                        });
+                    }
+                    Err(err) => {
+                        self.errors.update_location(&line);
+                        self.errors.push_message(&err);
+                    }
+                }
+            } else if self.process_line(line, depth) {
+                exited = true;
+            }
+            if exited {
+                break;
+            }
+            if self.ended {
+                break;
+            }
+        }
+        if in_block_comment && !exited && !self.ended {
+            self.errors.update_location(&comment_start);
+            self.errors
+                .push_message("Block comment is missing the closing */");
+        }
+        self.include_stack.pop();
+        exited
+    }
+    /// Handles '.include "file"'. The file is searched for in the directory of the current file, then in the include directories.
+    /// Returns true if the included file contains .exitm
+    fn include(&mut self, line: SourceLine, depth: usize) -> bool {
+        self.errors.update_location(&line);
+        let file = match Regex::new(r#"^\.include\s+"([^"]+)"$"#)
+            .unwrap()
+            .captures(&line.text)
+        {
+            Some(cap) => cap[1].to_string(),
+            None => {
+                self.errors
+                    .push_message(".include must be followed by a file name in quotes.");
+                return false;
+            }
+        };
+        let current_dir = self
+            .include_stack
+            .last()
+            .and_then(|current| current.path.parent());
+        let (path, content) = match find_include(&self.options, current_dir, &file) {
+            Some(found) => found,
+            None => {
+                self.errors.push_message(&format!(
+                    "Couldn't find the included file \"{}\" in the directory of the current file or the include_dirs.",
+                    file
+                ));
+                return false;
+            }
+        };
+        // detect include cycles
+        let canonical_path = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        if let Some(start) = self
+            .include_stack
+            .iter()
+            .position(|included| included.canonical_path == canonical_path)
+        {
+            let chain: Vec<String> = self.include_stack[start..]
+                .iter()
+                .map(|included| format!("\"{}\"", included.name))
+                .chain(std::iter::once(format!(
+                    "\"{}\"",
+                    self.include_stack[start].name
+                )))
+                .collect();
+            self.errors
+                .push_message(&format!("Include cycle: {}", chain.join(" includes ")));
+            return false;
+        }
+        let name = relative_name(&self.options, &path);
+        let mut backtrace = vec![Frame {
+            description: "included from".into(),
+            location: line.location,
+        }];
+        backtrace.extend(line.backtrace);
+        let raw_lines = file_lines(&name, &content);
+        self.process_file(path, name, raw_lines, &backtrace, depth)
+    }
+    /// Handles "name .req register", which makes name refer to the register.
+    fn define_register_alias(&mut self, line: &SourceLine) {
+        self.errors.update_location(line);
+        let words: Vec<String> = line
+            .text
+            .to_lowercase()
+            .split_whitespace()
+            .map(String::from)
+            .collect();
+        if words.len() != 3 {
+            self.errors
+                .push_message(".req must be written as \"name .req register\", ex: count .req r4");
+            return;
+        }
+        let (alias, target) = (&words[0], &words[2]);
+        let register = match self.register_aliases.get(target) {
+            Some(register) => *register,
+            None => match utils::register_number(target) {
+                Some(register) => register,
+                None => {
+                    self.errors
+                        .push_message(&format!("\"{}\" is not a register.", target));
+                    return;
+                }
+            },
+        };
+        let is_builtin = utils::register_names().contains(alias)
+            || utils::apcs_registers()
+                .iter()
+                .any(|(name, _)| name == alias);
+        match self.register_aliases.get(alias) {
+            _ if is_builtin => self.errors.push_message(&format!(
+                "\"{}\" is already a register name, choose another alias.",
+                alias
+            )),
+            Some(existing) if *existing != register => self.errors.push_message(&format!(
+                "\"{}\" already refers to r{}, remove it with .unreq first.",
+                alias, existing
+            )),
+            _ => {
+                self.register_aliases.insert(alias.clone(), register);
+            }
+        }
+    }
+    /// Handles ".unreq name", which removes a register alias.
+    fn remove_register_alias(&mut self, line: &SourceLine) {
+        self.errors.update_location(line);
+        let alias = line.text[".unreq".len()..].trim().to_lowercase();
+        let is_apcs = utils::apcs_registers()
+            .iter()
+            .any(|(name, _)| *name == alias);
+        if is_apcs || self.register_aliases.remove(&alias).is_none() {
+            self.errors.push_message(&format!(
+                "\"{}\" is not a register alias defined with .req",
+                alias
+            ));
+        }
+    }
+    /// Handles one line. Returns true if the line is .exitm, which stops the current macro expansion.
+    fn process_line(&mut self, line: SourceLine, depth: usize) -> bool {
+        if self.ended {
+            return false;
+        }
+        let lowercase = line.text.to_lowercase();
+        let first_word = lowercase.split_whitespace().next().unwrap_or("");
+
+        // record the body of a macro definition
+        if let Some((mac, _, nesting)) = &mut self.definition {
+            if first_word != ".endm" || *nesting > 0 {
+                if first_word == ".macro" {
+                    *nesting += 1;
+                } else if first_word == ".endm" {
+                    *nesting -= 1;
+                }
+                mac.body.push(line);
+                return false;
+            }
+            let (mac, _, _) = self.definition.take().unwrap();
+            // a macro with an invalid definition is not stored.
+            if !mac.name.is_empty() {
+                self.macros.insert(mac.name.clone(), mac);
+            }
+            return false;
+        }
+        // conditional assembly
+        if self.process_conditional(first_word, &line) {
+            return false;
+        }
+        if !self.is_active() {
+            return false;
+        }
+        // a label followed by an instruction or directive is split into two lines, ex: "loop: add r0, #1"
+        if let Some(cap) = Regex::new(r"^([a-zA-Z_]\w*|\d+)\s*:\s*(\S.*)$")
+            .unwrap()
+            .captures(&line.text)
+        {
+            let (label, rest) = (format!("{}:", &cap[1]), cap[2].to_string());
+            self.lines.push(SourceLine {
+                text: label,
+                ..line.clone()
+            });
+            return self.process_line(SourceLine { text: rest, ..line }, depth);
+        }
+        // "name .req register"
+        if lowercase.split_whitespace().nth(1) == Some(".req") {
+            self.define_register_alias(&line);
+            return false;
+        }
+        match first_word {
+            ".unreq" => self.remove_register_alias(&line),
+            ".equ" | ".set" => {
+                self.define_symbol(first_word, &line);
+                self.lines.push(line);
+            }
+            ".macro" => match Macro::new(&line.text) {
+                Ok(mac) => self.definition = Some((mac, line, 0)),
+                Err(err) => {
+                    self.errors.update_location(&line);
+                    self.errors.push_message(&err);
+                    // record the body anyway, so it is not compiled as code.
+                    let mac = Macro {
+                        name: String::new(),
+                        params: Vec::new(),
+                        body: Vec::new(),
+                    };
+                    self.definition = Some((mac, line, 0));
+                }
+            },
+            ".endm" => {
+                self.errors.update_location(&line);
+                self.errors
+                    .push_message(".endm does not have a matching .macro");
+            }
+            ".exitm" => {
+                if depth > 0 {
+                    return true;
+                }
+                self.errors.update_location(&line);
+                self.errors
+                    .push_message(".exitm can only be used inside a macro.");
+            }
+            ".include" => return self.include(line, depth),
+            _ if self.macros.contains_key(first_word) => {
+                let name = first_word.to_string();
+                self.expand(&name, line, depth);
+            }
+            _ if first_word.starts_with('.') => {
+                let args = &line.text[first_word.len()..];
+                match directive::check(first_word, args) {
+                    Ok(Handling::Keep) => self.lines.push(SourceLine {
+                        symbols: self.defined_symbols(),
+                        ..line
+                    }),
+                    Ok(Handling::Ignore) => (),
+                    Ok(Handling::End) => self.ended = true,
+                    Err(err) => {
+                        self.errors.update_location(&line);
+                        self.errors.push_message(&err);
+                    }
+                }
+            }
+            _ => {
+                // the aliases are resolved when the operands are parsed, so the text stays as written
+                let mut register_aliases: Vec<(String, u32)> = self
+                    .register_aliases
+                    .iter()
+                    .map(|(alias, register)| (alias.clone(), *register))
+                    .collect();
+                register_aliases.sort_unstable();
+                self.lines.push(SourceLine {
+                    register_aliases,
+                    symbols: self.defined_symbols(),
+                    ..line
+                });
+            }
+        }
+        false
+    }
+    /// Expands a macro invocation.
+    fn expand(&mut self, name: &str, line: SourceLine, depth: usize) {
+        self.errors.update_location(&line);
+        if depth >= MAX_EXPANSION_DEPTH {
+            self.errors.push_message(&format!(
+                "Macro \"{}\" is nested too deeply, it may be invoking itself.",
+                name
+            ));
+            return;
+        }
+        let mac = self.macros[name].clone();
+        let args = line.text.trim()[name.len()..].trim();
+        let values = match mac.bind_arguments(args) {
+            Ok(values) => values,
+            Err(errors) => {
+                self.errors.extend(errors);
+                return;
+            }
+        };
+        let unique = self.invocations;
+        self.invocations += 1;
+
+        let mut backtrace = vec![Frame {
+            description: format!("macro \"{}\" invoked from", mac.name),
+            location: line.location,
+        }];
+        backtrace.extend(line.backtrace);
+        // .if blocks opened inside the macro must be closed inside the macro.
+        let open_conditionals = self.conditionals.len();
+        let mut exited = false;
+        for body_line in &mac.body {
+            let expanded = SourceLine {
+                text: Macro::substitute(&body_line.text, &values, unique),
+                location: body_line.location.clone(),
+                backtrace: backtrace.clone(),
+                register_aliases: Vec::new(),
+                symbols: Vec::new(),
+            };
+            if self.process_line(expanded, depth + 1) {
+                exited = true;
+                break;
+            }
+        }
+        for conditional in self
+            .conditionals
+            .split_off(open_conditionals.min(self.conditionals.len()))
+        {
+            if !exited {
+                self.errors.update_location(&conditional.line);
+                self.errors
+                    .push_message(".if block inside a macro is missing .endif");
+            }
+        }
+    }
+    /// True if lines are kept, false if they are inside a skipped .if branch.
+    fn is_active(&self) -> bool {
+        self.conditionals
+            .iter()
+            .all(|conditional| conditional.active)
+    }
+    /// Handles .if, .elseif, .else, and .endif directives. Returns false if the line is not a conditional directive.
+    fn process_conditional(&mut self, directive: &str, line: &SourceLine) -> bool {
+        match directive {
+            ".if" | ".ifdef" | ".ifndef" | ".ifnotdef" | ".ifeq" | ".ifne" | ".ifgt" | ".ifge"
+            | ".iflt" | ".ifle" => {
+                // conditions inside a skipped branch are not evaluated.
+                let active = self.is_active() && self.condition(directive, line);
+                self.conditionals.push(Conditional {
+                    active,
+                    taken: active || !self.is_active(),
+                    has_else: false,
+                    line: line.clone(),
+                });
+            }
+            ".elseif" | ".else" => {
+                let parent_active = self.conditionals.len() < 2
+                    || self.conditionals[..self.conditionals.len() - 1]
+                        .iter()
+                        .all(|conditional| conditional.active);
+                let (taken, has_else) = match self.conditionals.last() {
+                    Some(conditional) => (conditional.taken, conditional.has_else),
+                    None => {
+                        self.errors.update_location(line);
+                        self.errors
+                            .push_message(&format!("{} does not have a matching .if", directive));
+                        return true;
+                    }
+                };
+                if has_else {
+                    self.errors.update_location(line);
+                    self.errors
+                        .push_message(&format!("{} cannot come after .else", directive));
+                }
+                let active = !taken
+                    && parent_active
+                    && (directive == ".else" || self.condition(directive, line));
+                let conditional = self.conditionals.last_mut().unwrap();
+                conditional.active = active;
+                conditional.taken |= active;
+                conditional.has_else |= directive == ".else";
+            }
+            ".endif" => {
+                if self.conditionals.pop().is_none() {
+                    self.errors.update_location(line);
+                    self.errors
+                        .push_message(".endif does not have a matching .if");
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+    /// Evaluates the condition of an .if or .elseif directive.
+    fn condition(&mut self, directive: &str, line: &SourceLine) -> bool {
+        let operand = line.text[directive.len()..].trim();
+        if directive == ".ifdef" || directive == ".ifndef" || directive == ".ifnotdef" {
+            if operand.is_empty() || operand.contains(char::is_whitespace) {
+                self.errors.update_location(line);
+                self.errors
+                    .push_message(&format!("{} takes a single symbol name.", directive));
+                return false;
+            }
+            return self.symbols.contains_key(operand) == (directive == ".ifdef");
+        }
+        match expression::evaluate(operand, &|name| self.symbols.get(name).copied()) {
+            Ok(value) => match directive {
+                ".ifeq" => value == 0,
+                ".ifgt" => value > 0,
+                ".ifge" => value >= 0,
+                ".iflt" => value < 0,
+                ".ifle" => value <= 0,
+                _ => value != 0, // .if, .ifne, .elseif
+            },
+            Err(err) => {
+                self.errors.update_location(line);
+                self.errors.push_message(&err);
+                false
+            }
+        }
+    }
+    /// The .equ/.set symbols defined so far, sorted so the same file always gives the same lines.
+    fn defined_symbols(&self) -> Vec<(String, i64)> {
+        let mut symbols: Vec<(String, i64)> = self
+            .symbols
+            .iter()
+            .map(|(name, value)| (name.clone(), *value))
+            .collect();
+        symbols.sort_unstable();
+        symbols
+    }
+    /// Handles ".equ name, expression" and ".set name, expression"
+    /// Only symbols whose value is known here are recorded, values that use labels or later symbols are left to the assembler.
+    fn define_symbol(&mut self, directive: &str, line: &SourceLine) {
+        let operands = line.text[directive.len()..].trim();
+        let result = match operands.split_once(',') {
+            Some((name, expr)) => {
+                let name = name.trim();
+                if Regex::new(r"^[a-zA-Z_.$][\w.$]*$").unwrap().is_match(name) {
+                    Ok((name.to_string(), expr))
+                } else {
+                    Err(format!("\"{}\" is not a valid symbol name.", name))
+                }
+            }
+            None => Err(format!(
+                "{} must be followed by a name and a value.",
+                directive
+            )),
+        };
+        match result {
+            Ok((name, expr)) => {
+                if let Ok(value) =
+                    expression::evaluate(expr, &|name| self.symbols.get(name).copied())
+                {
+                    self.symbols.insert(name, value);
+                }
+            }
+            Err(err) => {
+                self.errors.update_location(line);
+                self.errors.push_message(&err);
+            }
+        }
+    }
+}
+
+/// Preprocesses the contents of a file.
+pub fn preprocess_file(
+    file_name: &str,
+    content: &str,
+    options: &PreprocessOptions,
+) -> Result<Vec<SourceLine>, Vec<String>> {
+    Preprocessor::new(options).run(file_name, content)
+}
@@ -1,16 +1,124 @@
 use crate::{
     arm7::{InputStatus, Label, MemSize, Operands, Processor},
     error,
+    memory::Memory,
+    preprocess::SourceLine,
 };
 use regex::Regex;
+use std::cell::RefCell;
+
+thread_local! {
+    /// The register aliases defined with .req for the line being compiled, set by with_source_line.
+    static REGISTER_ALIASES: RefCell<Vec<(String, u32)>> = const { RefCell::new(Vec::new()) };
+    /// The .equ/.set symbols defined before the line being compiled, set by with_source_line.
+    static SYMBOLS: RefCell<Vec<(String, i64)>> = const { RefCell::new(Vec::new()) };
+}
 
 /// Regex expression for every condition code.
 pub fn condition_codes() -> &'static str {
     r"(eq|ne|cs|hs|cc|lo|mi|pl|vs|vc|hi|ls|ge|lt|gt|le|al)"
 }
 
-fn register() -> &'static str {
-    r"\s*(r\d+|sp|lr|pc)\s*"
+/// Regex expression for a register, its APCS name, or a register alias defined with .req
+fn register() -> String {
+    let aliases: String = REGISTER_ALIASES.with(|aliases| {
+        aliases
+            .borrow()
+            .iter()
+            .map(|(alias, _)| format!("|{}", regex::escape(alias)))
+            .collect()
+    });
+    format!(
+        r"\s*\b(r\d+|sp|lr|pc|a[1-4]|v[1-8]|sb|sl|fp|ip{})\b\s*",
+        aliases
+    )
+}
+/// Returns the number of a register, ex: "r4", "sp", or the APCS name "fp".
+/// The register aliases set by with_source_line are also resolved.
+pub fn register_number(name: &str) -> Option<u32> {
+    let name = apcs_registers()
+        .iter()
+        .find(|(alias, _)| *alias == name)
+        .map_or(name, |(_, register)| *register);
+    match name {
+        "sp" => Some(13),
+        "lr" => Some(14),
+        "pc" => Some(15),
+        _ => match name.strip_prefix('r').map(str::parse::<u32>) {
+            Some(Ok(n)) if n <= 15 => Some(n),
+            _ => REGISTER_ALIASES.with(|aliases| {
+                aliases
+                    .borrow()
+                    .iter()
+                    .find(|(alias, _)| alias == name)
+                    .map(|(_, n)| *n)
+            }),
+        },
+    }
+}
+/// Compiles a line with the register aliases and .equ/.set symbols that were defined before it.
+pub fn with_source_line<T>(line: &SourceLine, compile: impl FnOnce() -> T) -> T {
+    REGISTER_ALIASES.with(|current| *current.borrow_mut() = line.register_aliases.clone());
+    SYMBOLS.with(|current| *current.borrow_mut() = line.symbols.clone());
+    let result = compile();
+    REGISTER_ALIASES.with(|current| current.borrow_mut().clear());
+    SYMBOLS.with(|current| current.borrow_mut().clear());
+    result
+}
+/// Regex expression for the .equ/.set symbols set by with_source_line, ex: "count|size".
+/// Instructions are lowercased before they are parsed, so the case of a symbol is ignored.
+fn symbol_names() -> Option<String> {
+    SYMBOLS.with(|symbols| {
+        let symbols = symbols.borrow();
+        if symbols.is_empty() {
+            return None;
+        }
+        let names: Vec<String> = symbols
+            .iter()
+            .map(|(name, _)| regex::escape(name))
+            .collect();
+        Some(format!(r"(?i:{})\b", names.join("|")))
+    })
+}
+/// Returns the value of a .equ/.set symbol set by with_source_line, the case is ignored.
+pub fn symbol_value(name: &str) -> Option<i64> {
+    SYMBOLS.with(|symbols| {
+        symbols
+            .borrow()
+            .iter()
+            .find(|(symbol, _)| symbol.eq_ignore_ascii_case(name))
+            .map(|(_, value)| *value)
+    })
+}
+/// All valid register names, used for suggestions.
+pub fn register_names() -> Vec<String> {
+    ["sp", "lr", "pc"]
+        .into_iter()
+        .map(String::from)
+        .chain((0..16).map(|n| format!("r{}", n)))
+        .collect()
+}
+
+/// The APCS register names, and the register each one refers to.
+pub fn apcs_registers() -> [(&'static str, &'static str); 16] {
+    [
+        ("a1", "r0"),
+        ("a2", "r1"),
+        ("a3", "r2"),
+        ("a4", "r3"),
+        ("v1", "r4"),
+        ("v2", "r5"),
+        ("v3", "r6"),
+        ("v4", "r7"),
+        ("v5", "r8"),
+        ("v6", "r9"),
+        ("v7", "r10"),
+        ("v8", "r11"),
+        ("sb", "r9"),
+        ("sl", "r10"),
+        ("fp", "r11"),
+        ("ip", "r12"),
+    ]
 }
 
 #[warn(dead_code)]
@@ -23,13 +131,20 @@ fn mnemonic_extension() -> &'static str {
 pub fn u_number() -> &'static str {
     r"#(0b[01]+|0x[A-Fa-f\d]+|\d+)"
 }
-/// Regex expression for signed immediate values
-/// ex: #0x12, #-12, #-0b1100, #12
-pub fn i_number() -> &'static str {
-    r"\s*#-?(0b[01]+|0x[A-Fa-f\d]+|\d+)\s*"
+/// Regex expression for signed immediate values, or a .equ/.set symbol
+/// ex: #0x12, #-12, #-0b1100, #12, #COUNT
+pub fn i_number() -> String {
+    match symbol_names() {
+        Some(names) => format!(r"\s*#(-?(0b[01]+|0x[A-Fa-f\d]+|\d+)|{})\s*", names),
+        None => r"\s*#-?(0b[01]+|0x[A-Fa-f\d]+|\d+)\s*".to_string(),
+    }
+}
+/// A number without a "#", as in "ldr rt, =0x100"
+pub fn literal_number() -> &'static str {
+    r"\s*-?(0b[01]+|0x[A-Fa-f\d]+|\d+)\b\s*"
 }
 pub fn re_label() -> &'static str {
-    r"\s*[a-zA-Z_]\w*\s*"
+    r"\s*[a-zA-Z_][\w$]*\s*"
 }
 pub fn is_bin(num: &str) -> bool {
     Regex::new(r"^#-?0b[01]+$").unwrap().is_match(num)
@@ -46,14 +161,40 @@ pub fn is_dec(num: &str) -> bool {
 pub fn get_all_numbers(line: &str) -> Result<Vec<u32>, Vec<String>> {
     let mut errors: Vec<String> = Vec::new();
     let mut numbers: Vec<u32> = Vec::new();
+    // skip the mnemonic, so it is never read as a register alias
+    let operands = line
+        .trim_start()
+        .split_once(char::is_whitespace)
+        .map_or("", |(_, operands)| operands);
 
-    for mat in Regex::new(format!(r"{}|#[\da-fA-Fx]+|{}", register(), i_number()).as_str())
-        .unwrap()
-        .find_iter(line)
-        .map(|m| m.as_str())
+    // symbols are matched before "#[\da-fA-Fx]+", which would read "#count" as "#c"
+    let symbols = symbol_names().map_or(String::new(), |names| format!("|#{}", names));
+    for mat in Regex::new(
+        format!(
+            r"{}|={}{}|#[\da-fA-Fx]+|{}",
+            register(),
+            literal_number(),
+            symbols,
+            i_number()
+        )
+        .as_str(),
+    )
+    .unwrap()
+    .find_iter(operands)
+    .map(|m| m.as_str())
     {
-        let mat = mat.trim();
-        if mat.starts_with('r') {
+        // "=0x100" is read the same as "=#0x100"
+        let literal;
+        let mat = match mat.trim().strip_prefix('=') {
+            Some(value) => {
+                literal = format!("#{}", value.trim_start());
+                literal.as_str()
+            }
+            None => mat.trim(),
+        };
+        if let Some(n) = register_number(mat) {
+            numbers.push(n);
+        } else if mat.starts_with('r') {
             // handle register numbers
             match (&mat[1..]).parse::<u32>() {
                 Ok(n) => {
@@ -71,13 +212,6 @@ pub fn get_all_numbers(line: &str) -> Result<Vec<u32>, Vec<String>> {
                     mat
                 )),
             }
-        // check special registers
-        } else if mat == "sp" {
-            numbers.push(13);
-        } else if mat == "lr" {
-            numbers.push(14);
-        } else if mat == "pc" {
-            numbers.push(15);
         } else {
             // Handle immediate values
             // check for negative value
@@ -88,21 +222,26 @@ pub fn get_all_numbers(line: &str) -> Result<Vec<u32>, Vec<String>> {
                 (false, 0)
             };
             // reject number if it cannot be contained in 32 bits.
-            let num = if is_bin(mat) {
+            let num = if let Some(value) = symbol_value(&mat[1..]) {
+                // .equ/.set symbol, which can be negative
+                u32::try_from(value)
+                    .or_else(|_| i32::try_from(value).map(|value| value as u32))
+                    .ok()
+            } else if is_bin(mat) {
                 // Binary
-                u32::from_str_radix(&mat[3 + index_offset..], 2)
+                u32::from_str_radix(&mat[3 + index_offset..], 2).ok()
             } else if is_hex(mat) {
                 // Hexadecimal
-                u32::from_str_radix(&mat[3 + index_offset..], 16)
+                u32::from_str_radix(&mat[3 + index_offset..], 16).ok()
             } else if is_dec(mat) {
                 // Immediate Decimal Value
-                (&mat[1 + index_offset..]).parse::<u32>()
+                (&mat[1 + index_offset..]).parse::<u32>().ok()
             } else {
                 // invalid immediate value
                 errors.push(format!("{} is not a valid immediate value.", mat));
                 continue;
             };
-            if let Ok(num) = num {
+            if let Some(num) = num {
                 // negate number if negative
                 if sign {
                     numbers.push(num.wrapping_neg());
@@ -122,6 +261,69 @@ pub fn get_all_numbers(line: &str) -> Result<Vec<u32>, Vec<String>> {
     }
 }
 
+/// Optimal string alignment distance between two words.
+/// Counts insertions, deletions, substitutions, and swaps of adjacent characters.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    // d[i][j] is the distance between the first i characters of a, and the first j characters of b.
+    let mut d = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    d[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1)
+                .min(d[i][j - 1] + 1)
+                .min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+/// Returns the candidate closest to the word, if it is close enough to be a likely typo.
+/// Ties are broken by the order of the candidates.
+pub fn closest_match<'a>(
+    word: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> Option<&'a str> {
+    let max_distance = (word.chars().count() / 3).max(1);
+    let mut best: Option<(usize, &str)> = None;
+    for candidate in candidates {
+        let distance = edit_distance(word, candidate);
+        if distance <= max_distance && best.map(|(d, _)| distance < d).unwrap_or(true) {
+            best = Some((distance, candidate));
+        }
+    }
+    best.map(|(_, candidate)| candidate)
+}
+/// Returns a suggestion for the first operand that looks like a misspelled register.
+/// ex: "mov r0, rl" suggests "lr"
+pub fn suggest_register(line: &str) -> Option<String> {
+    if line.contains('=') {
+        // label or literal operands are not registers.
+        return None;
+    }
+    let re_register = Regex::new(r"^(r\d+|sp|lr|pc|lsl)$").unwrap();
+    let re_word = Regex::new(r"^[a-z_]\w*$").unwrap();
+    let names = register_names();
+    let (_, operands) = line.split_once(char::is_whitespace)?;
+    operands
+        .split(|c: char| c == ',' || c == '[' || c == ']' || c == '!' || c.is_whitespace())
+        .filter(|word| {
+            re_word.is_match(word) && !re_register.is_match(word) && register_number(word).is_none()
+        })
+        .find_map(|word| {
+            closest_match(word, names.iter().map(String::as_str)).map(|register| {
+                error::with_suggestion(format!("\"{}\" is not a register.", word), Some(register))
+            })
+        })
+}
+
 /// Sets the N and Z aspr flags
 pub fn set_nz_flags(num: u32, chip: &mut Processor) {
     // set aspr flags
@@ -235,16 +437,24 @@ pub fn is_Rt_Rn_Rm_shift(line: &str) -> bool {
 #[allow(non_snake_case)]
 /// ldr rt ,= <label>
 pub fn is_Rt_equal_label(line: &str) -> bool {
-    Regex::new(format!(r"^\S+{},={}$", register(), re_label()).as_str())
+    Regex::new(format!(r"^\S+{},\s*={}$", register(), re_label()).as_str())
         .unwrap()
         .is_match(line)
 }
 #[allow(non_snake_case)]
 /// ldr rt ,= imm32
 pub fn is_Rt_equal_immed(line: &str) -> bool {
-    Regex::new(format!(r"^\S+{},={}*$", register(), i_number()).as_str())
-        .unwrap()
-        .is_match(line)
+    Regex::new(
+        format!(
+            r"^\S+{},\s*=({}|{})$",
+            register(),
+            i_number(),
+            literal_number()
+        )
+        .as_str(),
+    )
+    .unwrap()
+    .is_match(line)
 }
 
 /// Determines if line is in the format "mnemonic<extensions> <label>"
@@ -288,25 +498,14 @@ fn get_rt_and_address(operands: &Operands, chip: &mut Processor) -> Result<(u8,
 /// Stores a byte, halfword, or word in memory
 pub fn store_bytes(operands: &Operands, chip: &mut Processor, size: MemSize) -> Result<(), String> {
     let (rt, address) = get_rt_and_address(operands, chip)?;
-    let address = error::check_memory_bounds(address, chip.memory.len(), size)?;
-    let bytes = chip.R[rt as usize].to_le_bytes();
-    for i in 0..size.bytes() {
-        chip.memory[address + i] = bytes[i];
-    }
-    Ok(())
+    chip.memory.write(address, size, chip.R[rt as usize])
 }
 
 /// Stores a byte, halfword, or word in memory
 pub fn load_bytes(operands: &Operands, chip: &mut Processor, size: MemSize) -> Result<(), String> {
     let (rt, address) = get_rt_and_address(operands, chip)?;
-    let address = error::check_memory_bounds(address, chip.memory.len(), size)?;
-    // store bytes, first element has lsb, value is zero extended
-    let mut bytes: [u8; 4] = [0, 0, 0, 0];
-    for i in 0..size.bytes() {
-        // store least significant bits first
-        bytes[i] = chip.memory[address + i];
-    }
-    chip.R[rt as usize] = u32::from_le_bytes(bytes);
+    // value is zero extended
+    chip.R[rt as usize] = chip.memory.read(address, size)?;
     Ok(())
 }
 
@@ -316,7 +515,7 @@ pub fn run_branch_instruction(
     operands: &Operands,
     file_name: &str,
     line_number: usize,
-    string_messages: &Vec<String>,
+    memory: &Memory,
 ) -> Result<Option<InputStatus>, String> {
     match operands {
         Operands::label { label } => match label {
@@ -333,10 +532,16 @@ pub fn run_branch_instruction(
                 }
             }
             Label::PRINTF => {
-                *std_out = format!(
-                    "{}{}",
-                    std_out, string_messages.get(r0 as usize).ok_or(format!("\"{}\" line {}: Cannot print string pointed to by register r0.", file_name, line_number))?
-                );
+                // print the null terminated string stored at the address in r0.
+                let mut address = r0;
+                loop {
+                    let byte = memory.read(address, MemSize::BYTE).map_err(|_| format!("\"{}\" line {}: Cannot print string pointed to by register r0.", file_name, line_number))?;
+                    if byte == 0 {
+                        break;
+                    }
+                    std_out.push(byte as u8 as char);
+                    address = address.wrapping_add(1);
+                }
             }
             Label::GetNumber => {
                 return Ok(Some(InputStatus::GetNumber));
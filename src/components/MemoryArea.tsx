@@ -1,14 +1,54 @@
-import { useAssemblySource } from "@/context/AssemblyContext";
+import { MemoryView, memory_byte, useAssemblySource } from "@/context/AssemblyContext";
+import { save } from "@tauri-apps/api/dialog";
 import { invoke } from "@tauri-apps/api/tauri";
 import { Fragment, useEffect, useRef, useState } from "react";
 
+/** number of bytes shown at a time, the data region can be much larger */
+const WINDOW_SIZE = 1024;
+
 export default function MemoryArea() {
-    const location = useRef<any | null>(null);
     const inputElement = useRef<HTMLInputElement | null>(null);
     const errorElement = useRef<HTMLParagraphElement | null>(null);
 
     const format = useRef<HTMLSelectElement | null>(null);
-    const { memory, memory_format } = useAssemblySource();
+    const { memory, memory_format, machine_code } = useAssemblySource();
+    // which view is shown, the memory, the machine code, or the disassembly of the program
+    const [view, setView] = useState("memory");
+    // the disassembled machine code, as [address, assembly text]
+    const [disassembly, setDisassembly] = useState<[number, string][]>([]);
+
+    // disassemble the program again when it is compiled
+    useEffect(() => {
+        if (view === "disassembly") {
+            invoke<[number, string][]>('disassemble').then(setDisassembly);
+        }
+    }, [view, machine_code]);
+
+    // the window of the data region that is shown, it ends at window_end. Starts at the end of the region, where the stack is.
+    const region_end = memory.origin + memory.length;
+    const [window_end, setWindowEnd] = useState<number | null>(null);
+    // the address chosen with "Go to"
+    const [selected, setSelected] = useState<number | null>(null);
+    const top = Math.min(window_end ?? region_end, region_end);
+    const bottom = Math.max(top - WINDOW_SIZE, memory.origin);
+    // full descending stack, the highest address is shown first
+    const addresses = Array.from({ length: Math.max(top - bottom, 0) }, (_, i) => top - 1 - i);
+
+    // a different region is shown from its end
+    useEffect(() => {
+        setWindowEnd(null);
+        setSelected(null);
+    }, [memory.origin, memory.length]);
+    // scroll to the chosen address once its window is shown
+    useEffect(() => {
+        if (selected !== null)
+            document.getElementById("memory-byte-" + selected)?.scrollIntoView({ behavior: "smooth", block: "center", inline: "nearest" });
+    }, [selected, window_end]);
+    // shows the window that ends at an address, rows stay aligned to the end of the region
+    const showWindow = (end: number) => {
+        end = Math.min(Math.max(end, memory.origin + 4), region_end);
+        setWindowEnd(region_end - Math.floor((region_end - end) / 4) * 4);
+    }
 
 
     // https://stackoverflow.com/questions/57803/how-to-convert-decimal-to-hexadecimal-in-javascript
@@ -23,34 +63,29 @@ export default function MemoryArea() {
     }
     const handleInput = (key: string) => {
         if (key === "Enter") {
-            if (location.current) {
-                // reset background color of previous selected memory
-                location.current.style.backgroundColor = "";
-            }
             if (inputElement.current) {
-                const input_value = inputElement.current.value; // index of memory location
-                let index = memory.memory.length - 1;
+                const input_value = inputElement.current.value; // address of memory location
+                let address = 0;
                 // convert string to number
                 if (input_value.match(/^0b[01]+$/i)) {
-                    index = parseInt(input_value.slice(2), 2);
+                    address = parseInt(input_value.slice(2), 2);
                 } else if (input_value.match(/^\d+$/i) || input_value.match(/^0x[a-fA-F\d]+$/i)) {
-                    index = parseInt(input_value);
+                    address = parseInt(input_value);
                 } else {
                     // handle invalid number
                     if (errorElement.current)
                         errorElement.current.innerText = "Must enter a valid number. eg. 10, 0b1010, 0xa, etc.";
                     return;
                 }
-                index = memory.memory.length - 1 - index;
                 // memory bound checking
-                if (index < 0 || index >= memory.memory.length) {
+                if (address < memory.origin || address >= region_end) {
                     // handles out of bounds error
                     if (errorElement.current)
                         errorElement.current.innerText = "Memory location is out of bounds.";
                 } else {
-                    location.current = document.getElementsByClassName("memory-byte")[index];
-                    location.current.scrollIntoView({ behavior: "smooth", block: "center", inline: "nearest" });
-                    location.current.style.backgroundColor = "cornflowerblue";
+                    // show the window with the address near its middle
+                    showWindow(address + WINDOW_SIZE / 2);
+                    setSelected(address);
                     if (errorElement.current)
                         errorElement.current.innerText = "";
                 }
@@ -60,14 +95,36 @@ export default function MemoryArea() {
     const updateMemory = () => {
         if (format?.current?.value ?? '' !== memory_format.current) {
             memory_format.current = format?.current?.value ?? '';
-            invoke<[string[], number]>('display_memory', { num_format: memory_format.current }).then(([ram, sp]) => {
-                memory.update_memory(ram.reverse(), sp);
+            invoke<MemoryView>('display_memory', { num_format: memory_format.current, all: true }).then(view => {
+                memory.update_memory(view);
             });
         }
     }
+    // saves the data region to an Intel HEX, S-record, or raw binary file, picked by the file extension
+    const exportMemory = async () => {
+        const file_path = await save({ filters: [{ name: "Memory Image", extensions: ["hex", "srec", "s19", "bin"] }] });
+        if (!file_path)
+            return;
+        invoke('export_memory', { file_path })
+            .then(() => {
+                if (errorElement.current)
+                    errorElement.current.innerText = "";
+            })
+            .catch(err => {
+                if (errorElement.current)
+                    errorElement.current.innerText = err;
+            });
+    }
     return (
         <div id="memory-area" className="bg-darken text-white">
             <div className="px-2 pt-3">
+                <select value={view} onChange={e => setView(e.target.value)} title="Displayed view" className="text-zinc-800 p-1 rounded-sm">
+                    <option value="memory">Memory</option>
+                    <option value="machine_code">Machine Code</option>
+                    <option value="disassembly">Disassembly</option>
+                </select>
+            </div>
+            <div className={"px-2 pt-3" + (view === "memory" ? "" : " hidden")}>
                 <span className=" mr-1">Go to: </span>
                 <input ref={inputElement} type="text" className="text-black rounded-md p-1" placeholder="Memory Address" onKeyDown={(e) => handleInput(e.key)} />
                 <select ref={format} onChange={updateMemory} defaultValue={"unsigned"} title="Display Format of bytes" className="text-zinc-800 ml-4 p-1 rounded-sm">
@@ -76,29 +133,63 @@ export default function MemoryArea() {
                     <option value="binary">Binary</option>
                     <option value="hexadecimal">Hexadecimal</option>
                 </select>
+                <button onClick={exportMemory} title="Save memory to a .hex, .srec, or .bin file" className="project-explorer ml-4">Export</button>
             </div>
-            <p className="text-xs text-red-500 px-6 h-4" ref={errorElement}>
+            <p className={"text-xs text-red-500 px-6 h-4" + (view === "memory" ? "" : " hidden")} ref={errorElement}>
                 {/* Used to output error messages */}
             </p>
-            <p className=" px-3 text-center"><span className=" text-purple-500 font-bold">&#9632; </span><span className=" text-sm">Stack Pointer</span></p>
-            <div id="memory-grid" className="overflow-scroll mt-2 pb-1 text-sm">
+            <p className={" px-3 text-center" + (view === "memory" ? "" : " hidden")}>
+                <button onClick={() => showWindow(top + WINDOW_SIZE)} disabled={top >= region_end} title="Show higher addresses" className="mr-3">&#9650;</button>
+                <span className=" text-purple-500 font-bold">&#9632; </span><span className=" text-sm">Stack Pointer</span>
+                <span className=" text-sm ml-3">{decimalToHex(bottom, 8)} - {decimalToHex(Math.max(top - 1, bottom), 8)}</span>
+                <button onClick={() => showWindow(top - WINDOW_SIZE)} disabled={bottom <= memory.origin} title="Show lower addresses" className="ml-3">&#9660;</button>
+            </p>
+            <div id="memory-grid" className={"overflow-scroll mt-2 pb-1 text-sm" + (view === "memory" ? "" : " hidden")}>
                 <span className="sticky top-0">addr + 3</span>
                 <span className="sticky top-0">addr + 2</span>
                 <span className="sticky top-0">addr + 1</span>
                 <span className="sticky top-0">addr + 0</span>
                 <span className="sticky top-0">address</span>
                 {/* full descending stack */}
-                {memory.memory.map((byte, i) => (
-                    <Fragment key={i}>
-                        <span className={"memory-byte" + ((memory.memory.length - i - 1) == memory.SP ? " bg-purple-500" : " bg-[#81898e]")}>{byte}</span>
+                {addresses.map((address, i) => (
+                    <Fragment key={address}>
+                        <span id={"memory-byte-" + address} className={"memory-byte" + (address == selected ? " bg-[cornflowerblue]" : address == memory.SP ? " bg-purple-500" : " bg-[#81898e]")}>{memory_byte(memory, address)}</span>
                         {
                             (i + 1) % 4 == 0 ?
-                                <span>{decimalToHex(memory.memory.length - i - 1, 8)}</span> :
+                                <span>{decimalToHex(address, 8)}</span> :
                                 null
                         }
                     </Fragment>
                 ))}
             </div>
+            {view === "machine_code" ?
+                <div id="code-grid" className="overflow-scroll mt-2 pb-1 text-sm">
+                    <span className="sticky top-0">address</span>
+                    <span className="sticky top-0">machine code</span>
+                    <span className="sticky top-0">line</span>
+                    {machine_code.map((encoding, i) => (
+                        <Fragment key={i}>
+                            <span>{decimalToHex(encoding.address, 8)}</span>
+                            <span>{encoding.machine_code}</span>
+                            <span>{encoding.file_name}: {encoding.line_number}</span>
+                        </Fragment>
+                    ))}
+                </div> :
+                null
+            }
+            {view === "disassembly" ?
+                <div id="disassembly-grid" className="overflow-scroll mt-2 pb-1 text-sm">
+                    <span className="sticky top-0">address</span>
+                    <span className="sticky top-0">instruction</span>
+                    {disassembly.map(([address, text], i) => (
+                        <Fragment key={i}>
+                            <span>{decimalToHex(address, 8)}</span>
+                            <span>{text}</span>
+                        </Fragment>
+                    ))}
+                </div> :
+                null
+            }
         </div>
     )
 }
\ No newline at end of file
@@ -74,6 +74,8 @@ export default function Terminal() {
                             return <p key={i} className="text-green-500 font-bold">{out.message}</p>
                         case "error":
                             return <p key={i}><span className=" text-red-500 font-bold">Error: </span>{out.message}</p>
+                        case "warning":
+                            return <p key={i}><span className=" text-yellow-500 font-bold">Warning: </span>{out.message}</p>
                         case "red":
                             return <p key={i} className="text-red-500 font-bold">{out.message}</p>
                         default:
@@ -1,7 +1,7 @@
 import { useAssemblySource } from "@/context/AssemblyContext";
 import { Icon } from "@iconify/react/dist/iconify.js"
 import StopBtn from "@/components/StopBtn"
-import { handleDebug, handleContinue, handleRun, handleStep, handleCompileRun } from "@/helpers/control";
+import { handleDebug, handleContinue, handleRun, handleStep, handleCompileRun, handleLoadExecutable, handleExportElf } from "@/helpers/control";
 import { useSource } from "@/context/SourceContext";
 
 export default function Toolbar() {
@@ -19,6 +19,8 @@ export default function Toolbar() {
             <span title="step"><i onClick={() => handleStep(source, ass_source)} className={activeBtn(toolbar_btn.state.step) + " hover:bg-gray-600 cursor-pointer p-1"}><Icon icon="clarity:step-forward-solid" /></i></span>
             <span>Run: <i title="run" onClick={() => handleCompileRun(source, ass_source)} className={activeBtn(toolbar_btn.state.run) + " hover:bg-gray-600 cursor-pointer p-1"}><Icon icon="codicon:run-all" /></i></span>
             <StopBtn active={toolbar_btn.state.stop} />
+            <span>ELF: <i title="load and run an ELF executable" onClick={() => handleLoadExecutable(ass_source)} className={activeBtn(toolbar_btn.state.run) + " hover:bg-gray-600 cursor-pointer p-1"}><Icon icon="codicon:folder-opened" /></i></span>
+            <span title="export the compiled program as an ELF executable"><i onClick={() => handleExportElf(ass_source)} className="text-green-400 hover:bg-gray-600 cursor-pointer p-1"><Icon icon="codicon:save-as" /></i></span>
         </div>
     )
 }
\ No newline at end of file
@@ -32,9 +32,43 @@ export interface CPU {
     update_cpu: (R: string[], N: boolean, Z: boolean, C: boolean, V: boolean) => void;
 }
 export interface Memory {
-    memory: string[];
+    /** the pages of the data region that were received, by start address. Missing pages are zero. */
+    pages: { [address: number]: string[] };
+    /** a zero byte in the number format of the pages */
+    zero: string;
     SP: number;
-    update_memory: (memory: string[], SP: number) => void;
+    /** address of the first byte and size of the data region */
+    origin: number;
+    length: number;
+    update_memory: (view: MemoryView) => void;
+}
+/** the pages of memory that changed, as [start address, bytes] */
+export interface MemoryView {
+    pages: [number, string[]][];
+    /** every page that is not zero was sent */
+    all: boolean;
+    zero: string;
+    SP: number;
+    origin: number;
+    length: number;
+}
+/** size of the pages sent by the backend */
+export const PAGE_SIZE = 4096;
+/** returns the byte at an address of the data region */
+export const memory_byte = (memory: Memory, address: number): string => {
+    const page = Math.max(address - address % PAGE_SIZE, memory.origin);
+    return memory.pages[page]?.[address - page] ?? memory.zero;
+}
+export interface InstructionEncoding {
+    file_name: string;
+    line_number: number;
+    address: number;
+    machine_code: string;
+}
+export interface LoadedExecutable {
+    listing: string;
+    stack_pointer: number;
+    entry: number;
 }
 export enum InputStatus {
     GetChar = 'GetChar',
@@ -62,6 +96,8 @@ export interface IAssemblyContext {
     input_status: MutableRefObject<InputStatus>,
     set_debug_status: (status: DebugStatus) => void;
     highlight_line: Ihighlight_line,
+    machine_code: InstructionEncoding[];    // machine code of the compiled program
+    set_machine_code: (machine_code: InstructionEncoding[]) => void;
 }
 
 const AssemblyContext = createContext<IAssemblyContext>({
@@ -88,6 +124,8 @@ const AssemblyContext = createContext<IAssemblyContext>({
         number: 0,
         setLine: (id, number) => { },
     },
+    machine_code: [],
+    set_machine_code: (machine_code) => { },
     cpu: {
         R: [],
         N: false,
@@ -98,9 +136,12 @@ const AssemblyContext = createContext<IAssemblyContext>({
     },
     cpu_format: createRef() as MutableRefObject<string>,
     memory: {
-        memory: [],
+        pages: {},
+        zero: "0",
         SP: 0,
-        update_memory(memory, SP) { },
+        origin: 0,
+        length: 0,
+        update_memory(view) { },
     },
     memory_format: createRef() as MutableRefObject<string>,
 });
@@ -108,6 +149,7 @@ const AssemblyContext = createContext<IAssemblyContext>({
 export const AssemblySourceProvider = ({ children }: { children: JSX.Element | JSX.Element[] }) => {
     const [std_out, setSTDOut] = useState<I_std_out[]>([]);
     const [debug_status, set_debug_status] = useState(DebugStatus.END);
+    const [machine_code, set_machine_code] = useState<InstructionEncoding[]>([]);
     const input_status = useRef<InputStatus>(InputStatus.None);
     const cpu_format = useRef<string>('unsigned');
     const [cpu, setCPU] = useState<CPU>({
@@ -122,10 +164,21 @@ export const AssemblySourceProvider = ({ children }: { children: JSX.Element | J
     });
     const memory_format = useRef<string>('unsigned');
     const [memory, setMemory] = useState<Memory>({
-        memory: new Array(1024).fill("0"),
+        pages: {},
+        zero: "0",
         SP: 0,
-        update_memory(memory, SP) {
-            setMemory(mem => ({ ...mem, memory, SP }));
+        origin: 0,
+        length: 1024,
+        update_memory(view) {
+            setMemory(mem => {
+                // only changed pages are sent, unless every page was sent or the region is different
+                const keep = !view.all && mem.origin === view.origin && mem.length === view.length;
+                const pages = keep ? { ...mem.pages } : {};
+                view.pages.forEach(([address, bytes]) => {
+                    pages[address] = bytes;
+                });
+                return { ...mem, pages, zero: view.zero, SP: view.SP, origin: view.origin, length: view.length };
+            });
         },
     });
     const [highlight_line, set_highlite_line] = useState<Ihighlight_line>({
@@ -169,7 +222,9 @@ export const AssemblySourceProvider = ({ children }: { children: JSX.Element | J
         debug_status,
         set_debug_status,
         highlight_line,
-    }), [cpu, memory, toolbar_btn, debug_status]);
+        machine_code,
+        set_machine_code,
+    }), [cpu, memory, toolbar_btn, debug_status, machine_code]);
 
     return (
         <AssemblyContext.Provider value={assemblyValues}>
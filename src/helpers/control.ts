@@ -1,6 +1,7 @@
-import { CPU, DebugStatus, IAssemblyContext, InputStatus } from "@/context/AssemblyContext";
+import { CPU, DebugStatus, IAssemblyContext, InputStatus, InstructionEncoding, LoadedExecutable, MemoryView } from "@/context/AssemblyContext";
 import { ISourceContext } from "@/context/SourceContext";
 import { getFileFromName, getFileObject } from "@/stores/files";
+import { open, save } from "@tauri-apps/api/dialog";
 import { invoke } from "@tauri-apps/api/tauri";
 
 export const handleCompileRun = (source: ISourceContext, ass_source: IAssemblyContext) => {
@@ -20,9 +21,13 @@ export const handleCompileRun = (source: ISourceContext, ass_source: IAssemblyCo
     // Compile Code, update terminal with result
     push_std_out("compile", "Compiling...");
 
-    invoke('compile', { dir_path: directory })
-        .then(_ => {
+    invoke<string[]>('compile', { dir_path: directory })
+        .then(warnings => {
+            warnings.forEach(mess => {
+                push_std_out("warning", mess);
+            });
             push_std_out("compile", "Compiled Successfully");
+            update_machine_code(ass_source);
 
             // Run assembly code, activate Stop btn.
             push_std_out("run", "Running...");
@@ -38,6 +43,58 @@ export const handleCompileRun = (source: ISourceContext, ass_source: IAssemblyCo
             push_std_out("red", "Compiling failed...");
         });
 }
+// loads an ELF executable instead of compiling the project, and runs it.
+export const handleLoadExecutable = async (ass_source: IAssemblyContext) => {
+    const {
+        clear_std_out,
+        push_std_out,
+        toolbar_btn,
+        set_debug_status
+    } = ass_source;
+
+    if (!toolbar_btn.state.run)
+        return;
+    const file_path = await open({ filters: [{ name: "ELF Executable", extensions: ["elf", "axf", "out"] }] });
+    if (!file_path || Array.isArray(file_path))
+        return;
+    clear_std_out();
+    push_std_out("compile", "Loading...");
+
+    invoke<LoadedExecutable>('load_executable', { file_path })
+        .then(executable => {
+            push_std_out("compile", "Loaded Successfully");
+            // the line numbers of the debugger refer to the disassembled code
+            push_std_out("text", executable.listing + "\n");
+            update_machine_code(ass_source);
+
+            push_std_out("run", "Running...");
+            toolbar_btn.setRunningMode();
+            set_debug_status(DebugStatus.RUNNING);
+
+            handleRun(ass_source);
+        }).catch(err => {
+            err.forEach((mess: string) => {
+                push_std_out("error", mess);
+            });
+            push_std_out("red", "Loading failed...");
+        });
+}
+// writes the compiled program to an ELF executable.
+export const handleExportElf = async (ass_source: IAssemblyContext) => {
+    const { push_std_out } = ass_source;
+    const file_path = await save({ filters: [{ name: "ELF Executable", extensions: ["elf"] }] });
+    if (!file_path)
+        return;
+    invoke('export_elf', { file_path })
+        .then(() => push_std_out("compile", "Exported " + file_path))
+        .catch(err => push_std_out("error", err));
+}
+// gets the machine code of the compiled program, shown in the memory area.
+export const update_machine_code = (ass_source: IAssemblyContext) => {
+    invoke<InstructionEncoding[]>('machine_code').then(machine_code => {
+        ass_source.set_machine_code(machine_code);
+    });
+}
 export const handleRun = (ass_source: IAssemblyContext, std_input?: number) => {
     const {
         cpu,
@@ -73,8 +130,8 @@ export const handleRun = (ass_source: IAssemblyContext, std_input?: number) => {
             invoke<CPU>('display_cpu', { num_format: cpu_format.current }).then(newCPU => {
                 cpu.update_cpu(newCPU.R, newCPU.N, newCPU.Z, newCPU.C, newCPU.V);
             });
-            invoke<[string[], number]>('display_memory', { num_format: memory_format.current }).then(([ram, sp]) => {
-                memory.update_memory(ram.reverse(), sp);
+            invoke<MemoryView>('display_memory', { num_format: memory_format.current, all: false }).then(view => {
+                memory.update_memory(view);
             });
         });
 };
@@ -135,8 +192,8 @@ export const debug_step = async (source: ISourceContext, ass_source: IAssemblyCo
         await invoke<CPU>('display_cpu', { num_format: cpu_format.current }).then(res => {
             cpu.update_cpu(res.R, res.N, res.Z, res.C, res.V);
         });
-        await invoke<[string[], number]>('display_memory', { num_format: memory_format.current }).then(([ram, sp]) => {
-            memory.update_memory(ram.reverse(), sp);
+        await invoke<MemoryView>('display_memory', { num_format: memory_format.current, all: false }).then(view => {
+            memory.update_memory(view);
         });
     });
     return Promise.resolve(stop);
@@ -174,10 +231,14 @@ export const handleDebug = (source: ISourceContext, ass_source: IAssemblyContext
 
     // Compile Code, update terminal with result
     push_std_out("compile", "Compiling...");
-    invoke('compile', { dir_path: directory, breakpoint_map })
-        .then(_ => {
+    invoke<string[]>('compile', { dir_path: directory, breakpoint_map })
+        .then(warnings => {
+            warnings.forEach(mess => {
+                push_std_out("warning", mess);
+            });
             // Run assembly code, activate Stop btn.
             push_std_out("compile", "Compiled Successfully");
+            update_machine_code(ass_source);
             push_std_out("run", "Debugging...");
             toolbar_btn.setRunningMode();
             set_debug_status(DebugStatus.CONTINUE);